@@ -1,3 +1,12 @@
-#![feature(try_from)]
+extern crate base64;
+extern crate bytes;
+extern crate futures;
+extern crate pbkdf2;
+extern crate sha1;
+extern crate sha2;
+extern crate tokio;
+extern crate tokio_rustls;
+extern crate x509_parser;
+
 pub use mqtt::*;
 mod mqtt;
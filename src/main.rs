@@ -1,22 +1,232 @@
-#![feature(try_from)]
 extern crate tokio;
 extern crate futures;
+extern crate mqtt;
 
-use futures::Future;
-use std::collections::BTreeMap;
+use futures::future;
 use tokio::prelude::*;
-use tokio::io;
-use tokio::net::tcp;
 use tokio::net::TcpListener;
-use tokio::io::AsyncWrite;
-use tokio::net::TcpStream;
-use std::net::SocketAddr;
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// What can be set from the command line
+#[derive(Default)]
+struct Options {
+    config: mqtt::Config,
+    password_file: Option<String>,
+    allow_anonymous: bool,
+    acl_file: Option<String>,
+    tls_port: Option<u16>,
+    tls_bind_address: Option<IpAddr>,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    tls_ca: Option<String>,
+    require_certificate: bool,
+    use_identity_as: Option<mqtt::CertificateIdentity>,
+    ws_port: Option<u16>,
+    unix_socket: Option<String>,
+    unix_socket_mode: Option<u32>,
+    trusted_uids: Vec<u32>,
+    trusted_gids: Vec<u32>
+}
+
+impl Options {
+    fn parse() -> Options {
+        let mut options = Options::default();
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--password-file" => options.password_file = Some(Options::value(&arg, args.next())),
+                "--allow-anonymous" => options.allow_anonymous = true,
+                "--trust-uid" => options.trusted_uids.push(Options::number(&arg, args.next())),
+                "--trust-gid" => options.trusted_gids.push(Options::number(&arg, args.next())),
+                "--acl-file" => options.acl_file = Some(Options::value(&arg, args.next())),
+                "--tls-port" => options.tls_port = Some(Options::port(&arg, args.next())),
+                "--tls-bind-address" => options.tls_bind_address = Some(Options::address(&arg, args.next())),
+                "--tls-cert" => options.tls_cert = Some(Options::value(&arg, args.next())),
+                "--tls-key" => options.tls_key = Some(Options::value(&arg, args.next())),
+                "--tls-ca" => options.tls_ca = Some(Options::value(&arg, args.next())),
+                "--require-certificate" => options.require_certificate = true,
+                "--use-identity-as" => {
+                    options.use_identity_as = match Options::value(&arg, args.next()).as_str() {
+                        "username" => Some(mqtt::CertificateIdentity::Username),
+                        "client-id" => Some(mqtt::CertificateIdentity::ClientId),
+                        other => Options::usage(&format!("can't use the certificate identity as '{}'", other))
+                    }
+                },
+                "--ws-port" => options.ws_port = Some(Options::port(&arg, args.next())),
+                "--unix-socket" => options.unix_socket = Some(Options::value(&arg, args.next())),
+                "--unix-socket-mode" => {
+                    let mode = Options::value(&arg, args.next());
+                    match u32::from_str_radix(&mode, 8) {
+                        Ok(mode) if mode <= 0o777 => options.unix_socket_mode = Some(mode),
+                        _ => Options::usage(&format!("'{}' is not an octal file mode", mode))
+                    }
+                },
+                "--max-qos" => {
+                    let qos = Options::value(&arg, args.next());
+                    options.config.max_qos = qos.parse().ok()
+                        .and_then(|qos| { mqtt::QualityOfService::from_byte(qos).ok() })
+                        .unwrap_or_else(|| { Options::usage(&format!("'{}' is not a QoS of 0, 1 or 2", qos)) })
+                },
+                "--max-inflight" => {
+                    options.config.max_inflight = Options::number(&arg, args.next());
+                    if options.config.max_inflight == 0 {
+                        Options::usage("--max-inflight has to be at least 1")
+                    }
+                },
+                "--max-queued" => {
+                    options.config.max_queued = Options::number(&arg, args.next());
+                    if options.config.max_queued == 0 {
+                        Options::usage("--max-queued has to be at least 1")
+                    }
+                },
+                "--retry-interval" => {
+                    let seconds = Options::number(&arg, args.next());
+                    if seconds == 0 {
+                        Options::usage("--retry-interval has to be at least 1 second")
+                    }
+                    options.config.retry_interval = Some(Duration::from_secs(seconds))
+                },
+                "--max-packet-size" => {
+                    options.config.max_packet_size = Options::number(&arg, args.next());
+                    // The smallest packets are two bytes of fixed header with nothing after
+                    if options.config.max_packet_size < 2 {
+                        Options::usage("--max-packet-size has to be at least 2 bytes")
+                    }
+                },
+                "--max-keep-alive" => options.config.max_keep_alive = Some(Options::number(&arg, args.next())),
+                "--connect-timeout" =>
+                    options.config.connect_timeout = Duration::from_secs(Options::number(&arg, args.next())),
+                _ => Options::usage(&format!("unknown option '{}'", arg))
+            }
+        }
+        if cfg!(not(unix)) && options.unix_socket.is_some() {
+            Options::usage("--unix-socket needs a Unix-like system")
+        }
+        if options.unix_socket_mode.is_some() && options.unix_socket.is_none() {
+            Options::usage("--unix-socket-mode needs --unix-socket")
+        }
+        if (!options.trusted_uids.is_empty() || !options.trusted_gids.is_empty()) && options.unix_socket.is_none() {
+            Options::usage("--trust-uid and --trust-gid need --unix-socket")
+        }
+        if options.tls_cert.is_some() != options.tls_key.is_some() {
+            Options::usage("TLS needs both --tls-cert and --tls-key")
+        }
+        if options.tls_cert.is_none() && (options.tls_port.is_some() || options.tls_bind_address.is_some() || options.tls_ca.is_some()) {
+            Options::usage("the TLS listener needs --tls-cert and --tls-key")
+        }
+        // Client certificates are only asked for when there's a CA to check them against
+        if options.tls_ca.is_none() && (options.require_certificate || options.use_identity_as.is_some()) {
+            Options::usage("--require-certificate and --use-identity-as need --tls-ca")
+        }
+        options
+    }
+
+    fn tls_config(&self) -> Option<mqtt::TlsConfig> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Some(mqtt::TlsConfig {
+                ca_file: self.tls_ca.as_ref().map(PathBuf::from),
+                require_certificate: self.require_certificate,
+                identity: self.use_identity_as,
+                ..mqtt::TlsConfig::new(cert, key)
+            }),
+            _ => None
+        }
+    }
+
+    fn value(option: &str, value: Option<String>) -> String {
+        value.unwrap_or_else(|| { Options::usage(&format!("{} needs a value", option)) })
+    }
+
+    fn port(option: &str, value: Option<String>) -> u16 {
+        let port = Options::value(option, value);
+        port.parse().unwrap_or_else(|_| { Options::usage(&format!("'{}' is not a port", port)) })
+    }
+
+    fn address(option: &str, value: Option<String>) -> IpAddr {
+        let address = Options::value(option, value);
+        address.parse().unwrap_or_else(|_| { Options::usage(&format!("'{}' is not an IP address", address)) })
+    }
+
+    fn number<T: FromStr>(option: &str, value: Option<String>) -> T {
+        let number = Options::value(option, value);
+        number.parse().unwrap_or_else(|_| { Options::usage(&format!("'{}' is not a valid {}", number, option)) })
+    }
+
+    fn usage(problem: &str) -> ! {
+        eprintln!("{}", problem);
+        eprintln!("usage: mqtt [--max-qos 0|1|2] [--max-packet-size BYTES] [--max-keep-alive SECONDS]");
+        eprintln!("            [--connect-timeout SECONDS] [--max-inflight COUNT] [--max-queued COUNT]");
+        eprintln!("            [--retry-interval SECONDS]");
+        eprintln!("            [--password-file PATH [--allow-anonymous]] [--acl-file PATH]");
+        eprintln!("            [--trust-uid UID]... [--trust-gid GID]...");
+        eprintln!("            [--tls-cert PATH --tls-key PATH [--tls-port PORT] [--tls-bind-address IP]");
+        eprintln!("             [--tls-ca PATH [--require-certificate] [--use-identity-as username|client-id]]]");
+        eprintln!("            [--ws-port PORT] [--unix-socket PATH [--unix-socket-mode MODE]]");
+        process::exit(2)
+    }
+}
 
 fn main() {
+    let options = Options::parse();
     let addr = "127.0.0.1:9002".parse::<SocketAddr>().unwrap();
     let listener = TcpListener::bind(&addr).unwrap();
 
-    let sessions = mqtt::Sessions::new();
+    // Every connection shares the one set of sessions, so that publishes can be routed
+    // between them
+    let config = options.config.clone();
+    let retry_interval = config.retry_interval;
+    let mut sessions = mqtt::Sessions::with_config(config);
+    // Without a password file, anyone can connect
+    let mut authenticator: Box<dyn mqtt::Authenticator> = match options.password_file {
+        Some(ref path) => match mqtt::PasswordFile::load(path, options.allow_anonymous) {
+            Ok(password_file) => Box::new(password_file),
+            Err(err) => {
+                eprintln!("couldn't read password file {}: {}", path, err);
+                process::exit(1)
+            }
+        },
+        None => Box::new(mqtt::AllowAnonymous)
+    };
+    // Processes on the Unix socket running as a trusted user or group need no password, and
+    // neither do clients whose certificate gives them their username
+    let certified_usernames = options.use_identity_as == Some(mqtt::CertificateIdentity::Username);
+    if !options.trusted_uids.is_empty() || !options.trusted_gids.is_empty() || certified_usernames {
+        let mut trusted_peers = mqtt::TrustedPeers::new(authenticator);
+        if certified_usernames {
+            trusted_peers.trust_usernames();
+        }
+        for &uid in &options.trusted_uids {
+            trusted_peers.trust_uid(uid);
+        }
+        for &gid in &options.trusted_gids {
+            trusted_peers.trust_gid(gid);
+        }
+        authenticator = Box::new(trusted_peers);
+    }
+    sessions.set_authenticator(authenticator);
+    // Without an ACL file, anyone can publish and subscribe to anything
+    if let Some(ref path) = options.acl_file {
+        match mqtt::AclFile::load(path) {
+            Ok(acl_file) => sessions.set_authorizer(Box::new(acl_file)),
+            Err(err) => {
+                eprintln!("couldn't read ACL file {}: {}", path, err);
+                process::exit(1)
+            }
+        }
+    }
+    let sessions = Arc::new(Mutex::new(sessions));
+    let retry_sessions = sessions.clone();
+    let expiry_sessions = sessions.clone();
+    let tls_sessions = sessions.clone();
+    let unix_sessions = sessions.clone();
+    let ws_sessions = sessions.clone();
+    let connect_timeout = options.config.connect_timeout;
 
     // Here we convert the `TcpListener` to a stream of incoming connections
     // with the `incoming` method. We then define how to process each element in
@@ -26,20 +236,91 @@ fn main() {
         .map_err(|err| {
             println!("listener error = {:?}", err);
         })
-        .for_each(|socket| {
-            let (reader, writer) = socket.split();
-            let bytes_copied = tokio::io::copy(reader, writer);
-            let handle_conn = bytes_copied.map(|amt| {
-                println!("wrote {:?} bytes", amt)
-            }).map_err(|err| {
-                eprintln!("I/O error {:?}", err)
-            });
-            tokio::spawn(handle_conn);
+        .for_each(move |socket| {
+            match socket.peer_addr() {
+                Ok(peer) => {
+                    tokio::spawn(mqtt::handle_connection(socket, peer.into(), mqtt::PeerCredentials::default(), sessions.clone()));
+                },
+                Err(err) => eprintln!("I/O error {:?}", err)
+            }
             Ok(())
         });
 
     println!("server running on localhost:9002");
 
+    // The same sessions over TLS on a port of its own, for clients that aren't on this machine
+    let tls_server = options.tls_config().map(|tls_config| {
+        let acceptor = match mqtt::TlsAcceptor::new(&tls_config) {
+            Ok(acceptor) => acceptor,
+            Err(err) => {
+                eprintln!("couldn't set up TLS: {}", err);
+                process::exit(1)
+            }
+        };
+        // Unlike the others, this listener is safe to open to other machines
+        let tls_ip = options.tls_bind_address.unwrap_or_else(|| { addr.ip() });
+        let tls_addr = SocketAddr::new(tls_ip, options.tls_port.unwrap_or(8883));
+        let tls_listener = TcpListener::bind(&tls_addr).unwrap();
+        println!("TLS server running on {}", tls_addr);
+        tls_listener
+            .incoming()
+            .map_err(|err| {
+                println!("TLS listener error = {:?}", err);
+            })
+            .for_each(move |socket| {
+                match socket.peer_addr() {
+                    Ok(peer) => {
+                        let sessions = tls_sessions.clone();
+                        tokio::spawn(mqtt::handshake_timeout(acceptor.accept(socket), connect_timeout).then(move |accepted| {
+                            match accepted {
+                                Ok((stream, peer_credentials)) =>
+                                    future::Either::A(mqtt::handle_connection(stream, peer.into(), peer_credentials, sessions)),
+                                Err(err) => {
+                                    eprintln!("TLS handshake failed\t{}\t{}", peer, err);
+                                    future::Either::B(future::ok(()))
+                                }
+                            }
+                        }));
+                    },
+                    Err(err) => eprintln!("I/O error {:?}", err)
+                }
+                Ok(())
+            })
+    });
+
+    // The same sessions again for browsers, which can only speak MQTT over WebSockets
+    let ws_server = options.ws_port.map(|ws_port| {
+        let ws_addr = SocketAddr::new(addr.ip(), ws_port);
+        let ws_listener = TcpListener::bind(&ws_addr).unwrap();
+        println!("WebSocket server running on localhost:{}", ws_port);
+        ws_listener
+            .incoming()
+            .map_err(|err| {
+                println!("WebSocket listener error = {:?}", err);
+            })
+            .for_each(move |socket| {
+                match socket.peer_addr() {
+                    Ok(peer) => {
+                        let sessions = ws_sessions.clone();
+                        tokio::spawn(mqtt::handshake_timeout(mqtt::accept_websocket(socket), connect_timeout).then(move |accepted| {
+                            match accepted {
+                                Ok(stream) =>
+                                    future::Either::A(mqtt::handle_connection(stream, peer.into(), mqtt::PeerCredentials::default(), sessions)),
+                                Err(err) => {
+                                    eprintln!("WebSocket handshake failed\t{}\t{}", peer, err);
+                                    future::Either::B(future::ok(()))
+                                }
+                            }
+                        }));
+                    },
+                    Err(err) => eprintln!("I/O error {:?}", err)
+                }
+                Ok(())
+            })
+    });
+
+    let unix_server = unix_server(&options, unix_sessions);
+
     // Start the server
     //
     // This does a few things:
@@ -48,5 +329,57 @@ fn main() {
     // * Spawns the `server` task onto the runtime.
     // * Blocks the current thread until the runtime becomes idle, i.e. all
     //   spawned tasks have completed.
-    tokio::run(server);
+    tokio::run(future::lazy(move || {
+        tokio::spawn(mqtt::expire_sessions(expiry_sessions, Duration::from_secs(1)));
+        if let Some(interval) = retry_interval {
+            tokio::spawn(mqtt::retry_unacknowledged(retry_sessions, interval));
+        }
+        if let Some(tls_server) = tls_server {
+            tokio::spawn(tls_server);
+        }
+        if let Some(ws_server) = ws_server {
+            tokio::spawn(ws_server);
+        }
+        if let Some(unix_server) = unix_server {
+            tokio::spawn(unix_server);
+        }
+        server
+    }));
+}
+
+// The same sessions again for processes on this machine, which the kernel can vouch for
+#[cfg(unix)]
+fn unix_server(options: &Options, sessions: Arc<Mutex<mqtt::Sessions>>) -> Option<impl Future<Item = (), Error = ()> + Send> {
+    options.unix_socket.as_ref().map(|path| {
+        let unix_listener = match mqtt::bind_unix(path, options.unix_socket_mode) {
+            Ok(unix_listener) => unix_listener,
+            Err(err) => {
+                eprintln!("couldn't listen on {}: {}", path, err);
+                process::exit(1)
+            }
+        };
+        println!("Unix socket server running on {}", path);
+        let mut connections = 0;
+        unix_listener
+            .incoming()
+            .map_err(|err| {
+                println!("Unix socket listener error = {:?}", err);
+            })
+            .for_each(move |socket| {
+                connections += 1;
+                match mqtt::unix_peer_credentials(&socket) {
+                    Ok(peer_credentials) => {
+                        let peer = mqtt::PeerAddr::Unix(connections);
+                        tokio::spawn(mqtt::handle_connection(socket, peer, peer_credentials, sessions.clone()));
+                    },
+                    Err(err) => eprintln!("I/O error {:?}", err)
+                }
+                Ok(())
+            })
+    })
+}
+
+#[cfg(not(unix))]
+fn unix_server(_options: &Options, _sessions: Arc<Mutex<mqtt::Sessions>>) -> Option<future::Empty<(), ()>> {
+    None
 }
@@ -16,14 +16,65 @@ pub use self::control::*;
 mod fixed_header;
 pub use self::fixed_header::*;
 
+mod protocol;
+pub use self::protocol::*;
+
+mod protocol_error;
+pub use self::protocol_error::*;
+
+mod reason_code;
+pub use self::reason_code::*;
+
+mod properties;
+pub use self::properties::*;
+
+mod subscription_options;
+pub use self::subscription_options::*;
+
 mod connack;
 pub use self::connack::*;
 
+mod topic;
+pub use self::topic::*;
+
 mod payload;
 pub use self::payload::*;
 
 mod message;
 pub use self::message::*;
 
+mod config;
+pub use self::config::*;
+
+mod auth;
+pub use self::auth::*;
+
+mod acl;
+pub use self::acl::*;
+
+mod subscriptions;
+pub use self::subscriptions::*;
+
+mod retained;
+pub use self::retained::*;
+
 mod session;
 pub use self::session::*;
+
+mod codec;
+pub use self::codec::*;
+
+mod connection;
+pub use self::connection::*;
+
+mod tls;
+pub use self::tls::*;
+
+mod websocket;
+pub use self::websocket::*;
+
+// Unix sockets, and the credentials of whoever is at the other end, only exist on Unix
+#[cfg(unix)]
+mod unix;
+#[cfg(unix)]
+pub use self::unix::*;
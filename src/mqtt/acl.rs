@@ -0,0 +1,371 @@
+use mqtt::*;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result};
+use std::path::Path;
+
+// Who a connected client is, for deciding what it may do
+pub struct ClientIdentity<'a> {
+    pub client_id: &'a str,
+    pub username: Option<&'a str>
+}
+
+// Decides which topics a client may publish and subscribe to
+pub trait Authorizer: Send {
+    fn authorize_publish(&self, client: &ClientIdentity, topic: &TopicName) -> bool;
+
+    fn authorize_subscribe(&self, client: &ClientIdentity, topic_filter: &TopicFilter) -> bool;
+
+    // Whether a message published to `topic` may go to a client through a subscription it was
+    // allowed to make. Lets a broad subscription be refused the odd topic under it.
+    fn authorize_delivery(&self, _client: &ClientIdentity, _topic: &TopicName) -> bool {
+        true
+    }
+}
+
+// Lets every client publish and subscribe to anything
+pub struct AllowAll;
+
+impl Authorizer for AllowAll {
+    fn authorize_publish(&self, _client: &ClientIdentity, _topic: &TopicName) -> bool {
+        true
+    }
+
+    fn authorize_subscribe(&self, _client: &ClientIdentity, _topic_filter: &TopicFilter) -> bool {
+        true
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Access {
+    Read,
+    Write,
+    ReadWrite,
+    Deny
+}
+
+impl Access {
+    fn allows(self, wanted: Access) -> bool {
+        self == wanted || self == Access::ReadWrite
+    }
+}
+
+struct Rule {
+    access: Access,
+    // May contain %u and %c, for the username and client identifier, if it's a pattern
+    topic_filter: OwnedTopicFilter,
+    // Which of them a pattern fills in. Always false for a `topic` rule, which is taken as is.
+    fills_username: bool,
+    fills_client_id: bool
+}
+
+impl Rule {
+    // A pattern only applies to a client that has the username and identifier it fills in,
+    // and only if they're not empty and wouldn't add wildcards or levels
+    fn applies_to(&self, client: &ClientIdentity) -> bool {
+        let safe = |value: &str| { !value.is_empty() && !value.contains(['+', '#', '/']) };
+        (!self.fills_username || client.username.is_some_and(safe)) &&
+            (!self.fills_client_id || safe(client.client_id))
+    }
+
+    // Whether `rule_level`, filled in for `client` if this is a pattern, is `level`
+    fn level_is(&self, client: &ClientIdentity, rule_level: &str, level: &str) -> bool {
+        if self.fills_username || self.fills_client_id {
+            fills_to(rule_level, level, client)
+        } else {
+            rule_level == level
+        }
+    }
+
+    // https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718107
+    // Matches the way `TopicFilter::matches` does, filling patterns in a level at a time
+    // rather than building a filter for each client
+    fn matches(&self, client: &ClientIdentity, topic: &TopicName) -> bool {
+        if topic.is_system() && self.topic_filter.starts_with(['+', '#']) {
+            return false
+        }
+        let mut rule_levels = self.topic_filter.levels();
+        let mut topic_levels = topic.levels();
+        loop {
+            match (rule_levels.next(), topic_levels.next()) {
+                (Some("#"), _) => return true,
+                (Some("+"), Some(_)) => (),
+                (Some(rule_level), Some(level)) if self.level_is(client, rule_level, level) => (),
+                (None, None) => return true,
+                _ => return false
+            }
+        }
+    }
+
+    // Whether every topic `topic_filter` matches is also matched by this rule, walking both a
+    // level at a time
+    fn covers(&self, client: &ClientIdentity, topic_filter: &TopicFilter) -> bool {
+        let mut rule_levels = self.topic_filter.levels();
+        let mut filter_levels = topic_filter.levels();
+        loop {
+            match (rule_levels.next(), filter_levels.next()) {
+                (Some("#"), _) => return true,
+                (Some("+"), Some(level)) if level != "#" => (),
+                (Some(rule_level), Some(level)) if self.level_is(client, rule_level, level) => (),
+                (None, None) => return true,
+                _ => return false
+            }
+        }
+    }
+}
+
+// Whether a pattern's level, with %u and %c standing for the client's username and identifier,
+// is `level`. Any other '%' is taken as it is.
+fn fills_to(mut pattern: &str, mut level: &str, client: &ClientIdentity) -> bool {
+    while let Some(start) = pattern.find('%') {
+        let (value, len) = match &pattern[start..] {
+            rest if rest.starts_with("%u") => (client.username.unwrap_or(""), 2),
+            rest if rest.starts_with("%c") => (client.client_id, 2),
+            _ => ("%", 1)
+        };
+        level = match level.strip_prefix(&pattern[..start]).and_then(|rest| { rest.strip_prefix(value) }) {
+            Some(rest) => rest,
+            None => return false
+        };
+        pattern = &pattern[start + len..];
+    }
+    pattern == level
+}
+
+// Rules in the format of mosquitto's acl_file, one to a line:
+//
+//     topic [read|write|readwrite|deny] <filter>
+//     user <username>
+//     pattern [read|write|readwrite|deny] <filter>
+//
+// `topic` rules belong to the `user` line above them, or to clients without a username if
+// they come before any. `pattern` rules apply to everyone, with %u and %c in their filter
+// standing for the client's username and identifier. Access defaults to readwrite. Anything
+// not granted is refused, and `deny` refuses whatever it matches even if something else grants
+// it. Blank lines and lines starting with '#' are skipped.
+pub struct AclFile {
+    anonymous: Vec<Rule>,
+    users: HashMap<String, Vec<Rule>>,
+    patterns: Vec<Rule>
+}
+
+impl AclFile {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut contents = String::new();
+        File::open(path)?.read_to_string(&mut contents)?;
+        AclFile::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut acl_file = AclFile{ anonymous: Vec::new(), users: HashMap::new(), patterns: Vec::new() };
+        let mut user: Option<String> = None;
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue
+            }
+            let invalid = |problem: &str| { Error::new(ErrorKind::InvalidData, format!("line {}: {}", number + 1, problem)) };
+            let (keyword, rest) = AclFile::split_word(line);
+            match keyword {
+                "user" if !rest.is_empty() => {
+                    acl_file.users.entry(rest.to_string()).or_default();
+                    user = Some(rest.to_string());
+                },
+                "topic" | "pattern" => {
+                    let rule = AclFile::parse_rule(rest, keyword == "pattern").map_err(|e| { invalid(&e.to_string()) })?;
+                    match (keyword, &user) {
+                        ("pattern", _) => acl_file.patterns.push(rule),
+                        (_, Some(user)) => acl_file.users.get_mut(user).unwrap().push(rule),
+                        (_, None) => acl_file.anonymous.push(rule)
+                    }
+                },
+                _ => return Err(invalid("expected 'topic', 'user' or 'pattern'"))
+            }
+        }
+        Ok(acl_file)
+    }
+
+    fn split_word(line: &str) -> (&str, &str) {
+        match line.find(char::is_whitespace) {
+            Some(end) => (&line[..end], line[end..].trim_start()),
+            None => (line, "")
+        }
+    }
+
+    // The filter is the rest of the line, so it can contain spaces
+    fn parse_rule(rule: &str, pattern: bool) -> Result<Rule> {
+        let (first, rest) = AclFile::split_word(rule);
+        let (access, topic_filter) = match first {
+            "read" => (Access::Read, rest),
+            "write" => (Access::Write, rest),
+            "readwrite" => (Access::ReadWrite, rest),
+            "deny" => (Access::Deny, rest),
+            _ => (Access::ReadWrite, rule)
+        };
+        let topic_filter = TopicFilter::new(topic_filter.to_string())?;
+        Ok(Rule {
+            access,
+            fills_username: pattern && topic_filter.contains("%u"),
+            fills_client_id: pattern && topic_filter.contains("%c"),
+            topic_filter
+        })
+    }
+
+    // Every rule that applies to `client`: those for its username, or for clients without
+    // one, then whichever patterns can be filled in for it
+    fn rules<'s>(&'s self, client: &'s ClientIdentity<'s>) -> impl Iterator<Item = &'s Rule> + 's {
+        let own_rules = match client.username {
+            Some(username) => self.users.get(username).map_or(&[][..], |rules| { &rules[..] }),
+            None => &self.anonymous[..]
+        };
+        own_rules.iter().chain(self.patterns.iter().filter(move |pattern| { pattern.applies_to(client) }))
+    }
+
+    fn authorize_topic(&self, client: &ClientIdentity, wanted: Access, topic: &TopicName) -> bool {
+        let matching = || { self.rules(client).filter(|rule| { rule.matches(client, topic) }) };
+        !matching().any(|rule| { rule.access == Access::Deny }) && matching().any(|rule| { rule.access.allows(wanted) })
+    }
+}
+
+impl Authorizer for AclFile {
+    fn authorize_publish(&self, client: &ClientIdentity, topic: &TopicName) -> bool {
+        self.authorize_topic(client, Access::Write, topic)
+    }
+
+    // A subscription has to be covered by a rule that grants reading, and isn't allowed at all
+    // if a deny rule covers it. Deny rules narrower than the subscription are applied to each
+    // message instead.
+    fn authorize_subscribe(&self, client: &ClientIdentity, topic_filter: &TopicFilter) -> bool {
+        let covering = || { self.rules(client).filter(|rule| { rule.covers(client, topic_filter) }) };
+        !covering().any(|rule| { rule.access == Access::Deny }) &&
+            covering().any(|rule| { rule.access.allows(Access::Read) })
+    }
+
+    fn authorize_delivery(&self, client: &ClientIdentity, topic: &TopicName) -> bool {
+        self.authorize_topic(client, Access::Read, topic)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqtt::*;
+    use std::io::ErrorKind;
+
+    fn client<'a>(client_id: &'a str, username: Option<&'a str>) -> ClientIdentity<'a> {
+        ClientIdentity{ client_id, username }
+    }
+
+    fn topic(name: &str) -> TopicName<'_> {
+        TopicName::new(name).unwrap()
+    }
+
+    fn filter(filter: &str) -> TopicFilter<'_> {
+        TopicFilter::new(filter).unwrap()
+    }
+
+    #[test]
+    fn parses_topic_user_and_pattern_lines() {
+        let acl = AclFile::parse("
+            # Anonymous clients
+            topic read public/#
+
+            user alice
+            topic write alice/out
+            topic   alice/both
+            pattern deny secret/%c
+        ").unwrap();
+        let anonymous = client("anon", None);
+        assert!(acl.authorize_delivery(&anonymous, &topic("public/news")));
+        assert!(!acl.authorize_publish(&anonymous, &topic("public/news")));
+
+        let alice = client("phone", Some("alice"));
+        assert!(acl.authorize_publish(&alice, &topic("alice/out")));
+        assert!(!acl.authorize_delivery(&alice, &topic("alice/out")));
+        // Access defaults to readwrite, and the filter may follow more than one space
+        assert!(acl.authorize_publish(&alice, &topic("alice/both")));
+        assert!(acl.authorize_delivery(&alice, &topic("alice/both")));
+        // Alice's rules are her own, and anonymous ones aren't hers
+        assert!(!acl.authorize_publish(&client("x", Some("bob")), &topic("alice/out")));
+        assert!(!acl.authorize_delivery(&alice, &topic("public/news")));
+    }
+
+    #[test]
+    fn refuses_malformed_lines_with_their_number() {
+        for (contents, line) in &[("topic read a/#\nuser\n", 2), ("grant a/#", 1), ("\n\ntopic a/#/b", 3)] {
+            match AclFile::parse(contents) {
+                Err(e) => {
+                    assert_eq!(e.kind(), ErrorKind::InvalidData);
+                    assert!(e.to_string().starts_with(&format!("line {}:", line)), "{}", e);
+                },
+                Ok(_) => panic!("parsed {:?}", contents)
+            }
+        }
+    }
+
+    #[test]
+    fn patterns_fill_in_the_username_and_client_id() {
+        let acl = AclFile::parse("pattern readwrite users/%u/%c/#\npattern read id-%c").unwrap();
+        let alice = client("phone", Some("alice"));
+        assert!(acl.authorize_publish(&alice, &topic("users/alice/phone/x")));
+        assert!(!acl.authorize_publish(&alice, &topic("users/bob/phone/x")));
+        assert!(!acl.authorize_publish(&alice, &topic("users/alice/laptop/x")));
+        assert!(acl.authorize_delivery(&alice, &topic("id-phone")));
+        assert!(!acl.authorize_delivery(&alice, &topic("id-laptop")));
+        assert!(acl.authorize_subscribe(&alice, &filter("users/alice/phone/+")));
+        assert!(!acl.authorize_subscribe(&alice, &filter("users/+/phone/#")));
+        // Without a username, a pattern that needs one doesn't apply
+        assert!(!acl.authorize_publish(&client("phone", None), &topic("users/alice/phone/x")));
+        // `topic` rules and any other '%' are taken as they are
+        let acl = AclFile::parse("topic raw/%u\npattern 100%/%c").unwrap();
+        let anonymous = client("c", None);
+        assert!(acl.authorize_publish(&anonymous, &topic("raw/%u")));
+        assert!(acl.authorize_publish(&anonymous, &topic("100%/c")));
+        assert!(!acl.authorize_publish(&anonymous, &topic("100/c")));
+    }
+
+    #[test]
+    fn patterns_refuse_values_that_would_add_wildcards_or_levels() {
+        let acl = AclFile::parse("pattern users/%u/#\npattern clients/%c").unwrap();
+        for username in &["+", "#", "a/b", ""] {
+            let sneaky = client("c", Some(*username));
+            assert!(!acl.authorize_subscribe(&sneaky, &filter("users/+/#")), "username {:?}", username);
+            assert!(!acl.authorize_publish(&sneaky, &topic("users/a/b/x")), "username {:?}", username);
+        }
+        assert!(!acl.authorize_publish(&client("a/b", None), &topic("clients/a/b")));
+        assert!(!acl.authorize_subscribe(&client("#", None), &filter("clients/#")));
+    }
+
+    #[test]
+    fn deny_wins_over_any_grant() {
+        let acl = AclFile::parse("topic a/#\ntopic deny a/secret\npattern deny a/%c").unwrap();
+        let anonymous = client("mine", None);
+        assert!(acl.authorize_publish(&anonymous, &topic("a/b")));
+        assert!(!acl.authorize_publish(&anonymous, &topic("a/secret")));
+        assert!(!acl.authorize_delivery(&anonymous, &topic("a/secret")));
+        assert!(!acl.authorize_publish(&anonymous, &topic("a/mine")));
+        assert!(acl.authorize_publish(&client("other", None), &topic("a/mine")));
+    }
+
+    #[test]
+    fn subscriptions_must_be_covered_by_a_rule() {
+        let acl = AclFile::parse("topic read a/#\ntopic read b/+\ntopic deny a/secret\ntopic deny c/#").unwrap();
+        let anonymous = client("c", None);
+        assert!(acl.authorize_subscribe(&anonymous, &filter("a/b/+")));
+        assert!(acl.authorize_subscribe(&anonymous, &filter("b/+")));
+        assert!(!acl.authorize_subscribe(&anonymous, &filter("b/#")));
+        assert!(!acl.authorize_subscribe(&anonymous, &filter("b/c/d")));
+        // A deny narrower than the subscription is left to each delivery, a broader one refuses it
+        assert!(acl.authorize_subscribe(&anonymous, &filter("a/#")));
+        assert!(!acl.authorize_delivery(&anonymous, &topic("a/secret")));
+        assert!(!acl.authorize_subscribe(&anonymous, &filter("c/d")));
+    }
+
+    #[test]
+    fn wildcard_rules_skip_system_topics() {
+        let acl = AclFile::parse("topic #\npattern +/%c").unwrap();
+        let anonymous = client("c", None);
+        assert!(!acl.authorize_delivery(&anonymous, &topic("$SYS/uptime")));
+        assert!(!acl.authorize_delivery(&anonymous, &topic("$SYS/c")));
+        assert!(acl.authorize_delivery(&anonymous, &topic("a/c")));
+    }
+}
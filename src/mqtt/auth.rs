@@ -0,0 +1,309 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use mqtt::*;
+use sha2::{Digest, Sha512};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result};
+use std::path::Path;
+use std::result;
+
+// Who a connecting client says it is, from its CONNECT and its connection
+pub struct Credentials<'a> {
+    pub client_id: &'a str,
+    pub username: Option<&'a str>,
+    pub password: Option<&'a [u8]>,
+    pub addr: &'a PeerAddr,
+    pub peer: &'a PeerCredentials
+}
+
+// What the listener a client connected through has already established about it, before its
+// CONNECT, e.g. from a TLS client certificate. A username or client identifier here is used
+// in place of the one in the CONNECT.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PeerCredentials {
+    pub username: Option<String>,
+    pub client_id: Option<String>,
+    // The user and group of the process at the other end of a Unix socket, as the kernel
+    // tells it, which an `Authenticator` can trust in place of a password
+    pub uid: Option<u32>,
+    pub gid: Option<u32>
+}
+
+// Decides whether a client may connect. A refusal is the CONNACK return code to send it, after
+// which it's disconnected.
+pub trait Authenticator: Send {
+    fn authenticate(&self, credentials: &Credentials) -> result::Result<(), ConnackReturnCode>;
+}
+
+// Lets every client connect, with or without credentials
+pub struct AllowAnonymous;
+
+impl Authenticator for AllowAnonymous {
+    fn authenticate(&self, _credentials: &Credentials) -> result::Result<(), ConnackReturnCode> {
+        Ok(())
+    }
+}
+
+// Lets in the clients a listener has already vouched for without a password: processes on this
+// machine that connect over a Unix socket as one of the trusted users or groups, and, if
+// trusted, clients whose username the listener gave them, e.g. from a TLS client certificate.
+// Every other client is left to `otherwise`.
+pub struct TrustedPeers {
+    uids: HashSet<u32>,
+    gids: HashSet<u32>,
+    usernames: bool,
+    otherwise: Box<dyn Authenticator>
+}
+
+impl TrustedPeers {
+    pub fn new(otherwise: Box<dyn Authenticator>) -> Self {
+        TrustedPeers{ uids: HashSet::new(), gids: HashSet::new(), usernames: false, otherwise }
+    }
+
+    pub fn trust_uid(&mut self, uid: u32) {
+        self.uids.insert(uid);
+    }
+
+    pub fn trust_gid(&mut self, gid: u32) {
+        self.gids.insert(gid);
+    }
+
+    pub fn trust_usernames(&mut self) {
+        self.usernames = true;
+    }
+}
+
+impl Authenticator for TrustedPeers {
+    fn authenticate(&self, credentials: &Credentials) -> result::Result<(), ConnackReturnCode> {
+        let peer = credentials.peer;
+        if peer.uid.is_some_and(|uid| { self.uids.contains(&uid) }) ||
+            peer.gid.is_some_and(|gid| { self.gids.contains(&gid) }) ||
+            (self.usernames && peer.username.is_some()) {
+            return Ok(())
+        }
+        self.otherwise.authenticate(credentials)
+    }
+}
+
+// The iterations and salt length mosquitto_passwd uses for PBKDF2 hashes
+const PBKDF2_ITERATIONS: u32 = 101;
+const SALT_LEN: usize = 12;
+
+// A password as stored in a password file: never the password itself, only a salted hash
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PasswordHash {
+    // "$6$salt$hash": SHA-512 of the password followed by the salt
+    Sha512 { salt: Vec<u8>, hash: Vec<u8> },
+    // "$7$iterations$salt$hash": PBKDF2 with HMAC-SHA-512
+    Pbkdf2Sha512 { iterations: u32, salt: Vec<u8>, hash: Vec<u8> }
+}
+
+impl PasswordHash {
+    // Hashes `password` with PBKDF2 and a fresh random salt
+    pub fn new(password: &[u8]) -> Result<Self> {
+        let mut salt = vec![0u8; SALT_LEN];
+        File::open("/dev/urandom")?.read_exact(&mut salt)?;
+        Ok(PasswordHash::pbkdf2_sha512(password, PBKDF2_ITERATIONS, salt))
+    }
+
+    fn pbkdf2_sha512(password: &[u8], iterations: u32, salt: Vec<u8>) -> Self {
+        let mut hash = vec![0u8; 64];
+        pbkdf2::pbkdf2_hmac::<Sha512>(password, &salt, iterations, &mut hash);
+        PasswordHash::Pbkdf2Sha512{ iterations, salt, hash }
+    }
+
+    // Reads a hash in the format mosquitto_passwd writes
+    pub fn parse(encoded: &str) -> Result<Self> {
+        let fields: Vec<&str> = encoded.split('$').collect();
+        match fields.as_slice() {
+            ["", "6", salt, hash] =>
+                Ok(PasswordHash::Sha512{ salt: decode(salt)?, hash: decode(hash)? }),
+            ["", "7", iterations, salt, hash] => {
+                let iterations = iterations.parse().map_err(|_| {
+                    Error::new(ErrorKind::InvalidData, format!("'{}' is not a number of iterations", iterations))
+                })?;
+                Ok(PasswordHash::Pbkdf2Sha512{ iterations, salt: decode(salt)?, hash: decode(hash)? })
+            },
+            _ => Err(Error::new(ErrorKind::InvalidData, "password hashes must look like $6$salt$hash or $7$iterations$salt$hash"))
+        }
+    }
+
+    pub fn verify(&self, password: &[u8]) -> bool {
+        let (expected, actual) = match self {
+            PasswordHash::Sha512{ salt, hash } => {
+                let mut hasher = Sha512::new();
+                hasher.update(password);
+                hasher.update(salt);
+                (hash, hasher.finalize().to_vec())
+            },
+            PasswordHash::Pbkdf2Sha512{ iterations, salt, hash } => {
+                let mut actual = vec![0u8; hash.len()];
+                pbkdf2::pbkdf2_hmac::<Sha512>(password, salt, *iterations, &mut actual);
+                (hash, actual)
+            }
+        };
+        // Looks at every byte whatever happens, so how long this takes says nothing about how
+        // much of the hash matched
+        expected.len() == actual.len() &&
+            expected.iter().zip(actual.iter()).fold(0u8, |diff, (a, b)| { diff | (a ^ b) }) == 0
+    }
+}
+
+impl ::std::fmt::Display for PasswordHash {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match self {
+            PasswordHash::Sha512{ salt, hash } =>
+                write!(f, "$6${}${}", BASE64.encode(salt), BASE64.encode(hash)),
+            PasswordHash::Pbkdf2Sha512{ iterations, salt, hash } =>
+                write!(f, "$7${}${}${}", iterations, BASE64.encode(salt), BASE64.encode(hash))
+        }
+    }
+}
+
+fn decode(encoded: &str) -> Result<Vec<u8>> {
+    BASE64.decode(encoded).map_err(|e| { Error::new(ErrorKind::InvalidData, e) })
+}
+
+// Checks usernames and passwords against a mosquitto-style password file: a "username:hash"
+// line for each user, as written by mosquitto_passwd. Blank lines and lines starting with '#'
+// are skipped.
+pub struct PasswordFile {
+    users: HashMap<String, PasswordHash>,
+    allow_anonymous: bool
+}
+
+impl PasswordFile {
+    // Clients that don't give a username are only let in if `allow_anonymous` is set
+    pub fn new(allow_anonymous: bool) -> Self {
+        PasswordFile{ users: HashMap::new(), allow_anonymous }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P, allow_anonymous: bool) -> Result<Self> {
+        let mut contents = String::new();
+        File::open(path)?.read_to_string(&mut contents)?;
+        PasswordFile::parse(&contents, allow_anonymous)
+    }
+
+    pub fn parse(contents: &str, allow_anonymous: bool) -> Result<Self> {
+        let mut password_file = PasswordFile::new(allow_anonymous);
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue
+            }
+            // Usernames can't contain ':', but hashes could
+            let mut fields = line.splitn(2, ':');
+            match (fields.next(), fields.next()) {
+                (Some(username), Some(hash)) if !username.is_empty() => {
+                    let hash = PasswordHash::parse(hash).map_err(|e| {
+                        Error::new(ErrorKind::InvalidData, format!("line {}: {}", number + 1, e))
+                    })?;
+                    password_file.users.insert(username.to_string(), hash);
+                },
+                _ => return Err(Error::new(ErrorKind::InvalidData, format!("line {}: expected username:hash", number + 1)))
+            }
+        }
+        Ok(password_file)
+    }
+
+    pub fn insert(&mut self, username: String, hash: PasswordHash) -> Option<PasswordHash> {
+        self.users.insert(username, hash)
+    }
+
+    pub fn remove(&mut self, username: &str) -> Option<PasswordHash> {
+        self.users.remove(username)
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+}
+
+impl Authenticator for PasswordFile {
+    fn authenticate(&self, credentials: &Credentials) -> result::Result<(), ConnackReturnCode> {
+        let username = match credentials.username {
+            Some(username) => username,
+            None if self.allow_anonymous => return Ok(()),
+            None => return Err(ConnackReturnCode::NotAuthorized)
+        };
+        match (self.users.get(username), credentials.password) {
+            (Some(hash), Some(password)) if hash.verify(password) => Ok(()),
+            _ => Err(ConnackReturnCode::BadUsernameOrPassword)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqtt::*;
+    use std::net::SocketAddr;
+
+    fn authenticate<A: Authenticator>(authenticator: &A,
+                                      username: Option<&str>,
+                                      password: Option<&[u8]>,
+                                      peer: PeerCredentials
+    ) -> Result<(), ConnackReturnCode> {
+        let addr = PeerAddr::from(SocketAddr::from(([127, 0, 0, 1], 1883)));
+        authenticator.authenticate(&Credentials{ client_id: "client", username, password, addr: &addr, peer: &peer })
+    }
+
+    fn local(uid: u32, gid: u32) -> PeerCredentials {
+        PeerCredentials{ uid: Some(uid), gid: Some(gid), ..PeerCredentials::default() }
+    }
+
+    #[test]
+    fn password_file_checks_passwords() {
+        let mut password_file = PasswordFile::new(false);
+        password_file.insert("alice".to_string(), PasswordHash::new(b"secret").unwrap());
+        let peer = PeerCredentials::default;
+        assert_eq!(authenticate(&password_file, Some("alice"), Some(b"secret"), peer()), Ok(()));
+        assert_eq!(authenticate(&password_file, Some("alice"), Some(b"wrong"), peer()),
+                   Err(ConnackReturnCode::BadUsernameOrPassword));
+        assert_eq!(authenticate(&password_file, Some("bob"), Some(b"secret"), peer()),
+                   Err(ConnackReturnCode::BadUsernameOrPassword));
+        assert_eq!(authenticate(&password_file, None, None, peer()), Err(ConnackReturnCode::NotAuthorized));
+        // Whatever the listener says about the client, the password file only checks passwords
+        let vouched = || { PeerCredentials{ username: Some("alice".to_string()), ..PeerCredentials::default() } };
+        assert_eq!(authenticate(&password_file, Some("alice"), None, vouched()),
+                   Err(ConnackReturnCode::BadUsernameOrPassword));
+        assert_eq!(authenticate(&password_file, None, None, vouched()), Err(ConnackReturnCode::NotAuthorized));
+    }
+
+    #[test]
+    fn password_hashes_round_trip_through_their_text_form() {
+        let hash = PasswordHash::new(b"secret").unwrap();
+        let parsed = PasswordHash::parse(&hash.to_string()).unwrap();
+        assert_eq!(parsed, hash);
+        assert!(parsed.verify(b"secret"));
+        assert!(!parsed.verify(b"Secret"));
+    }
+
+    #[test]
+    fn trusted_peers_need_no_password() {
+        let mut trusted_peers = TrustedPeers::new(Box::new(PasswordFile::new(false)));
+        trusted_peers.trust_uid(1000);
+        trusted_peers.trust_gid(50);
+        assert_eq!(authenticate(&trusted_peers, None, None, local(1000, 1000)), Ok(()));
+        assert_eq!(authenticate(&trusted_peers, None, None, local(1001, 50)), Ok(()));
+        // Everyone else is left to the password file
+        assert_eq!(authenticate(&trusted_peers, None, None, local(1001, 1001)), Err(ConnackReturnCode::NotAuthorized));
+        assert_eq!(authenticate(&trusted_peers, None, None, PeerCredentials::default()),
+                   Err(ConnackReturnCode::NotAuthorized));
+    }
+
+    #[test]
+    fn usernames_from_the_listener_are_only_trusted_when_asked() {
+        let vouched = || { PeerCredentials{ username: Some("alice".to_string()), ..PeerCredentials::default() } };
+        let mut trusted_peers = TrustedPeers::new(Box::new(PasswordFile::new(false)));
+        assert_eq!(authenticate(&trusted_peers, None, None, vouched()), Err(ConnackReturnCode::NotAuthorized));
+        trusted_peers.trust_usernames();
+        assert_eq!(authenticate(&trusted_peers, None, None, vouched()), Ok(()));
+        assert_eq!(authenticate(&trusted_peers, None, None, PeerCredentials::default()),
+                   Err(ConnackReturnCode::NotAuthorized));
+    }
+}
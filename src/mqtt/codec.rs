@@ -0,0 +1,171 @@
+use bytes::BytesMut;
+use mqtt::*;
+use std::io::{Error, ErrorKind, Result};
+use tokio::codec::{Decoder, Encoder};
+
+// Frames MQTT packets on a byte stream, so that `Framed<TcpStream, MqttCodec>` is a
+// `Stream` and `Sink` of messages. Starts out speaking MQTT 3.1.1, and switches to whichever
+// version a CONNECT going either way announces.
+pub struct MqttCodec {
+    version: ProtocolVersion,
+    max_packet_size: usize
+}
+
+// The biggest packet there can be: a byte of type and flags, four of remaining length, and the
+// most remaining length there can be
+pub const MAX_PACKET_SIZE: usize = 5 + RemainingLength::MAX_SIZE as usize;
+
+impl Default for MqttCodec {
+    fn default() -> Self {
+        MqttCodec::new()
+    }
+}
+
+impl MqttCodec {
+    pub fn new() -> Self {
+        MqttCodec::with_max_packet_size(MAX_PACKET_SIZE)
+    }
+
+    // Refuses to decode any packet longer than `max_packet_size` bytes, fixed header included,
+    // as soon as its remaining length says it will be
+    pub fn with_max_packet_size(max_packet_size: usize) -> Self {
+        MqttCodec{ version: ProtocolVersion::default(), max_packet_size }
+    }
+
+    pub fn version(&self) -> ProtocolVersion {
+        self.version
+    }
+}
+
+impl Decoder for MqttCodec {
+    type Item = OwnedMessage;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<OwnedMessage>> {
+        // The fixed header is one byte of type and flags, then up to four of remaining length
+        if src.len() < 2 {
+            return Ok(None)
+        }
+        let (remaining_length, remaining_length_size) = match RemainingLength::parse(&src[1..]) {
+            Ok(decoded) => decoded,
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e)
+        };
+        let remaining_length: u32 = remaining_length.into();
+        let packet_size = 1 + remaining_length_size + remaining_length as usize;
+        if packet_size > self.max_packet_size {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("packet of {} bytes is over the maximum of {}", packet_size, self.max_packet_size)
+            ))
+        }
+        // The buffer grows as the rest arrives, rather than trusting the peer with how much
+        // to set aside for it
+        if src.len() < packet_size {
+            return Ok(None)
+        }
+        let packet = src.split_to(packet_size);
+        let (message, _) = Message::parse_with(self.version, &packet)?;
+        if let Message::Connect{ protocol_version, .. } = message {
+            self.version = protocol_version;
+        }
+        Ok(Some(message.into_owned()))
+    }
+}
+
+impl Encoder for MqttCodec {
+    type Item = OwnedMessage;
+    type Error = Error;
+
+    fn encode(&mut self, item: OwnedMessage, dst: &mut BytesMut) -> Result<()> {
+        if let Message::Connect{ protocol_version, .. } = item {
+            self.version = protocol_version;
+        }
+        let mut buf = Vec::new();
+        item.ser_with(self.version, &mut buf)?;
+        dst.extend_from_slice(&buf);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use mqtt::*;
+    use std::borrow::Cow;
+    use std::io::ErrorKind;
+    use tokio::codec::{Decoder, Encoder};
+
+    fn connect() -> OwnedMessage {
+        Message::Connect {
+            protocol_version: ProtocolVersion::V311,
+            client_id: Cow::Borrowed("codec"),
+            username: None,
+            password: None,
+            will: None,
+            clean_session: true,
+            keep_alive: 60,
+            properties: Vec::new()
+        }
+    }
+
+    #[test]
+    fn decodes_a_packet_once_all_of_it_has_arrived() {
+        let mut codec = MqttCodec::new();
+        let mut encoded = BytesMut::new();
+        codec.encode(connect(), &mut encoded).unwrap();
+
+        let mut src = BytesMut::from(&encoded[..encoded.len() - 1]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(&encoded[encoded.len() - 1..]);
+        match codec.decode(&mut src).unwrap() {
+            Some(Message::Connect{ client_id, keep_alive, .. }) => {
+                assert_eq!(client_id, "codec");
+                assert_eq!(keep_alive, 60);
+            },
+            _ => panic!("expected a CONNECT")
+        }
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn refuses_packets_over_the_maximum_size() {
+        let mut codec = MqttCodec::with_max_packet_size(1024);
+        let mut src = BytesMut::from(&[0x10, 0xFF, 0xFF, 0xFF, 0x7F][..]);
+        match codec.decode(&mut src) {
+            Err(e) => assert_eq!(e.kind(), ErrorKind::InvalidData),
+            Ok(_) => panic!("decoded a packet over the maximum size")
+        }
+    }
+
+    #[test]
+    fn does_not_reserve_what_the_remaining_length_claims() {
+        let mut codec = MqttCodec::new();
+        let mut src = BytesMut::from(&[0x10, 0xFF, 0xFF, 0xFF, 0x7F][..]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        assert!(src.capacity() < 1024);
+    }
+
+    // The conformance statement the codec says a malformed packet breaks
+    fn broken_rule(codec: &mut MqttCodec, packet: &[u8]) -> &'static str {
+        match codec.decode(&mut BytesMut::from(packet)) {
+            Err(e) => ProtocolError::from_error(&e).expect("expected a protocol error").rule(),
+            Ok(_) => panic!("decoded a malformed packet")
+        }
+    }
+
+    #[test]
+    fn refuses_a_qos_of_3_for_a_subscription() {
+        let subscribe = [0x82, 0x06, 0x00, 0x01, 0x00, 0x01, b'a', 0x03];
+        assert_eq!(broken_rule(&mut MqttCodec::new(), &subscribe), "MQTT-3.8.3-4");
+    }
+
+    #[test]
+    fn refuses_a_qos_of_3_for_a_will() {
+        let connect = [
+            0x10, 0x12, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04, 0x1E, 0x00, 0x3C,
+            0x00, 0x01, b'c', 0x00, 0x01, b't', 0x00, 0x00
+        ];
+        assert_eq!(broken_rule(&mut MqttCodec::new(), &connect), "MQTT-3.1.2-14");
+    }
+}
@@ -0,0 +1,43 @@
+use mqtt::*;
+use std::time::Duration;
+
+// How the broker treats every client. `Config::default()` is a reasonable place to start.
+#[derive(Clone, Debug)]
+pub struct Config {
+    // How many QoS 1 and 2 messages a client can have unacknowledged at once. Any more wait in
+    // its session until it catches up. MQTT 5 clients can ask for fewer.
+    pub max_inflight: usize,
+    // How many messages a session holds for its client while it's away or catching up. Once
+    // that many are waiting, the oldest QoS 0 message makes way for a new one, and without any
+    // the new one is dropped.
+    pub max_queued: usize,
+    // How long to wait for an acknowledgement before sending a message again, on top of
+    // sending everything unacknowledged again when the client reconnects. MQTT 5 only allows
+    // the latter, so this is never done for MQTT 5 clients.
+    pub retry_interval: Option<Duration>,
+    // The longest keep alive, in seconds, a client can have. Clients that ask for longer, or for
+    // none at all, get this instead.
+    pub max_keep_alive: Option<u16>,
+    // How long a new connection has to finish any TLS or WebSocket handshake, and then again
+    // to send its CONNECT, before it's closed
+    pub connect_timeout: Duration,
+    // The highest QoS any subscription is granted. Deliveries never go out above it.
+    pub max_qos: QualityOfService,
+    // The longest packet, in bytes, a client can send. Anything longer closes its connection
+    // before it's been read. MQTT 5 clients are told what it is.
+    pub max_packet_size: usize
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_inflight: 20,
+            max_queued: 1000,
+            retry_interval: None,
+            max_keep_alive: None,
+            connect_timeout: Duration::from_secs(10),
+            max_qos: QualityOfService::ExactlyOnce,
+            max_packet_size: 1 << 20
+        }
+    }
+}
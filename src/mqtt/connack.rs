@@ -1,4 +1,7 @@
-#[derive(Copy, Clone)]
+use mqtt::*;
+use std::io::{Error, ErrorKind, Result};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum ConnackReturnCode {
     /* 0 */    Accepted,
     /* 1 */    UnacceptableProtocolVersion,
@@ -6,4 +9,65 @@ pub enum ConnackReturnCode {
     /* 3 */    ServerUnavailable,
     /* 4 */    BadUsernameOrPassword,
     /* 5 */    NotAuthorized,
+    // MQTT 5 refusals with no MQTT 3.1.1 equivalent
+    Refused(ReasonCode)
+}
+
+impl ConnackReturnCode {
+    pub fn to_byte(&self, version: ProtocolVersion) -> Result<u8> {
+        match (version, self) {
+            (_, ConnackReturnCode::Accepted) => Ok(0),
+            (ProtocolVersion::V5, ConnackReturnCode::UnacceptableProtocolVersion) =>
+                Ok(ReasonCode::UnsupportedProtocolVersion.to_byte()),
+            (ProtocolVersion::V5, ConnackReturnCode::IdentifierRejected) =>
+                Ok(ReasonCode::ClientIdentifierNotValid.to_byte()),
+            (ProtocolVersion::V5, ConnackReturnCode::ServerUnavailable) =>
+                Ok(ReasonCode::ServerUnavailable.to_byte()),
+            (ProtocolVersion::V5, ConnackReturnCode::BadUsernameOrPassword) =>
+                Ok(ReasonCode::BadUserNameOrPassword.to_byte()),
+            (ProtocolVersion::V5, ConnackReturnCode::NotAuthorized) =>
+                Ok(ReasonCode::NotAuthorized.to_byte()),
+            (ProtocolVersion::V5, ConnackReturnCode::Refused(code)) =>
+                Ok(code.to_byte()),
+            (_, ConnackReturnCode::UnacceptableProtocolVersion) => Ok(1),
+            (_, ConnackReturnCode::IdentifierRejected) => Ok(2),
+            (_, ConnackReturnCode::ServerUnavailable) => Ok(3),
+            (_, ConnackReturnCode::BadUsernameOrPassword) => Ok(4),
+            (_, ConnackReturnCode::NotAuthorized) => Ok(5),
+            (_, ConnackReturnCode::Refused(code)) => {
+                let msg = format!("{:?} has no MQTT 3.1 or 3.1.1 connack return code", code);
+                Err(Error::new(ErrorKind::InvalidInput, msg))
+            }
+        }
+    }
+
+    pub fn from_byte(version: ProtocolVersion, b: u8) -> Result<Self> {
+        match version {
+            ProtocolVersion::V31 | ProtocolVersion::V311 => match b {
+                0 => Ok(ConnackReturnCode::Accepted),
+                1 => Ok(ConnackReturnCode::UnacceptableProtocolVersion),
+                2 => Ok(ConnackReturnCode::IdentifierRejected),
+                3 => Ok(ConnackReturnCode::ServerUnavailable),
+                4 => Ok(ConnackReturnCode::BadUsernameOrPassword),
+                5 => Ok(ConnackReturnCode::NotAuthorized),
+                n => {
+                    let msg = format!("{} is not a valid connack return code: [0, 6)", n);
+                    Err(Error::new(ErrorKind::InvalidData, msg))
+                }
+            },
+            ProtocolVersion::V5 => match ReasonCode::from_byte(b)? {
+                ReasonCode::Success => Ok(ConnackReturnCode::Accepted),
+                ReasonCode::UnsupportedProtocolVersion => Ok(ConnackReturnCode::UnacceptableProtocolVersion),
+                ReasonCode::ClientIdentifierNotValid => Ok(ConnackReturnCode::IdentifierRejected),
+                ReasonCode::ServerUnavailable => Ok(ConnackReturnCode::ServerUnavailable),
+                ReasonCode::BadUserNameOrPassword => Ok(ConnackReturnCode::BadUsernameOrPassword),
+                ReasonCode::NotAuthorized => Ok(ConnackReturnCode::NotAuthorized),
+                code if code.is_failure() => Ok(ConnackReturnCode::Refused(code)),
+                code => {
+                    let msg = format!("{:?} is not a valid connack reason code", code);
+                    Err(Error::new(ErrorKind::InvalidData, msg))
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,336 @@
+use futures::future::Either;
+use futures::sync::{mpsc, oneshot};
+use futures::{Future, Sink, Stream};
+use mqtt::*;
+use std::io::{Error, ErrorKind};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::codec::Framed;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::timer::{Interval, Timeout};
+
+// Where a client is connected from, which tells its connection apart from every other. Clients
+// of a Unix socket are almost never bound to a path of their own, so their connections are
+// numbered by the listener instead.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum PeerAddr {
+    Inet(SocketAddr),
+    Unix(u64)
+}
+
+impl From<SocketAddr> for PeerAddr {
+    fn from(addr: SocketAddr) -> Self {
+        PeerAddr::Inet(addr)
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PeerAddr::Inet(addr) => write!(f, "{}", addr),
+            PeerAddr::Unix(number) => write!(f, "unix:{}", number)
+        }
+    }
+}
+
+// Where a connection's outgoing messages are queued. The connection is closed once every
+// sender for it has been dropped and whatever was queued has been written.
+pub type Outgoing = mpsc::UnboundedSender<OwnedMessage>;
+
+// Serves one client over `socket`: its first packet must be a CONNECT, and everything after
+// that is handed to `sessions`, which answers through the connection's `Outgoing` queue. The
+// returned future resolves once the connection has closed and its session has been told.
+// `peer` is whatever the listener knows about the client, and is checked along with its CONNECT.
+pub fn handle_connection<S>(
+    socket: S,
+    addr: PeerAddr,
+    peer: PeerCredentials,
+    sessions: Arc<Mutex<Sessions>>
+) -> impl Future<Item = (), Error = ()> + Send
+where S: AsyncRead + AsyncWrite + Send + 'static
+{
+    let (max_packet_size, connect_timeout) = {
+        let sessions = sessions.lock().unwrap();
+        (sessions.config().max_packet_size, sessions.config().connect_timeout)
+    };
+    let (sink, stream) = Framed::new(socket, MqttCodec::with_max_packet_size(max_packet_size)).split();
+    let (outgoing, queued) = mpsc::unbounded();
+    let (closed, on_closed) = oneshot::channel::<()>();
+
+    let writer = sink
+        .send_all(queued.map_err(|()| Error::other("outgoing queue failed")))
+        .then(move |result| {
+            if let Err(e) = result {
+                eprintln!("write error\t{}\t{}", addr, e);
+            }
+            let _ = closed.send(());
+            Ok(())
+        });
+
+    // https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc385349240
+    let connect_sessions = sessions.clone();
+    // A connection that doesn't get as far as a CONNECT doesn't get to stay open either
+    let handshake = Timeout::new(stream.into_future(), connect_timeout).then(move |first| {
+        match first {
+            Ok((Some(msg @ Message::Connect { .. }), rest)) => {
+                let keep_alive = connect_sessions.lock().unwrap().handle_connect(&addr, &peer, outgoing, msg)?;
+                Ok((rest, keep_alive))
+            },
+            Ok((Some(_), _)) =>
+                Err(Error::new(ErrorKind::InvalidData, "the first packet must be a CONNECT [MQTT-3.1.0-1]")),
+            Ok((None, _)) =>
+                Err(Error::new(ErrorKind::UnexpectedEof, "connection closed before CONNECT")),
+            Err(ref e) if e.is_elapsed() =>
+                Err(Error::new(ErrorKind::TimedOut, "no CONNECT before the connect timeout")),
+            Err(e) => match e.into_inner() {
+                Some((e, _)) => {
+                    if let Some(unsupported) = UnsupportedProtocolVersion::from_error(&e) {
+                        let _ = outgoing.unbounded_send(unsupported.connack());
+                    }
+                    Err(e)
+                },
+                None => Err(Error::other("timer failed while waiting for CONNECT"))
+            }
+        }
+    });
+
+    let message_sessions = sessions.clone();
+    let messages = handshake.and_then(move |(rest, keep_alive)| {
+        let handle_message = move |msg| { message_sessions.lock().unwrap().handle_message(&addr, msg) };
+        match keep_alive {
+            Some(keep_alive) => Either::A(
+                Timeout::new(rest, keep_alive)
+                    .map_err(|e| {
+                        if e.is_elapsed() {
+                            Error::new(ErrorKind::TimedOut, "keep alive expired [MQTT-3.1.2-24]")
+                        } else if e.is_inner() {
+                            e.into_inner().unwrap()
+                        } else {
+                            Error::other(e.into_timer().unwrap())
+                        }
+                    })
+                    .for_each(handle_message)
+            ),
+            None => Either::B(rest.for_each(handle_message))
+        }
+    });
+
+    // Reading stops when the client goes away, or when the writer finishes because the
+    // session was closed from our side
+    let reader = messages.select2(on_closed).then(move |result| {
+        if let Err(Either::A((e, _))) = result {
+            eprintln!("connection error\t{}\t{}", addr, e);
+        }
+        sessions.lock().unwrap().handle_disconnected(&addr);
+        Ok(())
+    });
+
+    reader.join(writer).map(|_| ())
+}
+
+// Fails `handshake`, e.g. a listener's TLS or WebSocket handshake, if it hasn't finished within
+// `timeout`
+pub fn handshake_timeout<F>(handshake: F, timeout: Duration) -> impl Future<Item = F::Item, Error = Error> + Send
+where F: Future<Error = Error> + Send
+{
+    Timeout::new(handshake, timeout).map_err(|e| {
+        if e.is_elapsed() {
+            Error::new(ErrorKind::TimedOut, "handshake didn't finish before the connect timeout")
+        } else if e.is_inner() {
+            e.into_inner().unwrap()
+        } else {
+            Error::other(e.into_timer().unwrap())
+        }
+    })
+}
+
+// Every `interval`, ends the sessions whose clients have been gone too long
+pub fn expire_sessions(sessions: Arc<Mutex<Sessions>>, interval: Duration) -> impl Future<Item = (), Error = ()> + Send {
+    Interval::new(Instant::now() + interval, interval)
+        .map_err(|e| { eprintln!("timer error\t{}", e) })
+        .for_each(move |_| {
+            sessions.lock().unwrap().expire_sessions();
+            Ok(())
+        })
+}
+
+// Every `interval`, sends again whatever has been waiting at least that long for an
+// acknowledgement, so nothing waits much longer than twice `interval`
+pub fn retry_unacknowledged(sessions: Arc<Mutex<Sessions>>, interval: Duration) -> impl Future<Item = (), Error = ()> + Send {
+    Interval::new(Instant::now() + interval, interval)
+        .map_err(|e| { eprintln!("timer error\t{}", e) })
+        .for_each(move |_| {
+            sessions.lock().unwrap().retry_unacknowledged();
+            Ok(())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::runtime::current_thread::Runtime;
+
+    #[test]
+    fn fails_handshakes_that_take_too_long() {
+        let mut runtime = Runtime::new().unwrap();
+        let never = futures::future::empty::<(), Error>();
+        let result = runtime.block_on(handshake_timeout(never, Duration::from_millis(50)));
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::TimedOut);
+    }
+
+    // Connections are tested over Unix socket pairs, as there's no counting on TCP in a test
+    #[cfg(unix)]
+    mod socket_pairs {
+        use super::*;
+        use std::borrow::Cow;
+        use std::io::{Read, Write};
+        use std::os::unix::net;
+        use std::thread;
+        use tokio::net::UnixStream;
+        use tokio::reactor::Handle;
+
+        // Serves a connection on its own thread, and returns the client's end of it along with
+        // the thread, which finishes once the connection has closed
+        fn serve(sessions: &Arc<Mutex<Sessions>>, number: u64) -> (net::UnixStream, thread::JoinHandle<::std::result::Result<(), ()>>) {
+            let (client, server) = net::UnixStream::pair().unwrap();
+            client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let sessions = sessions.clone();
+            let connection = thread::spawn(move || {
+                let mut runtime = Runtime::new().unwrap();
+                let server = UnixStream::from_std(server, &Handle::default()).unwrap();
+                runtime.block_on(handle_connection(server, PeerAddr::Unix(number), PeerCredentials::default(), sessions))
+            });
+            (client, connection)
+        }
+
+        #[test]
+        fn closes_connections_that_never_send_a_connect() {
+            let config = Config{ connect_timeout: Duration::from_millis(100), ..Config::default() };
+            let (mut client, connection) = serve(&Arc::new(Mutex::new(Sessions::with_config(config))), 1);
+            assert_eq!(client.read(&mut [0u8; 16]).unwrap(), 0);
+            connection.join().unwrap().unwrap();
+        }
+
+        #[test]
+        fn closes_connections_whose_first_packet_isnt_a_connect() {
+            let (mut client, connection) = serve(&Arc::new(Mutex::new(Sessions::new())), 1);
+            // A PINGREQ [MQTT-3.1.0-1]
+            client.write_all(&[0xC0, 0]).unwrap();
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).unwrap();
+            assert!(received.is_empty());
+            connection.join().unwrap().unwrap();
+        }
+
+        // A CONNECT at protocol level 4 for a one-letter client id, with a clean session
+        fn connect(client_id: u8) -> [u8; 15] {
+            [0x10, 13, 0, 4, b'M', b'Q', b'T', b'T', 4, 0x02, 0, 60, 0, 1, client_id]
+        }
+
+        fn read(client: &mut net::UnixStream, len: usize) -> Vec<u8> {
+            let mut received = vec![0; len];
+            client.read_exact(&mut received).unwrap();
+            received
+        }
+
+        #[test]
+        fn serves_clients_from_connect_to_disconnect() {
+            let sessions = Arc::new(Mutex::new(Sessions::new()));
+            let (mut subscriber, subscribed) = serve(&sessions, 1);
+            subscriber.write_all(&connect(b's')).unwrap();
+            assert_eq!(read(&mut subscriber, 4), [0x20, 2, 0, 0]);
+            // SUBSCRIBE to "t" at QoS 0, granted QoS 0
+            subscriber.write_all(&[0x82, 6, 0, 1, 0, 1, b't', 0]).unwrap();
+            assert_eq!(read(&mut subscriber, 5), [0x90, 3, 0, 1, 0]);
+
+            let (mut publisher, published) = serve(&sessions, 2);
+            publisher.write_all(&connect(b'p')).unwrap();
+            assert_eq!(read(&mut publisher, 4), [0x20, 2, 0, 0]);
+            // PUBLISH "hi" to "t" at QoS 0
+            let publish = [0x30, 5, 0, 1, b't', b'h', b'i'];
+            publisher.write_all(&publish).unwrap();
+            assert_eq!(read(&mut subscriber, publish.len()), publish);
+
+            for (mut client, connection) in [(publisher, published), (subscriber, subscribed)] {
+                client.write_all(&[0xE0, 0]).unwrap();
+                let mut received = Vec::new();
+                client.read_to_end(&mut received).unwrap();
+                assert!(received.is_empty());
+                connection.join().unwrap().unwrap();
+            }
+        }
+
+        #[test]
+        fn refuses_mqtt_3_1_under_the_mqtt_protocol_name() {
+            let (mut client, connection) = serve(&Arc::new(Mutex::new(Sessions::new())), 1);
+            // A CONNECT saying "MQTT" at protocol level 3, which only MQTT 3.1's "MQIsdp" can
+            client.write_all(&[0x10, 13, 0, 4, b'M', b'Q', b'T', b'T', 3, 0x02, 0, 60, 0, 1, b'c']).unwrap();
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).unwrap();
+            // Return code 1, "unacceptable protocol version" [MQTT-3.1.2-2]
+            assert_eq!(received, [0x20, 2, 0, 1]);
+            connection.join().unwrap().unwrap();
+        }
+
+        #[test]
+        fn publishes_the_will_of_a_client_whose_keep_alive_expires() {
+            let sessions = Arc::new(Mutex::new(Sessions::new()));
+            let (outgoing, watched) = mpsc::unbounded();
+            {
+                let mut sessions = sessions.lock().unwrap();
+                let watcher = PeerAddr::Unix(1);
+                let connect = Message::Connect {
+                    protocol_version: ProtocolVersion::V311,
+                    client_id: Cow::Borrowed("watcher"),
+                    username: None,
+                    password: None,
+                    will: None,
+                    clean_session: true,
+                    keep_alive: 0,
+                    properties: Vec::new()
+                };
+                sessions.handle_connect(&watcher, &PeerCredentials::default(), outgoing, connect).unwrap();
+                let subscribe = Message::Subscribe {
+                    packet_id: 1,
+                    topic_filters: vec![(SubscribeFilter::new("will"), SubscriptionOptions::new(QualityOfService::AtMostOnce))],
+                    properties: Vec::new()
+                };
+                sessions.handle_message(&watcher, subscribe).unwrap();
+            }
+
+            let (mut client, connection) = serve(&sessions, 2);
+            let connect = Message::Connect {
+                protocol_version: ProtocolVersion::V311,
+                client_id: Cow::Borrowed("quiet"),
+                username: None,
+                password: None,
+                will: Some(Will {
+                    retain: false,
+                    qos: QualityOfService::AtMostOnce,
+                    topic: TopicName::new("will").unwrap(),
+                    message: Cow::Borrowed(b"gone"),
+                    properties: Vec::new()
+                }),
+                clean_session: true,
+                keep_alive: 1,
+                properties: Vec::new()
+            };
+            let mut bytes = Vec::new();
+            connect.ser_with(ProtocolVersion::V311, &mut bytes).unwrap();
+            client.write_all(&bytes).unwrap();
+
+            // After the CONNACK, nothing until the connection is closed a second and a half later
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).unwrap();
+            assert_eq!(received, [0x20, 2, 0, 0]);
+            connection.join().unwrap().unwrap();
+            match watched.take(3).collect().wait().unwrap().as_slice() {
+                [Message::Connack{ .. }, Message::Suback{ .. }, Message::Publish{ topic, .. }] => assert_eq!(&**topic, "will"),
+                _ => panic!("expected the will to be published")
+            }
+        }
+    }
+}
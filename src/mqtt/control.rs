@@ -1,6 +1,6 @@
 use std::io::{Error, ErrorKind, Result};
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum ControlPacketType {
     ReservedLow,
     Connect,
@@ -17,7 +17,8 @@ pub enum ControlPacketType {
     Pingreq,
     Pingresp,
     Disconnect,
-    ReservedHigh
+    // Reserved in MQTT 3.1.1
+    Auth
 }
 
 impl ControlPacketType {
@@ -38,7 +39,7 @@ impl ControlPacketType {
             ControlPacketType::Pingreq => 12,
             ControlPacketType::Pingresp => 13,
             ControlPacketType::Disconnect => 14,
-            ControlPacketType::ReservedHigh => 15
+            ControlPacketType::Auth => 15
         }
     }
 
@@ -59,7 +60,7 @@ impl ControlPacketType {
             12 => Ok(ControlPacketType::Pingreq),
             13 => Ok(ControlPacketType::Pingresp),
             14 => Ok(ControlPacketType::Disconnect),
-            15 => Ok(ControlPacketType::ReservedHigh),
+            15 => Ok(ControlPacketType::Auth),
             n => {
                 let msg = format!("{} is not a valid control packet type: [0, 16)", n);
                 Err(Error::new(ErrorKind::InvalidData, msg))
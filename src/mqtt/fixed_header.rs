@@ -1,4 +1,4 @@
-use std::io::{Read, Result, Write};
+use std::io::{Error, ErrorKind, Read, Result, Write};
 use mqtt::*;
 
 // https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Figure_2.2_-
@@ -13,44 +13,76 @@ pub struct FixedHeader {
 impl Copy for FixedHeader{}
 
 impl FixedHeader {
-    fn to_first_byte(&self) -> u8 {
+    fn to_first_byte(self) -> u8 {
         let ctrl_bits = self.control_packet_type.to_byte();
         let flag_bits = self.flags
             .iter()
-            .enumerate()
-            .fold(
-                0_u8,
-                |acc, (idx, bit)| {acc + (*bit as u8) << (4_u8 - idx as u8)}
-            );
-        (ctrl_bits << 4) & flag_bits
+            .fold(0_u8, |acc, bit| { (acc << 1) | (*bit as u8) });
+        (ctrl_bits << 4) | flag_bits
     }
 
     fn from_first_byte(b: u8) -> Result<(ControlPacketType, [bool; 4])> {
         let ctrl_type = ControlPacketType::from_byte(b >> 4)?;
         let mut flags = [false; 4];
-        for idx in 0..3 {
-            flags[idx] = (b & (1u8 << idx)) != 0;
+        for (idx, flag) in flags.iter_mut().enumerate() {
+            *flag = (b & (8u8 >> idx)) != 0;
         }
         Ok((ctrl_type, flags))
     }
+
+    // https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Table_2.2_-
+    // Only PUBLISH uses its flags, PUBREL, SUBSCRIBE and UNSUBSCRIBE must set exactly 0b0010,
+    // and every other packet 0b0000
+    pub fn check_flags(&self) -> ::std::result::Result<(), ProtocolError> {
+        let expected = match self.control_packet_type {
+            ControlPacketType::Publish if self.flags[1] && self.flags[2] =>
+                return Err(ProtocolError::InvalidQos(3)),
+            ControlPacketType::Publish =>
+                return Ok(()),
+            ControlPacketType::Pubrel | ControlPacketType::Subscribe | ControlPacketType::Unsubscribe =>
+                [false, false, true, false],
+            _ =>
+                [false, false, false, false]
+        };
+        if self.flags == expected {
+            Ok(())
+        } else {
+            Err(ProtocolError::ReservedFlags { packet_type: self.control_packet_type, flags: self.flags })
+        }
+    }
+
+    // Decodes the fixed header at the start of `bytes`, returning it and its size
+    pub fn parse(bytes: &[u8]) -> Result<(Self, usize)> {
+        if bytes.is_empty() {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "fixed header ended early"))
+        }
+        let (ctrl, flags) = FixedHeader::from_first_byte(bytes[0])?;
+        let (remaining_length, remaining_length_size) = RemainingLength::parse(&bytes[1..])?;
+        let fixed_header = FixedHeader{
+            control_packet_type: ctrl,
+            flags,
+            remaining_length
+        };
+        Ok((fixed_header, remaining_length_size + 1))
+    }
 }
 
 impl Serde for FixedHeader {
-    fn ser(&self, sink: &mut Write) -> Result<usize> {
-        let written = sink.write(&[self.to_first_byte()])?;
+    fn ser(&self, sink: &mut dyn Write) -> Result<usize> {
+        let written = write_u8(sink, self.to_first_byte())?;
         let written = written + self.remaining_length.ser(sink)?;
         Ok(written)
     }
 
-    fn de(source: &mut Read) -> Result<(Self, usize)> {
+    fn de(source: &mut dyn Read) -> Result<(Self, usize)> {
         let mut buf = [0; 1];
         source.read_exact(&mut buf)?;
         let (ctrl, flags)  = FixedHeader::from_first_byte(buf[0])?;
         let (remaining_length, remaining_length_size) = RemainingLength::de(source)?;
         let fixed_header = FixedHeader{
             control_packet_type: ctrl,
-            flags: flags,
-            remaining_length: remaining_length
+            flags,
+            remaining_length
         };
         Ok((fixed_header, remaining_length_size + 1))
     }
@@ -1,259 +1,1088 @@
 use mqtt::*;
+use std::borrow::Cow;
 use std::io::{Error, ErrorKind, Read, Result, Write};
 use std::convert::TryFrom;
 
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub struct Will<'a> {
-    retain: bool,
-    qos: QualityOfService,
-    topic: &'a str,
-    message: &'a str
+    pub retain: bool,
+    pub qos: QualityOfService,
+    pub topic: TopicName<'a>,
+    pub message: Cow<'a, [u8]>,
+    pub properties: Properties<'a>
 }
 
-#[derive(Copy, Clone)]
+// Every variant that has properties in MQTT 5 carries them here; on an MQTT 3.1.1 connection
+// they're always empty, and reason codes are always `ReasonCode::Success`
+#[derive(Clone)]
 pub enum Message<'a> {
     Connect {
-        client_id: &'a str,
-        username: &'a str,
-        password: &'a str,
+        protocol_version: ProtocolVersion,
+        client_id: Cow<'a, str>,
+        // An empty username or password isn't the same as none at all
+        username: Option<Cow<'a, str>>,
+        password: Option<Cow<'a, [u8]>>,
         will: Option<Will<'a>>,
         clean_session: bool,
         keep_alive: u16,
+        properties: Properties<'a>
     },
     Connack {
         session_present: bool,
-        return_code: ConnackReturnCode
+        return_code: ConnackReturnCode,
+        properties: Properties<'a>
     },
     Publish {
         dup: bool,
         qos: QualityOfService,
         retain: bool,
-        topic: &'a str,
+        topic: TopicName<'a>,
         packet_id: Option<PacketId>,
-        payload: &'a str
+        payload: Cow<'a, [u8]>,
+        properties: Properties<'a>
+    },
+    Puback {
+        packet_id: PacketId,
+        reason_code: ReasonCode,
+        properties: Properties<'a>
+    },
+    Pubrec {
+        packet_id: PacketId,
+        reason_code: ReasonCode,
+        properties: Properties<'a>
+    },
+    Pubrel {
+        packet_id: PacketId,
+        reason_code: ReasonCode,
+        properties: Properties<'a>
+    },
+    Pubcomp {
+        packet_id: PacketId,
+        reason_code: ReasonCode,
+        properties: Properties<'a>
     },
-    Puback(PacketId),
-    Pubrec(PacketId),
-    Pubrel(PacketId),
-    Pubcomp(PacketId),
     Subscribe {
         packet_id: PacketId,
-        topic_filters: &'a[(&'a str, QualityOfService)]
+        topic_filters: Vec<(SubscribeFilter<'a>, SubscriptionOptions)>,
+        properties: Properties<'a>
     },
     Suback {
         packet_id: PacketId,
-        return_codes: &'a[Option<QualityOfService>]
+        return_codes: Vec<SubackReturn>,
+        properties: Properties<'a>
     },
     Unsubscribe {
         packet_id: PacketId,
-        topic_filters: &'a[&'a str]
+        topic_filters: Vec<TopicFilter<'a>>,
+        properties: Properties<'a>
+    },
+    // `reason_codes` are MQTT 5 only
+    Unsuback {
+        packet_id: PacketId,
+        reason_codes: Vec<ReasonCode>,
+        properties: Properties<'a>
     },
-    Unsuback(PacketId),
     Pingreq,
     Pingresp,
-    Disconnect
+    Disconnect {
+        reason_code: ReasonCode,
+        properties: Properties<'a>
+    },
+    // MQTT 5 only
+    Auth {
+        reason_code: ReasonCode,
+        properties: Properties<'a>
+    }
+}
+
+// Messages and wills that own all of their data, and so can be queued, stored in a session or
+// sent to another task after the buffer they were decoded from is gone
+pub type OwnedMessage = Message<'static>;
+pub type OwnedWill = Will<'static>;
+
+fn own<T: ToOwned + ?Sized>(cow: Cow<T>) -> Cow<'static, T> {
+    Cow::Owned(cow.into_owned())
+}
+
+fn own_properties(properties: Properties) -> Properties<'static> {
+    properties.into_iter().map(Property::into_owned).collect()
+}
+
+impl<'a> Will<'a> {
+    pub fn into_owned(self) -> OwnedWill {
+        Will {
+            retain: self.retain,
+            qos: self.qos,
+            topic: self.topic.into_owned(),
+            message: own(self.message),
+            properties: own_properties(self.properties)
+        }
+    }
+
+    pub fn borrowed(&self) -> Will<'_> {
+        Will {
+            retain: self.retain,
+            qos: self.qos,
+            topic: self.topic.borrowed(),
+            message: Cow::Borrowed(&self.message),
+            properties: self.properties.clone()
+        }
+    }
 }
 
 impl<'a> Message<'a> {
+    // Copies whatever is still borrowed; data that's already owned is moved, not copied
+    pub fn into_owned(self) -> OwnedMessage {
+        match self {
+            Message::Connect {
+                protocol_version,
+                client_id,
+                username,
+                password,
+                will,
+                clean_session,
+                keep_alive,
+                properties
+            } =>
+                Message::Connect {
+                    protocol_version,
+                    client_id: own(client_id),
+                    username: username.map(own),
+                    password: password.map(own),
+                    will: will.map(Will::into_owned),
+                    clean_session,
+                    keep_alive,
+                    properties: own_properties(properties)
+                },
+            Message::Connack { session_present, return_code, properties } =>
+                Message::Connack { session_present, return_code, properties: own_properties(properties) },
+            Message::Publish { dup, qos, retain, topic, packet_id, payload, properties } =>
+                Message::Publish {
+                    dup,
+                    qos,
+                    retain,
+                    topic: topic.into_owned(),
+                    packet_id,
+                    payload: own(payload),
+                    properties: own_properties(properties)
+                },
+            Message::Puback { packet_id, reason_code, properties } =>
+                Message::Puback { packet_id, reason_code, properties: own_properties(properties) },
+            Message::Pubrec { packet_id, reason_code, properties } =>
+                Message::Pubrec { packet_id, reason_code, properties: own_properties(properties) },
+            Message::Pubrel { packet_id, reason_code, properties } =>
+                Message::Pubrel { packet_id, reason_code, properties: own_properties(properties) },
+            Message::Pubcomp { packet_id, reason_code, properties } =>
+                Message::Pubcomp { packet_id, reason_code, properties: own_properties(properties) },
+            Message::Subscribe { packet_id, topic_filters, properties } =>
+                Message::Subscribe {
+                    packet_id,
+                    topic_filters: topic_filters.into_iter().map(|(f, options)| { (f.into_owned(), options) }).collect(),
+                    properties: own_properties(properties)
+                },
+            Message::Suback { packet_id, return_codes, properties } =>
+                Message::Suback { packet_id, return_codes, properties: own_properties(properties) },
+            Message::Unsubscribe { packet_id, topic_filters, properties } =>
+                Message::Unsubscribe {
+                    packet_id,
+                    topic_filters: topic_filters.into_iter().map(TopicFilter::into_owned).collect(),
+                    properties: own_properties(properties)
+                },
+            Message::Unsuback { packet_id, reason_codes, properties } =>
+                Message::Unsuback { packet_id, reason_codes, properties: own_properties(properties) },
+            Message::Pingreq => Message::Pingreq,
+            Message::Pingresp => Message::Pingresp,
+            Message::Disconnect { reason_code, properties } =>
+                Message::Disconnect { reason_code, properties: own_properties(properties) },
+            Message::Auth { reason_code, properties } =>
+                Message::Auth { reason_code, properties: own_properties(properties) }
+        }
+    }
+
+    // A view of this message that borrows its data rather than copying it. Properties are
+    // small, and are cloned.
+    pub fn borrowed(&self) -> Message<'_> {
+        match self {
+            Message::Connect {
+                protocol_version,
+                client_id,
+                username,
+                password,
+                will,
+                clean_session,
+                keep_alive,
+                properties
+            } =>
+                Message::Connect {
+                    protocol_version: *protocol_version,
+                    client_id: Cow::Borrowed(client_id),
+                    username: username.as_deref().map(Cow::Borrowed),
+                    password: password.as_deref().map(Cow::Borrowed),
+                    will: will.as_ref().map(Will::borrowed),
+                    clean_session: *clean_session,
+                    keep_alive: *keep_alive,
+                    properties: properties.clone()
+                },
+            Message::Publish { dup, qos, retain, topic, packet_id, payload, properties } =>
+                Message::Publish {
+                    dup: *dup,
+                    qos: *qos,
+                    retain: *retain,
+                    topic: topic.borrowed(),
+                    packet_id: *packet_id,
+                    payload: Cow::Borrowed(payload),
+                    properties: properties.clone()
+                },
+            Message::Subscribe { packet_id, topic_filters, properties } =>
+                Message::Subscribe {
+                    packet_id: *packet_id,
+                    topic_filters: topic_filters.iter().map(|(f, options)| { (f.borrowed(), *options) }).collect(),
+                    properties: properties.clone()
+                },
+            Message::Unsubscribe { packet_id, topic_filters, properties } =>
+                Message::Unsubscribe {
+                    packet_id: *packet_id,
+                    topic_filters: topic_filters.iter().map(TopicFilter::borrowed).collect(),
+                    properties: properties.clone()
+                },
+            other => other.clone()
+        }
+    }
+
     fn packet_type(&self) -> ControlPacketType {
         match *self {
-            Message::Connect { client_id: _, username: _, password: _,
-                               will: _, clean_session: _, keep_alive: _ } =>
-                ControlPacketType::Connect,
-            Message::Connack { session_present: _, return_code: _ } =>
-                ControlPacketType::Connack,
-            Message::Publish { dup: _, qos: _, retain: _, topic: _, packet_id: _, payload: _ } =>
-                ControlPacketType::Publish,
-            Message::Puback(_) =>
-                ControlPacketType::Puback,
-            Message::Pubrec(_) =>
-                ControlPacketType::Pubrec,
-            Message::Pubrel(_) =>
-                ControlPacketType::Pubrel,
-            Message::Pubcomp(_) =>
-                ControlPacketType::Pubcomp,
-            Message::Subscribe { packet_id: _, topic_filters: _ } =>
-                ControlPacketType::Subscribe,
-            Message::Suback { packet_id: _, return_codes: _ } =>
-                ControlPacketType::Suback,
-            Message::Unsubscribe { packet_id: _, topic_filters: _ } =>
-                ControlPacketType::Unsubscribe,
-            Message::Unsuback(_) => ControlPacketType::Unsuback,
+            Message::Connect { .. } => ControlPacketType::Connect,
+            Message::Connack { .. } => ControlPacketType::Connack,
+            Message::Publish { .. } => ControlPacketType::Publish,
+            Message::Puback { .. } => ControlPacketType::Puback,
+            Message::Pubrec { .. } => ControlPacketType::Pubrec,
+            Message::Pubrel { .. } => ControlPacketType::Pubrel,
+            Message::Pubcomp { .. } => ControlPacketType::Pubcomp,
+            Message::Subscribe { .. } => ControlPacketType::Subscribe,
+            Message::Suback { .. } => ControlPacketType::Suback,
+            Message::Unsubscribe { .. } => ControlPacketType::Unsubscribe,
+            Message::Unsuback { .. } => ControlPacketType::Unsuback,
             Message::Pingreq => ControlPacketType::Pingreq,
             Message::Pingresp => ControlPacketType::Pingresp,
-            Message::Disconnect => ControlPacketType::Disconnect
+            Message::Disconnect { .. } => ControlPacketType::Disconnect,
+            Message::Auth { .. } => ControlPacketType::Auth
         }
     }
 
     fn flags(&self) -> [bool; 4] {
         match self {
-            Message::Publish { dup, qos, retain, topic, packet_id, payload } => {
+            Message::Publish { dup, qos, retain, .. } => {
                 let (qos0, qos1) = qos.bits();
                 [*dup, qos0, qos1, *retain]
             },
-            Message::Pubrel(_) => [false, false, true, false],
-            Message::Subscribe { packet_id, topic_filters } => [false, false, true, false],
-            Message::Unsubscribe { packet_id, topic_filters } => [false, false, true, false],
+            Message::Pubrel { .. } => [false, false, true, false],
+            Message::Subscribe { .. } => [false, false, true, false],
+            Message::Unsubscribe { .. } => [false, false, true, false],
             _ => [false, false, false, false]
         }
     }
 
+    // A CONNECT is always encoded as the version it announces
+    fn version(&self, version: ProtocolVersion) -> ProtocolVersion {
+        match self {
+            Message::Connect { protocol_version, .. } => *protocol_version,
+            _ => version
+        }
+    }
+
     fn remaining_length(
-        vho: &Option<VariableHeader<'a>>,
-        plo: &Option<Payload<'a>>
+        version: ProtocolVersion,
+        vho: &Option<VariableHeader>,
+        plo: &Option<Payload>
     ) -> Result<RemainingLength> {
-        let vh_len = vho.map_or(0, |v| { v.len() }) as u32;
+        let vh_len = vho.as_ref().map_or(0, |v| { v.len(version) });
         let pl_len = match plo {
             None => 0u32,
-            Some(plo) => plo.len() as u32
+            Some(plo) => plo.len(version) as u32
         };
-         //   plo.map_or(0, |p| { p.len() as u32 }) as u32;
-        RemainingLength::try_from((vh_len + pl_len) as u32)
+        RemainingLength::try_from(vh_len + pl_len)
     }
 
-    fn variable_header(self) -> Option<VariableHeader<'a>> {
+    fn variable_header(&self, version: ProtocolVersion) -> Option<VariableHeader<'_>> {
         match self {
             Message::Connect {
-                client_id,
+                protocol_version,
+                client_id: _,
                 username,
                 password,
                 will,
                 clean_session,
-                keep_alive
+                keep_alive,
+                properties
             } => {
                 let (retain, qos, flag) = match will {
-                    Some(Will{ retain, qos, topic: _, message: _ }) =>
-                        (retain, qos, true),
+                    Some(Will{ retain, qos, .. }) =>
+                        (*retain, *qos, true),
                     None =>
                         (false, QualityOfService::AtMostOnce, false)
                 };
                 Some(VariableHeader::Connect {
-                    username: username.len() != 0,
-                    password: password.len() != 0,
+                    protocol_version: *protocol_version,
+                    username: username.is_some(),
+                    password: password.is_some(),
                     will_retain: retain,
                     will_qos: qos,
                     will_flag: flag,
-                    clean_session,
-                    keep_alive
+                    clean_session: *clean_session,
+                    keep_alive: *keep_alive,
+                    properties: Cow::Borrowed(properties)
                 })
             },
-            Message::Connack { session_present, return_code } =>
+            Message::Connack { session_present, return_code, properties } =>
                 Some(VariableHeader::Connack {
-                    session_present,
-                    return_code
+                    session_present: *session_present,
+                    return_code: *return_code,
+                    properties: Cow::Borrowed(properties)
                 }),
-            Message::Publish { dup, qos, retain, topic, packet_id, payload } =>
-                Some(VariableHeader::Publish { topic_name: topic, packet_id: packet_id }),
-            Message::Subscribe{ packet_id, topic_filters } =>
-                Some(VariableHeader::Subscribe(packet_id)),
-            Message::Suback{ packet_id, return_codes } =>
-                Some(VariableHeader::Suback(packet_id)),
-            Message::Unsubscribe { packet_id, topic_filters } =>
-                Some(VariableHeader::Unsubscribe(packet_id)),
-            Message::Unsuback(packet_id) =>
-                Some(VariableHeader::Unsuback(packet_id)),
-            Message::Puback(packet_id) => Some(VariableHeader::Puback(packet_id)),
-            Message::Pubrec(packet_id) => Some(VariableHeader::Pubrec(packet_id)),
-            Message::Pubrel(packet_id) => Some(VariableHeader::Pubrel(packet_id)),
-            Message::Pubcomp(packet_id) => Some(VariableHeader::Pubcomp(packet_id)),
+            Message::Publish { topic, packet_id, properties, .. } =>
+                Some(VariableHeader::Publish {
+                    topic_name: topic.borrowed(),
+                    packet_id: *packet_id,
+                    properties: Cow::Borrowed(properties)
+                }),
+            Message::Puback { packet_id, reason_code, properties } =>
+                Some(VariableHeader::Puback(*packet_id, *reason_code, Cow::Borrowed(properties))),
+            Message::Pubrec { packet_id, reason_code, properties } =>
+                Some(VariableHeader::Pubrec(*packet_id, *reason_code, Cow::Borrowed(properties))),
+            Message::Pubrel { packet_id, reason_code, properties } =>
+                Some(VariableHeader::Pubrel(*packet_id, *reason_code, Cow::Borrowed(properties))),
+            Message::Pubcomp { packet_id, reason_code, properties } =>
+                Some(VariableHeader::Pubcomp(*packet_id, *reason_code, Cow::Borrowed(properties))),
+            Message::Subscribe { packet_id, properties, .. } =>
+                Some(VariableHeader::Subscribe(*packet_id, Cow::Borrowed(properties))),
+            Message::Suback { packet_id, properties, .. } =>
+                Some(VariableHeader::Suback(*packet_id, Cow::Borrowed(properties))),
+            Message::Unsubscribe { packet_id, properties, .. } =>
+                Some(VariableHeader::Unsubscribe(*packet_id, Cow::Borrowed(properties))),
+            Message::Unsuback { packet_id, properties, .. } =>
+                Some(VariableHeader::Unsuback(*packet_id, Cow::Borrowed(properties))),
+            Message::Disconnect { reason_code, properties } if version == ProtocolVersion::V5 =>
+                Some(VariableHeader::Disconnect(*reason_code, Cow::Borrowed(properties))),
+            Message::Auth { reason_code, properties } =>
+                Some(VariableHeader::Auth(*reason_code, Cow::Borrowed(properties))),
             _ => None
         }
     }
 
-    fn payload(&self) -> Option<Payload> {
+    fn payload(&self, version: ProtocolVersion) -> Option<Payload<'_>> {
         match self {
-            Message::Connect{
-                client_id,
-                username,
-                password,
-                will,
-                clean_session: _,
-                keep_alive: _
-            } => {
-                let will_pair = match will {
-                    Some(Will{ retain: _, qos: _, topic, message }) => Some((*topic, *message)),
-                    None => None
-                };
+            Message::Connect{ client_id, username, password, will, .. } => {
+                let will = will.as_ref().map(|Will{ topic, message, properties, .. }| {
+                    (Cow::Borrowed(&properties[..]), topic.borrowed(), Cow::Borrowed(&**message))
+                });
                 Some(Payload::Connect{
-                    client_id,
-                    will: will_pair,
-                    username,
-                    password
+                    client_id: Cow::Borrowed(client_id),
+                    will,
+                    username: username.as_deref().map(Cow::Borrowed),
+                    password: password.as_deref().map(Cow::Borrowed)
                 })
             },
-            Message::Publish { dup: _, qos: _, retain: _, topic: _, packet_id: _, payload } =>
-                Some(Payload::Publish(payload)),
-            Message::Subscribe { packet_id: _, topic_filters } =>
-                Some(Payload::Subscribe(topic_filters)),
-            Message::Suback { packet_id: _, return_codes } =>
-                Some(Payload::Suback(return_codes)),
-            Message::Unsubscribe { packet_id: _, topic_filters } =>
-                Some(Payload::Unsubscribe(topic_filters)),
+            Message::Publish { payload, .. } =>
+                Some(Payload::Publish(Cow::Borrowed(payload))),
+            Message::Subscribe { topic_filters, .. } =>
+                Some(Payload::Subscribe(Cow::Borrowed(topic_filters))),
+            Message::Suback { return_codes, .. } =>
+                Some(Payload::Suback(Cow::Borrowed(return_codes))),
+            Message::Unsubscribe { topic_filters, .. } =>
+                Some(Payload::Unsubscribe(Cow::Borrowed(topic_filters))),
+            Message::Unsuback { reason_codes, .. } if version == ProtocolVersion::V5 =>
+                Some(Payload::Unsuback(Cow::Borrowed(reason_codes))),
             _ => None
         }
     }
-}
 
-impl<'a> Serde for Message<'a> {
-    fn ser(&self, sink: &mut Write) -> Result<usize> {
+    // Checks the rules a message has to follow beyond being well-formed. Decoded messages have
+    // already been checked, as has anything that's encoded.
+    pub fn validate(&self) -> ::std::result::Result<(), ProtocolError> {
+        let packet_type = self.packet_type();
+        match self {
+            Message::Connect { protocol_version, username, password, .. }
+                if *protocol_version != ProtocolVersion::V5 && username.is_none() && password.is_some() =>
+                Err(ProtocolError::PasswordWithoutUsername),
+            Message::Publish { qos: QualityOfService::AtMostOnce, packet_id: Some(_), .. } =>
+                Err(ProtocolError::PacketIdWithQosZero),
+            Message::Publish { qos: QualityOfService::AtMostOnce, dup: true, .. } =>
+                Err(ProtocolError::DupWithQosZero),
+            Message::Publish { packet_id: None, qos, .. } if *qos != QualityOfService::AtMostOnce =>
+                Err(ProtocolError::MissingPacketId),
+            Message::Publish { packet_id: Some(0), .. } |
+            Message::Puback { packet_id: 0, .. } |
+            Message::Pubrec { packet_id: 0, .. } |
+            Message::Pubrel { packet_id: 0, .. } |
+            Message::Pubcomp { packet_id: 0, .. } |
+            Message::Subscribe { packet_id: 0, .. } |
+            Message::Suback { packet_id: 0, .. } |
+            Message::Unsubscribe { packet_id: 0, .. } |
+            Message::Unsuback { packet_id: 0, .. } =>
+                Err(ProtocolError::ZeroPacketId(packet_type)),
+            Message::Subscribe { topic_filters, .. } if topic_filters.is_empty() =>
+                Err(ProtocolError::EmptySubscribe),
+            Message::Unsubscribe { topic_filters, .. } if topic_filters.is_empty() =>
+                Err(ProtocolError::EmptyUnsubscribe),
+            _ =>
+                Ok(())
+        }
+    }
+
+    // Encodes this message as `version` would, bar a CONNECT, which is encoded as the version it
+    // announces. Properties and reason codes are left out for MQTT 3.1.1.
+    pub fn ser_with(&self, version: ProtocolVersion, sink: &mut dyn Write) -> Result<usize> {
+        if version != ProtocolVersion::V5 && matches!(self, Message::Auth { .. }) {
+            return Err(Error::new(ErrorKind::InvalidInput, "AUTH packets only exist in MQTT 5"))
+        }
+        self.validate().map_err(ProtocolError::into_encoding_error)?;
+        let version = self.version(version);
         let control_packet_type = self.packet_type();
         let flags = self.flags();
-        let variable_header = self.variable_header(); // vho
-        let payload = self.payload(); // plo
+        let variable_header = self.variable_header(version); // vho
+        let payload = self.payload(version); // plo
         let remaining_length = Message::remaining_length(
+            version,
             &variable_header,
             &payload
         )?;
         let fixed_header = FixedHeader{ control_packet_type, flags, remaining_length };
         let mut written = fixed_header.ser(sink)?;
-        written += variable_header.map_or(Ok(0), |v| { v.ser(sink) })?;
-        written += payload.map_or(Ok(0), |p| { p.ser(sink) })?;
+        written += variable_header.map_or(Ok(0), |v| { v.ser_with(version, sink) })?;
+        written += payload.map_or(Ok(0), |p| { p.ser_with(version, sink) })?;
         Ok(written)
     }
 
-    fn de(source: &mut Read) -> Result<(Self, usize)> {
-        let (fixed_header, _) = FixedHeader::de(source)?;
-        match fixed_header.control_packet_type {
-            ControlPacketType::ReservedLow =>
+    // Reads exactly one packet as `version` would, bar a CONNECT, which is decoded as the
+    // version it announces
+    pub fn de_with(version: ProtocolVersion, source: &mut dyn Read) -> Result<(OwnedMessage, usize)> {
+        let (fixed_header, header_size) = FixedHeader::de(source)?;
+        let remaining_length: u32 = fixed_header.remaining_length.into();
+        let mut body = source.take(remaining_length as u64);
+        let message = Message::de_body(fixed_header, version, &mut ReadSource(&mut body))?;
+        Ok((message, header_size + remaining_length as usize))
+    }
+
+    // Decodes the first packet in `bytes` as an MQTT 3.1.1 packet, see `Message::parse_with`
+    pub fn parse(bytes: &'a [u8]) -> Result<(Self, usize)> {
+        Message::parse_with(ProtocolVersion::V311, bytes)
+    }
+
+    // Decodes the first packet in `bytes` without copying: topics, client ids, payloads and the
+    // like borrow from `bytes`. Returns the message and how many bytes it took up.
+    pub fn parse_with(version: ProtocolVersion, bytes: &'a [u8]) -> Result<(Self, usize)> {
+        let (fixed_header, header_size) = FixedHeader::parse(bytes)?;
+        let remaining_length: u32 = fixed_header.remaining_length.into();
+        let packet_size = header_size + remaining_length as usize;
+        if bytes.len() < packet_size {
+            let msg = format!("packet is {} bytes but only {} are available", packet_size, bytes.len());
+            return Err(Error::new(ErrorKind::UnexpectedEof, msg))
+        }
+        let body = &bytes[header_size..packet_size];
+        let message = Message::de_body(fixed_header, version, &mut SliceSource(body))?;
+        Ok((message, packet_size))
+    }
+
+    // `source` must already be bounded by the fixed header's remaining length
+    fn de_body(fixed_header: FixedHeader, version: ProtocolVersion, source: &mut dyn Source<'a>) -> Result<Self> {
+        fixed_header.check_flags()?;
+        let remaining_length: u32 = fixed_header.remaining_length.into();
+        let remaining_length = remaining_length as usize;
+        let (variable_header, vh_size) = VariableHeader::de_with(&fixed_header, version, source)?;
+        let (payload, pl_size) = Payload::de_with(
+            &fixed_header,
+            &variable_header,
+            version,
+            source,
+            remaining_length - vh_size
+        )?;
+        if vh_size + pl_size != remaining_length {
+            let msg = format!(
+                "remaining length was {} but the packet body was {} bytes",
+                remaining_length,
+                vh_size + pl_size
+            );
+            return Err(Error::new(ErrorKind::InvalidData, msg))
+        }
+        let message = Message::from_parts(fixed_header, variable_header, payload)?;
+        message.validate()?;
+        Ok(message)
+    }
+
+    // Assembles a message from its decoded parts, checking they agree with each other
+    fn from_parts(
+        fixed_header: FixedHeader,
+        variable_header: Option<VariableHeader<'a>>,
+        payload: Option<Payload<'a>>
+    ) -> Result<Self> {
+        match (fixed_header.control_packet_type, variable_header, payload) {
+            (
+                ControlPacketType::Connect,
+                Some(VariableHeader::Connect {
+                    protocol_version,
+                    will_retain,
+                    will_qos,
+                    clean_session,
+                    keep_alive,
+                    properties,
+                    ..
+                }),
+                Some(Payload::Connect { client_id, will, username, password })
+            ) => {
+                let will = will.map(|(properties, topic, message)| {
+                    Will { retain: will_retain, qos: will_qos, topic, message, properties: properties.into_owned() }
+                });
+                Ok(Message::Connect {
+                    protocol_version,
+                    client_id,
+                    username,
+                    password,
+                    will,
+                    clean_session,
+                    keep_alive,
+                    properties: properties.into_owned()
+                })
+            },
+            (
+                ControlPacketType::Connack,
+                Some(VariableHeader::Connack { session_present, return_code, properties }),
+                None
+            ) =>
+                Ok(Message::Connack { session_present, return_code, properties: properties.into_owned() }),
+            (
+                ControlPacketType::Publish,
+                Some(VariableHeader::Publish { topic_name, packet_id, properties }),
+                Some(Payload::Publish(payload))
+            ) => {
+                let flags = fixed_header.flags;
+                Ok(Message::Publish {
+                    dup: flags[0],
+                    qos: QualityOfService::from_bits(flags[1], flags[2])?,
+                    retain: flags[3],
+                    topic: topic_name,
+                    packet_id,
+                    payload,
+                    properties: properties.into_owned()
+                })
+            },
+            (ControlPacketType::Puback, Some(VariableHeader::Puback(packet_id, reason_code, properties)), None) =>
+                Ok(Message::Puback { packet_id, reason_code, properties: properties.into_owned() }),
+            (ControlPacketType::Pubrec, Some(VariableHeader::Pubrec(packet_id, reason_code, properties)), None) =>
+                Ok(Message::Pubrec { packet_id, reason_code, properties: properties.into_owned() }),
+            (ControlPacketType::Pubrel, Some(VariableHeader::Pubrel(packet_id, reason_code, properties)), None) =>
+                Ok(Message::Pubrel { packet_id, reason_code, properties: properties.into_owned() }),
+            (ControlPacketType::Pubcomp, Some(VariableHeader::Pubcomp(packet_id, reason_code, properties)), None) =>
+                Ok(Message::Pubcomp { packet_id, reason_code, properties: properties.into_owned() }),
+            (
+                ControlPacketType::Subscribe,
+                Some(VariableHeader::Subscribe(packet_id, properties)),
+                Some(Payload::Subscribe(topic_filters))
+            ) =>
+                Ok(Message::Subscribe {
+                    packet_id,
+                    topic_filters: topic_filters.into_owned(),
+                    properties: properties.into_owned()
+                }),
+            (
+                ControlPacketType::Suback,
+                Some(VariableHeader::Suback(packet_id, properties)),
+                Some(Payload::Suback(return_codes))
+            ) =>
+                Ok(Message::Suback {
+                    packet_id,
+                    return_codes: return_codes.into_owned(),
+                    properties: properties.into_owned()
+                }),
+            (
+                ControlPacketType::Unsubscribe,
+                Some(VariableHeader::Unsubscribe(packet_id, properties)),
+                Some(Payload::Unsubscribe(topic_filters))
+            ) =>
+                Ok(Message::Unsubscribe {
+                    packet_id,
+                    topic_filters: topic_filters.into_owned(),
+                    properties: properties.into_owned()
+                }),
+            (
+                ControlPacketType::Unsuback,
+                Some(VariableHeader::Unsuback(packet_id, properties)),
+                Some(Payload::Unsuback(reason_codes))
+            ) =>
+                Ok(Message::Unsuback {
+                    packet_id,
+                    reason_codes: reason_codes.into_owned(),
+                    properties: properties.into_owned()
+                }),
+            (ControlPacketType::Unsuback, Some(VariableHeader::Unsuback(packet_id, properties)), None) =>
+                Ok(Message::Unsuback { packet_id, reason_codes: Vec::new(), properties: properties.into_owned() }),
+            (ControlPacketType::Pingreq, None, None) => Ok(Message::Pingreq),
+            (ControlPacketType::Pingresp, None, None) => Ok(Message::Pingresp),
+            (ControlPacketType::Disconnect, Some(VariableHeader::Disconnect(reason_code, properties)), None) =>
+                Ok(Message::Disconnect { reason_code, properties: properties.into_owned() }),
+            (ControlPacketType::Disconnect, None, None) =>
+                Ok(Message::Disconnect { reason_code: ReasonCode::Success, properties: Vec::new() }),
+            (ControlPacketType::Auth, Some(VariableHeader::Auth(reason_code, properties)), None) =>
+                Ok(Message::Auth { reason_code, properties: properties.into_owned() }),
+            (ControlPacketType::ReservedLow, _, _) =>
                 raise_reserved("Cannot use control-packet-type 0, 'reserved low'"),
-            ControlPacketType::Connect =>
-                unimplemented_error(),
-            ControlPacketType::Connack =>
-                unimplemented_error(),
-            ControlPacketType::Publish =>
-                unimplemented_error(),
-            ControlPacketType::Puback =>
-                unimplemented_error(),
-            ControlPacketType::Pubrec =>
-                unimplemented_error(),
-            ControlPacketType::Pubrel =>
-                unimplemented_error(),
-            ControlPacketType::Pubcomp =>
-                unimplemented_error(),
-            ControlPacketType::Subscribe =>
-                unimplemented_error(),
-            ControlPacketType::Suback =>
-                unimplemented_error(),
-            ControlPacketType::Unsubscribe =>
-                unimplemented_error(),
-            ControlPacketType::Unsuback =>
-                unimplemented_error(),
-            ControlPacketType::Pingreq =>
-                unimplemented_error(),
-            ControlPacketType::Pingresp =>
-                unimplemented_error(),
-            ControlPacketType::Disconnect =>
-                unimplemented_error(),
-            ControlPacketType::ReservedHigh =>
-                raise_reserved("Cannot use control-packet-type 15, 'reserved high'")
+            (ControlPacketType::Auth, None, _) =>
+                raise_reserved("Cannot use control-packet-type 15, reserved before MQTT 5"),
+            _ =>
+                Err(Error::new(ErrorKind::InvalidData, "packet headers and payload do not agree"))
         }
     }
 }
 
+impl<'a> Serde for Message<'a> {
+    fn ser(&self, sink: &mut dyn Write) -> Result<usize> {
+        self.ser_with(ProtocolVersion::V311, sink)
+    }
+
+    // Reads exactly one packet: the fixed header, then no more and no less than the
+    // remaining length it announces
+    fn de(source: &mut dyn Read) -> Result<(Self, usize)> {
+        Message::de_with(ProtocolVersion::V311, source)
+    }
+}
+
 fn raise_reserved<T>(msg: &str) -> Result<T> {
     Err(Error::new(ErrorKind::InvalidData, msg))
 }
 
-fn unimplemented_error<T>() -> Result<T> {
-    Err(Error::new(ErrorKind::Other, "not implemented yet"))
+#[cfg(test)]
+mod tests {
+    use mqtt::*;
+    use std::borrow::Cow;
+    use std::io::ErrorKind;
+
+    fn encode(version: ProtocolVersion, msg: &Message) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        let written = msg.ser_with(version, &mut encoded).unwrap();
+        assert_eq!(written, encoded.len());
+        encoded
+    }
+
+    // Decodes `msg` back from its encoding, both borrowing from a slice and reading from a
+    // stream, and checks that each encodes to the same bytes again
+    fn round_trip(version: ProtocolVersion, msg: Message) -> OwnedMessage {
+        let encoded = encode(version, &msg);
+        let (parsed, parsed_size) = Message::parse_with(version, &encoded).unwrap();
+        assert_eq!(parsed_size, encoded.len());
+        assert_eq!(encode(version, &parsed), encoded);
+        let (read, read_size) = Message::de_with(version, &mut &encoded[..]).unwrap();
+        assert_eq!(read_size, encoded.len());
+        assert_eq!(encode(version, &read), encoded);
+        read
+    }
+
+    // Every packet an MQTT 3.1.1 connection can carry
+    fn v311_messages() -> Vec<OwnedMessage> {
+        let (packet_id, reason_code, properties) = (10, ReasonCode::Success, Vec::new());
+        vec![
+            Message::Connect {
+                protocol_version: ProtocolVersion::V311,
+                client_id: Cow::Borrowed("client"),
+                username: Some(Cow::Borrowed("user")),
+                password: Some(Cow::Borrowed(b"secret")),
+                will: Some(Will {
+                    retain: true,
+                    qos: QualityOfService::AtLeastOnce,
+                    topic: TopicName::new("will/topic").unwrap(),
+                    message: Cow::Borrowed(b"gone"),
+                    properties: Vec::new()
+                }),
+                clean_session: true,
+                keep_alive: 30,
+                properties: Vec::new()
+            },
+            Message::Connack { session_present: true, return_code: ConnackReturnCode::Accepted, properties: Vec::new() },
+            Message::Publish {
+                dup: true,
+                qos: QualityOfService::ExactlyOnce,
+                retain: true,
+                topic: TopicName::new("a/b").unwrap(),
+                packet_id: Some(packet_id),
+                payload: Cow::Borrowed(b"payload"),
+                properties: Vec::new()
+            },
+            Message::Puback { packet_id, reason_code, properties: properties.clone() },
+            Message::Pubrec { packet_id, reason_code, properties: properties.clone() },
+            Message::Pubrel { packet_id, reason_code, properties: properties.clone() },
+            Message::Pubcomp { packet_id, reason_code, properties: properties.clone() },
+            Message::Subscribe {
+                packet_id,
+                topic_filters: vec![
+                    (SubscribeFilter::new("a/+"), SubscriptionOptions::new(QualityOfService::AtMostOnce)),
+                    (SubscribeFilter::new("b/#"), SubscriptionOptions::new(QualityOfService::ExactlyOnce))
+                ],
+                properties: properties.clone()
+            },
+            Message::Suback {
+                packet_id,
+                return_codes: vec![SubackReturn::AtMostOnce, SubackReturn::Failure],
+                properties: properties.clone()
+            },
+            Message::Unsubscribe {
+                packet_id,
+                topic_filters: vec![TopicFilter::new("a/+").unwrap()],
+                properties: properties.clone()
+            },
+            Message::Unsuback { packet_id, reason_codes: Vec::new(), properties: properties.clone() },
+            Message::Pingreq,
+            Message::Pingresp,
+            Message::Disconnect { reason_code, properties }
+        ]
+    }
+
+    #[test]
+    fn every_v311_packet_type_round_trips() {
+        let messages = v311_messages();
+        let mut packet_types: Vec<u8> = messages.iter().map(|msg| { msg.packet_type().to_byte() }).collect();
+        packet_types.dedup();
+        assert_eq!(packet_types, (1..15).collect::<Vec<u8>>());
+        for msg in messages {
+            round_trip(ProtocolVersion::V311, msg);
+        }
+    }
+
+    #[test]
+    fn decoded_fields_match_what_was_encoded() {
+        match round_trip(ProtocolVersion::V311, v311_messages().remove(0)) {
+            Message::Connect { client_id, username, password, will: Some(will), clean_session, keep_alive, .. } => {
+                assert_eq!(client_id, "client");
+                assert_eq!(username.as_deref(), Some("user"));
+                assert_eq!(password.as_deref(), Some(&b"secret"[..]));
+                assert!(will.retain);
+                assert_eq!(will.qos, QualityOfService::AtLeastOnce);
+                assert_eq!(will.topic.as_str(), "will/topic");
+                assert_eq!(&*will.message, b"gone");
+                assert!(clean_session);
+                assert_eq!(keep_alive, 30);
+            },
+            _ => panic!("expected a CONNECT with a will")
+        }
+    }
+
+    #[test]
+    fn parses_one_packet_at_a_time() {
+        let mut encoded = encode(ProtocolVersion::V311, &Message::Pingreq);
+        encoded.extend(encode(ProtocolVersion::V311, &Message::Pingresp));
+        let (first, first_size) = Message::parse(&encoded).unwrap();
+        assert!(matches!(first, Message::Pingreq));
+        let (second, _) = Message::parse(&encoded[first_size..]).unwrap();
+        assert!(matches!(second, Message::Pingresp));
+    }
+
+    // The conformance statement a malformed packet breaks
+    fn broken_rule(packet: &[u8]) -> &'static str {
+        match Message::parse(packet) {
+            Err(e) => ProtocolError::from_error(&e).expect("expected a protocol error").rule(),
+            Ok(_) => panic!("parsed a malformed packet")
+        }
+    }
+
+    fn error_kind(packet: &[u8]) -> ErrorKind {
+        match Message::parse(packet) {
+            Err(e) => e.kind(),
+            Ok(_) => panic!("parsed a malformed packet")
+        }
+    }
+
+    #[test]
+    fn refuses_malformed_packets() {
+        // PUBREL without its reserved 0b0010 flags
+        assert_eq!(broken_rule(&[0x60, 0x02, 0x00, 0x01]), "MQTT-2.2.2-2");
+        // PUBACK for packet id 0
+        assert_eq!(broken_rule(&[0x40, 0x02, 0x00, 0x00]), "MQTT-2.3.1-1");
+        // QoS 3 PUBLISH
+        assert_eq!(broken_rule(&[0x36, 0x05, 0x00, 0x01, b't', 0x00, 0x01]), "MQTT-3.3.1-4");
+        // SUBSCRIBE with no topic filters
+        assert_eq!(broken_rule(&[0x82, 0x02, 0x00, 0x01]), "MQTT-3.8.3-3");
+        // PUBLISH to a topic with a wildcard
+        assert_eq!(broken_rule(&[0x30, 0x03, 0x00, 0x01, b'#']), "MQTT-3.3.2-2");
+        // Topic that isn't UTF-8
+        assert_eq!(broken_rule(&[0x30, 0x03, 0x00, 0x01, 0xFF]), "MQTT-1.5.3-1");
+    }
+
+    #[test]
+    fn refuses_truncated_and_inconsistent_packets() {
+        // Reserved packet types
+        assert_eq!(error_kind(&[0x00, 0x00]), ErrorKind::InvalidData);
+        assert_eq!(error_kind(&[0xF0, 0x00]), ErrorKind::InvalidData);
+        // Remaining length longer than four bytes
+        assert_eq!(error_kind(&[0x30, 0xFF, 0xFF, 0xFF, 0xFF, 0x01]), ErrorKind::InvalidData);
+        // Remaining length promising more than there is
+        assert_eq!(error_kind(&[0x40, 0x02, 0x00]), ErrorKind::UnexpectedEof);
+        // Topic length running past the end of the packet
+        assert_eq!(error_kind(&[0x30, 0x03, 0x00, 0x05, b't']), ErrorKind::UnexpectedEof);
+        // PINGREQ with a body
+        assert_eq!(error_kind(&[0xC0, 0x01, 0x00]), ErrorKind::InvalidData);
+    }
+
+    fn connect(protocol_version: ProtocolVersion, username: Option<&'static str>, password: Option<&'static [u8]>) -> OwnedMessage {
+        Message::Connect {
+            protocol_version,
+            client_id: Cow::Borrowed("c"),
+            username: username.map(Cow::Borrowed),
+            password: password.map(Cow::Borrowed),
+            will: None,
+            clean_session: true,
+            keep_alive: 60,
+            properties: Vec::new()
+        }
+    }
+
+    #[test]
+    fn encodes_strings_with_their_length_prefixes() {
+        assert_eq!(encode(ProtocolVersion::V311, &connect(ProtocolVersion::V311, None, None)), vec![
+            0x10, 0x0D,
+            0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04, 0x02, 0x00, 0x3C,
+            0x00, 0x01, b'c'
+        ]);
+        assert_eq!(encode(ProtocolVersion::V311, &connect(ProtocolVersion::V31, Some("u"), Some(b"p"))), vec![
+            0x10, 0x15,
+            0x00, 0x06, b'M', b'Q', b'I', b's', b'd', b'p', 0x03, 0xC2, 0x00, 0x3C,
+            0x00, 0x01, b'c', 0x00, 0x01, b'u', 0x00, 0x01, b'p'
+        ]);
+        let publish = Message::Publish {
+            dup: false,
+            qos: QualityOfService::AtLeastOnce,
+            retain: false,
+            topic: TopicName::new("a/b").unwrap(),
+            packet_id: Some(10),
+            payload: Cow::Borrowed(b"hi"),
+            properties: Vec::new()
+        };
+        assert_eq!(encode(ProtocolVersion::V311, &publish), vec![
+            0x32, 0x09, 0x00, 0x03, b'a', b'/', b'b', 0x00, 0x0A, b'h', b'i'
+        ]);
+        let subscribe = Message::Subscribe {
+            packet_id: 1,
+            topic_filters: vec![(SubscribeFilter::new("a/#"), SubscriptionOptions::new(QualityOfService::AtLeastOnce))],
+            properties: Vec::new()
+        };
+        assert_eq!(encode(ProtocolVersion::V311, &subscribe), vec![
+            0x82, 0x08, 0x00, 0x01, 0x00, 0x03, b'a', b'/', b'#', 0x01
+        ]);
+        let unsubscribe = Message::Unsubscribe {
+            packet_id: 1,
+            topic_filters: vec![TopicFilter::new("a/#").unwrap()],
+            properties: Vec::new()
+        };
+        assert_eq!(encode(ProtocolVersion::V311, &unsubscribe), vec![
+            0xA2, 0x07, 0x00, 0x01, 0x00, 0x03, b'a', b'/', b'#'
+        ]);
+    }
+
+    #[test]
+    fn keeps_an_empty_password_apart_from_none() {
+        let msg = connect(ProtocolVersion::V311, Some("u"), Some(b""));
+        assert_eq!(encode(ProtocolVersion::V311, &msg), vec![
+            0x10, 0x12,
+            0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04, 0xC2, 0x00, 0x3C,
+            0x00, 0x01, b'c', 0x00, 0x01, b'u', 0x00, 0x00
+        ]);
+        match round_trip(ProtocolVersion::V311, msg) {
+            Message::Connect { password: Some(ref password), .. } if password.is_empty() => (),
+            _ => panic!("expected a CONNECT with an empty password")
+        }
+    }
+
+    #[test]
+    fn decodes_an_empty_username_with_a_password() {
+        let connect = [
+            0x10, 0x12,
+            0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04, 0xC2, 0x00, 0x3C,
+            0x00, 0x01, b'c', 0x00, 0x00, 0x00, 0x01, b'p'
+        ];
+        match Message::parse(&connect).unwrap().0 {
+            Message::Connect { username: Some(ref username), password: Some(ref password), .. } => {
+                assert_eq!(username, "");
+                assert_eq!(&**password, b"p");
+            },
+            _ => panic!("expected a CONNECT with a username and password")
+        }
+        // Only leaving the username out altogether breaks the rule [MQTT-3.1.2-22]
+        let connect = [
+            0x10, 0x10,
+            0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04, 0x42, 0x00, 0x3C,
+            0x00, 0x01, b'c', 0x00, 0x01, b'p'
+        ];
+        assert_eq!(broken_rule(&connect), "MQTT-3.1.2-22");
+    }
+
+    #[test]
+    fn header_and_payload_lengths_match_what_is_written() {
+        for msg in v311_messages() {
+            let version = msg.version(ProtocolVersion::V311);
+            if let Some(variable_header) = msg.variable_header(version) {
+                let mut written = Vec::new();
+                variable_header.ser_with(version, &mut written).unwrap();
+                assert_eq!(variable_header.len(version) as usize, written.len());
+            }
+            if let Some(payload) = msg.payload(version) {
+                let mut written = Vec::new();
+                payload.ser_with(version, &mut written).unwrap();
+                assert_eq!(payload.len(version), written.len());
+            }
+        }
+    }
+
+    #[test]
+    fn refuses_to_encode_what_it_would_refuse_to_decode() {
+        let publish = Message::Publish {
+            dup: false,
+            qos: QualityOfService::AtMostOnce,
+            retain: false,
+            topic: TopicName::new("t").unwrap(),
+            packet_id: Some(1),
+            payload: Cow::Borrowed(b""),
+            properties: Vec::new()
+        };
+        match publish.ser_with(ProtocolVersion::V311, &mut Vec::new()) {
+            Err(e) => {
+                assert_eq!(e.kind(), ErrorKind::InvalidInput);
+                assert_eq!(ProtocolError::from_error(&e).map(ProtocolError::rule), Some("MQTT-2.3.1-5"));
+            },
+            Ok(_) => panic!("encoded a QoS 0 PUBLISH with a packet id")
+        }
+        let auth = Message::Auth { reason_code: ReasonCode::Success, properties: Vec::new() };
+        assert!(auth.ser_with(ProtocolVersion::V311, &mut Vec::new()).is_err());
+    }
+
+    // Every packet an MQTT 5 connection can carry, with properties and reason codes
+    fn v5_messages() -> Vec<OwnedMessage> {
+        let properties = vec![
+            Property::ReasonString(Cow::Borrowed("why")),
+            Property::UserProperty(Cow::Borrowed("k"), Cow::Borrowed("v"))
+        ];
+        let reason_code = ReasonCode::ImplementationSpecificError;
+        let mut subscription = SubscriptionOptions::new(QualityOfService::AtLeastOnce);
+        subscription.no_local = true;
+        subscription.retain_as_published = true;
+        subscription.retain_handling = RetainHandling::DoNotSend;
+        vec![
+            Message::Connect {
+                protocol_version: ProtocolVersion::V5,
+                client_id: Cow::Borrowed("client"),
+                username: None,
+                password: Some(Cow::Borrowed(b"no username needed")),
+                will: Some(Will {
+                    retain: false,
+                    qos: QualityOfService::ExactlyOnce,
+                    topic: TopicName::new("will").unwrap(),
+                    message: Cow::Borrowed(b"gone"),
+                    properties: vec![Property::WillDelayInterval(5)]
+                }),
+                clean_session: false,
+                keep_alive: 0,
+                properties: vec![Property::SessionExpiryInterval(60), Property::ReceiveMaximum(10)]
+            },
+            Message::Connack {
+                session_present: false,
+                return_code: ConnackReturnCode::Refused(ReasonCode::QuotaExceeded),
+                properties: vec![Property::MaximumQos(1), Property::AssignedClientIdentifier(Cow::Borrowed("id"))]
+            },
+            Message::Publish {
+                dup: false,
+                qos: QualityOfService::AtLeastOnce,
+                retain: false,
+                topic: TopicName::new("t").unwrap(),
+                packet_id: Some(1),
+                payload: Cow::Borrowed(b"payload"),
+                properties: vec![Property::SubscriptionIdentifier(268_435_455), Property::TopicAlias(3)]
+            },
+            // A reason code with and without properties, and success with neither
+            Message::Puback { packet_id: 1, reason_code, properties: properties.clone() },
+            Message::Pubrec { packet_id: 1, reason_code, properties: Vec::new() },
+            Message::Pubrel { packet_id: 1, reason_code: ReasonCode::Success, properties: Vec::new() },
+            Message::Pubcomp { packet_id: 1, reason_code: ReasonCode::Success, properties: properties.clone() },
+            Message::Subscribe {
+                packet_id: 1,
+                topic_filters: vec![(SubscribeFilter::new("a/#"), subscription)],
+                properties: vec![Property::SubscriptionIdentifier(7)]
+            },
+            Message::Suback {
+                packet_id: 1,
+                return_codes: vec![SubackReturn::AtLeastOnce, SubackReturn::Refused(ReasonCode::TopicFilterInvalid)],
+                properties: properties.clone()
+            },
+            Message::Unsubscribe {
+                packet_id: 1,
+                topic_filters: vec![TopicFilter::new("a/#").unwrap()],
+                properties: properties.clone()
+            },
+            Message::Unsuback {
+                packet_id: 1,
+                reason_codes: vec![ReasonCode::Success, ReasonCode::NoSubscriptionExisted],
+                properties: properties.clone()
+            },
+            Message::Pingreq,
+            Message::Pingresp,
+            Message::Disconnect { reason_code: ReasonCode::Success, properties: Vec::new() },
+            Message::Auth { reason_code, properties }
+        ]
+    }
+
+    #[test]
+    fn every_v5_packet_type_round_trips() {
+        let messages = v5_messages();
+        let mut packet_types: Vec<u8> = messages.iter().map(|msg| { msg.packet_type().to_byte() }).collect();
+        packet_types.dedup();
+        assert_eq!(packet_types, (1..16).collect::<Vec<u8>>());
+        for msg in messages {
+            round_trip(ProtocolVersion::V5, msg);
+        }
+    }
+
+    #[test]
+    fn v5_subscription_options_and_reason_codes_survive_a_round_trip() {
+        let messages = v5_messages();
+        match round_trip(ProtocolVersion::V5, messages[7].clone()) {
+            Message::Subscribe { topic_filters, properties, .. } => {
+                let (_, options) = topic_filters[0];
+                assert!(options.no_local && options.retain_as_published);
+                assert_eq!(options.retain_handling, RetainHandling::DoNotSend);
+                assert_eq!(properties, vec![Property::SubscriptionIdentifier(7)]);
+            },
+            _ => panic!("expected a SUBSCRIBE")
+        }
+        match round_trip(ProtocolVersion::V5, messages[3].clone()) {
+            Message::Puback { reason_code, properties, .. } => {
+                assert_eq!(reason_code, ReasonCode::ImplementationSpecificError);
+                assert_eq!(properties.len(), 2);
+            },
+            _ => panic!("expected a PUBACK")
+        }
+        // The short forms: an acknowledgement of two bytes is a success with no properties
+        match Message::parse_with(ProtocolVersion::V5, &[0x40, 0x02, 0x00, 0x01]).unwrap().0 {
+            Message::Puback { reason_code: ReasonCode::Success, ref properties, .. } if properties.is_empty() => (),
+            _ => panic!("expected a successful PUBACK")
+        }
+        assert_eq!(encode(ProtocolVersion::V5, &messages[5]), vec![0x62, 0x02, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn v5_packets_lose_their_properties_on_a_v311_connection() {
+        let puback = v5_messages().remove(3);
+        assert_eq!(encode(ProtocolVersion::V311, &puback), vec![0x40, 0x02, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn refuses_malformed_v5_packets() {
+        let parse = |packet: &[u8]| {
+            match Message::parse_with(ProtocolVersion::V5, packet) {
+                Err(e) => e.kind(),
+                Ok(_) => panic!("parsed a malformed packet")
+            }
+        };
+        // Unknown property identifier
+        assert_eq!(parse(&[0x40, 0x05, 0x00, 0x01, 0x00, 0x01, 0x7F]), ErrorKind::InvalidData);
+        // Property block longer than the packet
+        assert_eq!(parse(&[0x40, 0x05, 0x00, 0x01, 0x00, 0x05, 0x01]), ErrorKind::UnexpectedEof);
+        // Reason code that doesn't exist
+        assert_eq!(parse(&[0x40, 0x03, 0x00, 0x01, 0x05]), ErrorKind::InvalidData);
+        // Retain handling of 3
+        assert_eq!(parse(&[0x82, 0x07, 0x00, 0x01, 0x00, 0x00, 0x01, b'a', 0x30]), ErrorKind::InvalidData);
+    }
 }
@@ -1,80 +1,266 @@
-use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::borrow::Cow;
+use std::io::{Error, ErrorKind, Result, Write};
 use mqtt::*;
 
+// A will is its properties (MQTT 5 only), topic and message
+pub type WillPayload<'a> = (Cow<'a, [Property<'a>]>, TopicName<'a>, Cow<'a, [u8]>);
+
+// What follows the variable header. It isn't `Serde`: how it's laid out depends on the headers
+// before it, so it's only decoded as part of a `Message`, and encoded for a given version.
 pub enum Payload<'a> {
     Connect {
-        client_id: &'a str,
-        will: Option<(&'a str, &'a str)>,
-        username: &'a str,
-        password: &'a str
+        client_id: Cow<'a, str>,
+        will: Option<WillPayload<'a>>,
+        username: Option<Cow<'a, str>>,
+        password: Option<Cow<'a, [u8]>>
     },
-    Publish(&'a str),
-    Subscribe(&'a[(&'a str, QualityOfService)]),
-    Suback(&'a[Option<QualityOfService>]),
-    Unsubscribe(&'a[&'a str])
+    Publish(Cow<'a, [u8]>),
+    Subscribe(Cow<'a, [(SubscribeFilter<'a>, SubscriptionOptions)]>),
+    Suback(Cow<'a, [SubackReturn]>),
+    Unsubscribe(Cow<'a, [TopicFilter<'a>]>),
+    // MQTT 5 only: an MQTT 3.1.1 UNSUBACK has no payload
+    Unsuback(Cow<'a, [ReasonCode]>)
 }
 
 impl<'a> Payload<'a> {
-    pub fn len(&self) -> usize {
+    // A CONNECT's payload is laid out according to the version in its own variable header,
+    // `version` is used for every other packet
+    pub fn len(&self, version: ProtocolVersion) -> usize {
         match self {
-            Payload::Connect{ client_id, will: Some((topic, msg)), username, password } =>
-                client_id.len() + topic.len() + msg.len() + username.len() + password.len(),
-            Payload::Connect{ client_id, will: None, username, password } =>
-                client_id.len() + username.len() + password.len(),
+            Payload::Connect{ client_id, will, username, password } => {
+                let will_len = will.as_ref().map_or(0, |(properties, topic, msg)| {
+                    let properties_len = match version {
+                        ProtocolVersion::V31 | ProtocolVersion::V311 => 0,
+                        ProtocolVersion::V5 => properties_size(properties)
+                    };
+                    properties_len + 2 + topic.len() + 2 + msg.len()
+                });
+                2 + client_id.len() + will_len + Payload::optional_len(username.as_ref().map(|username| { username.as_bytes() })) + Payload::optional_len(password.as_deref())
+            },
             Payload::Publish(msg) =>
                 msg.len(),
             Payload::Subscribe(filters) =>
                 filters.iter().map(|(t, _)| { t.len() + 3 }).sum(),
-            Payload::Suback(qoss) =>
-                qoss.len(),
+            Payload::Suback(return_codes) =>
+                return_codes.len(),
             Payload::Unsubscribe(topics) =>
-                topics.iter().map(|t| { t.len() + 2 }).sum()
+                topics.iter().map(|t| { t.len() + 2 }).sum(),
+            Payload::Unsuback(reason_codes) =>
+                reason_codes.len()
+        }
+    }
+
+    pub fn is_empty(&self, version: ProtocolVersion) -> bool {
+        self.len(version) == 0
+    }
+
+    pub fn ser_with(&self, version: ProtocolVersion, sink: &mut dyn Write) -> Result<usize> {
+        match self {
+            Payload::Connect { client_id, will, username, password } => {
+                let mut written = write_string(sink, client_id)?;
+                if let Some((properties, topic, msg)) = will {
+                    if version == ProtocolVersion::V5 {
+                        written += write_properties(sink, properties)?;
+                    }
+                    written += write_string(sink, topic)?;
+                    written += write_binary(sink, msg)?;
+                }
+                if let Some(username) = username {
+                    written += write_string(sink, username)?;
+                }
+                if let Some(password) = password {
+                    written += write_binary(sink, password)?;
+                }
+                Ok(written)
+            },
+            Payload::Publish(msg) => {
+                sink.write_all(msg)?;
+                Ok(msg.len())
+            },
+            Payload::Subscribe(filters) => {
+                let mut written = 0;
+                for (filter, options) in filters.iter() {
+                    written += write_string(sink, filter)?;
+                    written += write_u8(sink, options.to_byte(version))?;
+                }
+                Ok(written)
+            },
+            Payload::Suback(return_codes) => {
+                let mut written = 0;
+                for return_code in return_codes.iter() {
+                    written += write_u8(sink, return_code.to_byte(version))?;
+                }
+                Ok(written)
+            },
+            Payload::Unsubscribe(topics) => {
+                let mut written = 0;
+                for topic in topics.iter() {
+                    written += write_string(sink, topic)?;
+                }
+                Ok(written)
+            },
+            Payload::Unsuback(reason_codes) => {
+                let mut written = 0;
+                for reason_code in reason_codes.iter() {
+                    written += write_u8(sink, reason_code.to_byte())?;
+                }
+                Ok(written)
+            }
+        }
+    }
+
+    // Decodes the payload of the packet described by `header` and `variable_header`, if it has
+    // one. `len` is what's left of the remaining length once the variable header is consumed.
+    pub(crate) fn de_with(
+        header: &FixedHeader,
+        variable_header: &Option<VariableHeader>,
+        version: ProtocolVersion,
+        source: &mut dyn Source<'a>,
+        len: usize
+    ) -> Result<(Option<Self>, usize)> {
+        match (header.control_packet_type, variable_header) {
+            (
+                ControlPacketType::Connect,
+                Some(VariableHeader::Connect { protocol_version, username, password, will_flag, .. })
+            ) => {
+                let (client_id, mut read) = read_string(source)?;
+                let will = if *will_flag {
+                    let (properties, properties_size) = match protocol_version {
+                        ProtocolVersion::V31 | ProtocolVersion::V311 => (Cow::Borrowed(&[][..]), 0),
+                        ProtocolVersion::V5 => read_properties(source)?
+                    };
+                    let (topic, topic_size) = read_string(source)?;
+                    let (message, message_size) = read_binary(source)?;
+                    read += properties_size + topic_size + message_size;
+                    Some((properties, TopicName::new(topic)?, message))
+                } else {
+                    None
+                };
+                let username = if *username {
+                    let (username, username_size) = read_string(source)?;
+                    read += username_size;
+                    Some(username)
+                } else {
+                    None
+                };
+                let password = if *password {
+                    let (password, password_size) = read_binary(source)?;
+                    read += password_size;
+                    Some(password)
+                } else {
+                    None
+                };
+                Ok((Some(Payload::Connect { client_id, will, username, password }), read))
+            },
+            (ControlPacketType::Publish, _) => {
+                Ok((Some(Payload::Publish(source.take_bytes(len)?)), len))
+            },
+            (ControlPacketType::Subscribe, _) => {
+                let mut filters = Vec::new();
+                let mut read = 0;
+                while read < len {
+                    let (filter, filter_size) = read_string(source)?;
+                    let (options, options_size) = read_u8(source)?;
+                    read += filter_size + options_size;
+                    filters.push((SubscribeFilter::new(filter), SubscriptionOptions::from_byte(version, options)?));
+                }
+                Ok((Some(Payload::Subscribe(Cow::Owned(filters))), read))
+            },
+            (ControlPacketType::Suback, _) => {
+                let mut return_codes = Vec::with_capacity(len);
+                for byte in source.take_bytes(len)?.iter() {
+                    return_codes.push(SubackReturn::from_byte(version, *byte)?);
+                }
+                Ok((Some(Payload::Suback(Cow::Owned(return_codes))), len))
+            },
+            (ControlPacketType::Unsubscribe, _) => {
+                let mut filters = Vec::new();
+                let mut read = 0;
+                while read < len {
+                    let (filter, filter_size) = read_string(source)?;
+                    read += filter_size;
+                    filters.push(TopicFilter::new(filter)?);
+                }
+                Ok((Some(Payload::Unsubscribe(Cow::Owned(filters))), read))
+            },
+            (ControlPacketType::Unsuback, _) if version == ProtocolVersion::V5 => {
+                let mut reason_codes = Vec::with_capacity(len);
+                for byte in source.take_bytes(len)?.iter() {
+                    reason_codes.push(ReasonCode::from_byte(*byte)?);
+                }
+                Ok((Some(Payload::Unsuback(Cow::Owned(reason_codes))), len))
+            },
+            _ => Ok((None, 0))
         }
     }
+
+    // Usernames and passwords are left out of the payload entirely when there aren't any
+    fn optional_len(bytes: Option<&[u8]>) -> usize {
+        bytes.map_or(0, |bytes| { 2 + bytes.len() })
+    }
 }
 
+// https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc385349285
+// The answer to one of a SUBSCRIBE's topic filters: the QoS the subscription was granted, or
+// why there isn't one
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum SubackReturn {
     AtMostOnce,
     AtLeastOnce,
     ExactlyOnce,
-    Failure
+    Failure,
+    // MQTT 5 failures more specific than 0x80, sent as a plain failure to MQTT 3.1.1 clients
+    Refused(ReasonCode)
 }
 
 impl SubackReturn {
-    fn from_qos(qos: Option<QualityOfService>) -> Self {
+    pub fn from_qos(qos: QualityOfService) -> Self {
         match qos {
-            None => SubackReturn::Failure,
-            Some(QualityOfService::AtMostOnce) => SubackReturn::AtMostOnce,
-            Some(QualityOfService::AtLeastOnce) => SubackReturn::AtLeastOnce,
-            Some(QualityOfService::ExactlyOnce) => SubackReturn::ExactlyOnce
+            QualityOfService::AtMostOnce => SubackReturn::AtMostOnce,
+            QualityOfService::AtLeastOnce => SubackReturn::AtLeastOnce,
+            QualityOfService::ExactlyOnce => SubackReturn::ExactlyOnce
         }
     }
 
-    fn to_qos(&self) -> Option<QualityOfService> {
+    // The QoS granted, if the subscription was made
+    pub fn qos(self) -> Option<QualityOfService> {
         match self {
-            SubackReturn::Failure => None,
             SubackReturn::AtMostOnce => Some(QualityOfService::AtMostOnce),
             SubackReturn::AtLeastOnce => Some(QualityOfService::AtLeastOnce),
-            SubackReturn::ExactlyOnce => Some(QualityOfService::ExactlyOnce)
+            SubackReturn::ExactlyOnce => Some(QualityOfService::ExactlyOnce),
+            SubackReturn::Failure | SubackReturn::Refused(_) => None
         }
     }
 
-    fn to_byte(&self) -> u8 {
-        match self {
-            SubackReturn::AtMostOnce => 0u8,
-            SubackReturn::AtLeastOnce => 1u8,
-            SubackReturn::ExactlyOnce => 2u8,
-            SubackReturn::Failure => 128u8
+    pub fn is_failure(self) -> bool {
+        self.qos().is_none()
+    }
+
+    pub fn to_byte(self, version: ProtocolVersion) -> u8 {
+        match (version, self) {
+            (_, SubackReturn::AtMostOnce) => 0u8,
+            (_, SubackReturn::AtLeastOnce) => 1u8,
+            (_, SubackReturn::ExactlyOnce) => 2u8,
+            (_, SubackReturn::Failure) => 128u8,
+            (ProtocolVersion::V5, SubackReturn::Refused(code)) => code.to_byte(),
+            (_, SubackReturn::Refused(_)) => 128u8
         }
     }
 
-    fn from_byte(byte: u8) -> Result<Self> {
-        match byte {
-            0u8 => Ok(SubackReturn::AtMostOnce),
-            1u8 => Ok(SubackReturn::AtLeastOnce),
-            2u8 => Ok(SubackReturn::ExactlyOnce),
-            128u8 => Ok(SubackReturn::Failure),
-            _ =>
+    pub fn from_byte(version: ProtocolVersion, byte: u8) -> Result<Self> {
+        match (version, byte) {
+            (_, 0u8) => Ok(SubackReturn::AtMostOnce),
+            (_, 1u8) => Ok(SubackReturn::AtLeastOnce),
+            (_, 2u8) => Ok(SubackReturn::ExactlyOnce),
+            (_, 128u8) => Ok(SubackReturn::Failure),
+            (ProtocolVersion::V5, b) => match ReasonCode::from_byte(b)? {
+                code if code.is_failure() => Ok(SubackReturn::Refused(code)),
+                code => {
+                    let msg = format!("{:?} is not a valid suback reason code", code);
+                    Err(Error::new(ErrorKind::InvalidData, msg))
+                }
+            },
+            (_, _) =>
                 Err(Error::new(
                     ErrorKind::InvalidData,
                     "suback return codes must be 0: at most once, 1: at least once, 2: exactly once, or 128: failure"
@@ -82,13 +268,3 @@ impl SubackReturn {
         }
     }
 }
-
-impl<'a> Serde for Payload<'a> {
-    fn ser(&self, sink: &mut Write) -> Result<usize> {
-        Err(Error::new(ErrorKind::Other, "not implemented"))
-    }
-
-    fn de(source: &mut Read) -> Result<(Self, usize)> {
-        Err(Error::new(ErrorKind::Other, "not implemented"))
-    }
-}
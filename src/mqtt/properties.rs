@@ -0,0 +1,359 @@
+use mqtt::*;
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind, Result, Write};
+
+// https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901027
+// MQTT 5 packets carry a block of these after their variable header. An MQTT 3.1.1 connection
+// has no properties, so its messages always have an empty list.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Property<'a> {
+    /* 0x01 */ PayloadFormatIndicator(u8),
+    /* 0x02 */ MessageExpiryInterval(u32),
+    /* 0x03 */ ContentType(Cow<'a, str>),
+    /* 0x08 */ ResponseTopic(Cow<'a, str>),
+    /* 0x09 */ CorrelationData(Cow<'a, [u8]>),
+    /* 0x0B */ SubscriptionIdentifier(u32),
+    /* 0x11 */ SessionExpiryInterval(u32),
+    /* 0x12 */ AssignedClientIdentifier(Cow<'a, str>),
+    /* 0x13 */ ServerKeepAlive(u16),
+    /* 0x15 */ AuthenticationMethod(Cow<'a, str>),
+    /* 0x16 */ AuthenticationData(Cow<'a, [u8]>),
+    /* 0x17 */ RequestProblemInformation(u8),
+    /* 0x18 */ WillDelayInterval(u32),
+    /* 0x19 */ RequestResponseInformation(u8),
+    /* 0x1A */ ResponseInformation(Cow<'a, str>),
+    /* 0x1C */ ServerReference(Cow<'a, str>),
+    /* 0x1F */ ReasonString(Cow<'a, str>),
+    /* 0x21 */ ReceiveMaximum(u16),
+    /* 0x22 */ TopicAliasMaximum(u16),
+    /* 0x23 */ TopicAlias(u16),
+    /* 0x24 */ MaximumQos(u8),
+    /* 0x25 */ RetainAvailable(u8),
+    /* 0x26 */ UserProperty(Cow<'a, str>, Cow<'a, str>),
+    /* 0x27 */ MaximumPacketSize(u32),
+    /* 0x28 */ WildcardSubscriptionAvailable(u8),
+    /* 0x29 */ SubscriptionIdentifierAvailable(u8),
+    /* 0x2A */ SharedSubscriptionAvailable(u8)
+}
+
+pub type Properties<'a> = Vec<Property<'a>>;
+
+impl<'a> Property<'a> {
+    pub fn id(&self) -> u8 {
+        match self {
+            Property::PayloadFormatIndicator(_) => 0x01,
+            Property::MessageExpiryInterval(_) => 0x02,
+            Property::ContentType(_) => 0x03,
+            Property::ResponseTopic(_) => 0x08,
+            Property::CorrelationData(_) => 0x09,
+            Property::SubscriptionIdentifier(_) => 0x0B,
+            Property::SessionExpiryInterval(_) => 0x11,
+            Property::AssignedClientIdentifier(_) => 0x12,
+            Property::ServerKeepAlive(_) => 0x13,
+            Property::AuthenticationMethod(_) => 0x15,
+            Property::AuthenticationData(_) => 0x16,
+            Property::RequestProblemInformation(_) => 0x17,
+            Property::WillDelayInterval(_) => 0x18,
+            Property::RequestResponseInformation(_) => 0x19,
+            Property::ResponseInformation(_) => 0x1A,
+            Property::ServerReference(_) => 0x1C,
+            Property::ReasonString(_) => 0x1F,
+            Property::ReceiveMaximum(_) => 0x21,
+            Property::TopicAliasMaximum(_) => 0x22,
+            Property::TopicAlias(_) => 0x23,
+            Property::MaximumQos(_) => 0x24,
+            Property::RetainAvailable(_) => 0x25,
+            Property::UserProperty(_, _) => 0x26,
+            Property::MaximumPacketSize(_) => 0x27,
+            Property::WildcardSubscriptionAvailable(_) => 0x28,
+            Property::SubscriptionIdentifierAvailable(_) => 0x29,
+            Property::SharedSubscriptionAvailable(_) => 0x2A
+        }
+    }
+
+    // The encoded size, including the identifier byte
+    pub fn size(&self) -> usize {
+        let value_len = match self {
+            Property::PayloadFormatIndicator(_) |
+            Property::RequestProblemInformation(_) |
+            Property::RequestResponseInformation(_) |
+            Property::MaximumQos(_) |
+            Property::RetainAvailable(_) |
+            Property::WildcardSubscriptionAvailable(_) |
+            Property::SubscriptionIdentifierAvailable(_) |
+            Property::SharedSubscriptionAvailable(_) => 1,
+            Property::ServerKeepAlive(_) |
+            Property::ReceiveMaximum(_) |
+            Property::TopicAliasMaximum(_) |
+            Property::TopicAlias(_) => 2,
+            Property::MessageExpiryInterval(_) |
+            Property::SessionExpiryInterval(_) |
+            Property::WillDelayInterval(_) |
+            Property::MaximumPacketSize(_) => 4,
+            Property::SubscriptionIdentifier(n) =>
+                RemainingLength::try_from(*n).map_or(4, |n| { n.size() }),
+            Property::ContentType(s) |
+            Property::ResponseTopic(s) |
+            Property::AssignedClientIdentifier(s) |
+            Property::AuthenticationMethod(s) |
+            Property::ResponseInformation(s) |
+            Property::ServerReference(s) |
+            Property::ReasonString(s) => 2 + s.len(),
+            Property::CorrelationData(b) |
+            Property::AuthenticationData(b) => 2 + b.len(),
+            Property::UserProperty(k, v) => 2 + k.len() + 2 + v.len()
+        };
+        1 + value_len
+    }
+
+    pub fn into_owned(self) -> Property<'static> {
+        fn own<T: ToOwned + ?Sized>(cow: Cow<T>) -> Cow<'static, T> {
+            Cow::Owned(cow.into_owned())
+        }
+        match self {
+            Property::PayloadFormatIndicator(n) => Property::PayloadFormatIndicator(n),
+            Property::MessageExpiryInterval(n) => Property::MessageExpiryInterval(n),
+            Property::ContentType(s) => Property::ContentType(own(s)),
+            Property::ResponseTopic(s) => Property::ResponseTopic(own(s)),
+            Property::CorrelationData(b) => Property::CorrelationData(own(b)),
+            Property::SubscriptionIdentifier(n) => Property::SubscriptionIdentifier(n),
+            Property::SessionExpiryInterval(n) => Property::SessionExpiryInterval(n),
+            Property::AssignedClientIdentifier(s) => Property::AssignedClientIdentifier(own(s)),
+            Property::ServerKeepAlive(n) => Property::ServerKeepAlive(n),
+            Property::AuthenticationMethod(s) => Property::AuthenticationMethod(own(s)),
+            Property::AuthenticationData(b) => Property::AuthenticationData(own(b)),
+            Property::RequestProblemInformation(n) => Property::RequestProblemInformation(n),
+            Property::WillDelayInterval(n) => Property::WillDelayInterval(n),
+            Property::RequestResponseInformation(n) => Property::RequestResponseInformation(n),
+            Property::ResponseInformation(s) => Property::ResponseInformation(own(s)),
+            Property::ServerReference(s) => Property::ServerReference(own(s)),
+            Property::ReasonString(s) => Property::ReasonString(own(s)),
+            Property::ReceiveMaximum(n) => Property::ReceiveMaximum(n),
+            Property::TopicAliasMaximum(n) => Property::TopicAliasMaximum(n),
+            Property::TopicAlias(n) => Property::TopicAlias(n),
+            Property::MaximumQos(n) => Property::MaximumQos(n),
+            Property::RetainAvailable(n) => Property::RetainAvailable(n),
+            Property::UserProperty(k, v) => Property::UserProperty(own(k), own(v)),
+            Property::MaximumPacketSize(n) => Property::MaximumPacketSize(n),
+            Property::WildcardSubscriptionAvailable(n) => Property::WildcardSubscriptionAvailable(n),
+            Property::SubscriptionIdentifierAvailable(n) => Property::SubscriptionIdentifierAvailable(n),
+            Property::SharedSubscriptionAvailable(n) => Property::SharedSubscriptionAvailable(n)
+        }
+    }
+
+    fn ser(&self, sink: &mut dyn Write) -> Result<usize> {
+        let written = write_u8(sink, self.id())?;
+        let value_written = match self {
+            Property::PayloadFormatIndicator(n) |
+            Property::RequestProblemInformation(n) |
+            Property::RequestResponseInformation(n) |
+            Property::MaximumQos(n) |
+            Property::RetainAvailable(n) |
+            Property::WildcardSubscriptionAvailable(n) |
+            Property::SubscriptionIdentifierAvailable(n) |
+            Property::SharedSubscriptionAvailable(n) => write_u8(sink, *n)?,
+            Property::ServerKeepAlive(n) |
+            Property::ReceiveMaximum(n) |
+            Property::TopicAliasMaximum(n) |
+            Property::TopicAlias(n) => write_u16(sink, *n)?,
+            Property::MessageExpiryInterval(n) |
+            Property::SessionExpiryInterval(n) |
+            Property::WillDelayInterval(n) |
+            Property::MaximumPacketSize(n) => write_u32(sink, *n)?,
+            Property::SubscriptionIdentifier(n) => RemainingLength::try_from(*n)?.ser(sink)?,
+            Property::ContentType(s) |
+            Property::ResponseTopic(s) |
+            Property::AssignedClientIdentifier(s) |
+            Property::AuthenticationMethod(s) |
+            Property::ResponseInformation(s) |
+            Property::ServerReference(s) |
+            Property::ReasonString(s) => write_string(sink, s)?,
+            Property::CorrelationData(b) |
+            Property::AuthenticationData(b) => write_binary(sink, b)?,
+            Property::UserProperty(k, v) => write_string(sink, k)? + write_string(sink, v)?
+        };
+        Ok(written + value_written)
+    }
+
+    fn de(source: &mut dyn Source<'a>) -> Result<(Self, usize)> {
+        let (id, id_size) = read_u8(source)?;
+        let (property, size) = match id {
+            0x01 => Property::de_u8(source, Property::PayloadFormatIndicator)?,
+            0x02 => Property::de_u32(source, Property::MessageExpiryInterval)?,
+            0x03 => Property::de_string(source, Property::ContentType)?,
+            0x08 => Property::de_string(source, Property::ResponseTopic)?,
+            0x09 => Property::de_binary(source, Property::CorrelationData)?,
+            0x0B => {
+                let (n, size) = RemainingLength::de_source(source)?;
+                (Property::SubscriptionIdentifier(n.into()), size)
+            },
+            0x11 => Property::de_u32(source, Property::SessionExpiryInterval)?,
+            0x12 => Property::de_string(source, Property::AssignedClientIdentifier)?,
+            0x13 => Property::de_u16(source, Property::ServerKeepAlive)?,
+            0x15 => Property::de_string(source, Property::AuthenticationMethod)?,
+            0x16 => Property::de_binary(source, Property::AuthenticationData)?,
+            0x17 => Property::de_u8(source, Property::RequestProblemInformation)?,
+            0x18 => Property::de_u32(source, Property::WillDelayInterval)?,
+            0x19 => Property::de_u8(source, Property::RequestResponseInformation)?,
+            0x1A => Property::de_string(source, Property::ResponseInformation)?,
+            0x1C => Property::de_string(source, Property::ServerReference)?,
+            0x1F => Property::de_string(source, Property::ReasonString)?,
+            0x21 => Property::de_u16(source, Property::ReceiveMaximum)?,
+            0x22 => Property::de_u16(source, Property::TopicAliasMaximum)?,
+            0x23 => Property::de_u16(source, Property::TopicAlias)?,
+            0x24 => Property::de_u8(source, Property::MaximumQos)?,
+            0x25 => Property::de_u8(source, Property::RetainAvailable)?,
+            0x26 => {
+                let (k, k_size) = read_string(source)?;
+                let (v, v_size) = read_string(source)?;
+                (Property::UserProperty(k, v), k_size + v_size)
+            },
+            0x27 => Property::de_u32(source, Property::MaximumPacketSize)?,
+            0x28 => Property::de_u8(source, Property::WildcardSubscriptionAvailable)?,
+            0x29 => Property::de_u8(source, Property::SubscriptionIdentifierAvailable)?,
+            0x2A => Property::de_u8(source, Property::SharedSubscriptionAvailable)?,
+            n => {
+                let msg = format!("{:#04x} is not a valid property identifier", n);
+                return Err(Error::new(ErrorKind::InvalidData, msg))
+            }
+        };
+        Ok((property, id_size + size))
+    }
+
+    fn de_u8(source: &mut dyn Source, variant: fn(u8) -> Self) -> Result<(Self, usize)> {
+        let (n, size) = read_u8(source)?;
+        Ok((variant(n), size))
+    }
+
+    fn de_u16(source: &mut dyn Source, variant: fn(u16) -> Self) -> Result<(Self, usize)> {
+        let (n, size) = read_u16(source)?;
+        Ok((variant(n), size))
+    }
+
+    fn de_u32(source: &mut dyn Source, variant: fn(u32) -> Self) -> Result<(Self, usize)> {
+        let (n, size) = read_u32(source)?;
+        Ok((variant(n), size))
+    }
+
+    fn de_string(source: &mut dyn Source<'a>, variant: fn(Cow<'a, str>) -> Self) -> Result<(Self, usize)> {
+        let (s, size) = read_string(source)?;
+        Ok((variant(s), size))
+    }
+
+    fn de_binary(source: &mut dyn Source<'a>, variant: fn(Cow<'a, [u8]>) -> Self) -> Result<(Self, usize)> {
+        let (b, size) = read_binary(source)?;
+        Ok((variant(b), size))
+    }
+}
+
+// The size of a property block on the wire: its variable byte integer length, then the properties
+pub(crate) fn properties_size(properties: &[Property]) -> usize {
+    let len: usize = properties.iter().map(Property::size).sum();
+    RemainingLength::try_from(len as u32).map_or(4, |n| { n.size() }) + len
+}
+
+pub(crate) fn write_properties(sink: &mut dyn Write, properties: &[Property]) -> Result<usize> {
+    let len: usize = properties.iter().map(Property::size).sum();
+    let mut written = RemainingLength::try_from(len as u32)?.ser(sink)?;
+    for property in properties {
+        written += property.ser(sink)?;
+    }
+    Ok(written)
+}
+
+pub(crate) fn read_properties<'a>(source: &mut dyn Source<'a>) -> Result<(Cow<'a, [Property<'a>]>, usize)> {
+    let (len, len_size) = RemainingLength::de_source(source)?;
+    let len: u32 = len.into();
+    let mut properties = Vec::new();
+    let mut read = 0;
+    while read < len as usize {
+        let (property, size) = Property::de(source)?;
+        read += size;
+        properties.push(property);
+    }
+    if read != len as usize {
+        let msg = format!("property length was {} but the properties were {} bytes", len, read);
+        return Err(Error::new(ErrorKind::InvalidData, msg))
+    }
+    Ok((Cow::Owned(properties), len_size + read))
+}
+
+#[cfg(test)]
+mod tests {
+    use mqtt::*;
+    use std::borrow::Cow;
+    use std::io::ErrorKind;
+
+    // One of every property there is
+    fn every_property() -> Properties<'static> {
+        vec![
+            Property::PayloadFormatIndicator(1),
+            Property::MessageExpiryInterval(3600),
+            Property::ContentType(Cow::Borrowed("text/plain")),
+            Property::ResponseTopic(Cow::Borrowed("reply")),
+            Property::CorrelationData(Cow::Borrowed(b"\x00\x01")),
+            Property::SubscriptionIdentifier(128),
+            Property::SessionExpiryInterval(u32::MAX),
+            Property::AssignedClientIdentifier(Cow::Borrowed("assigned")),
+            Property::ServerKeepAlive(30),
+            Property::AuthenticationMethod(Cow::Borrowed("SCRAM-SHA-1")),
+            Property::AuthenticationData(Cow::Borrowed(b"data")),
+            Property::RequestProblemInformation(0),
+            Property::WillDelayInterval(10),
+            Property::RequestResponseInformation(1),
+            Property::ResponseInformation(Cow::Borrowed("info")),
+            Property::ServerReference(Cow::Borrowed("elsewhere")),
+            Property::ReasonString(Cow::Borrowed("reason")),
+            Property::ReceiveMaximum(100),
+            Property::TopicAliasMaximum(10),
+            Property::TopicAlias(1),
+            Property::MaximumQos(1),
+            Property::RetainAvailable(0),
+            Property::UserProperty(Cow::Borrowed("key"), Cow::Borrowed("value")),
+            Property::MaximumPacketSize(1 << 20),
+            Property::WildcardSubscriptionAvailable(1),
+            Property::SubscriptionIdentifierAvailable(0),
+            Property::SharedSubscriptionAvailable(0)
+        ]
+    }
+
+    fn read(bytes: &[u8]) -> ::std::io::Result<(Cow<'_, [Property<'_>]>, usize)> {
+        read_properties(&mut SliceSource(bytes))
+    }
+
+    #[test]
+    fn every_property_round_trips() {
+        let properties = every_property();
+        let mut encoded = Vec::new();
+        let written = write_properties(&mut encoded, &properties).unwrap();
+        assert_eq!(written, encoded.len());
+        assert_eq!(properties_size(&properties), encoded.len());
+        let (decoded, read_size) = read(&encoded).unwrap();
+        assert_eq!(read_size, encoded.len());
+        assert_eq!(&*decoded, &properties[..]);
+    }
+
+    #[test]
+    fn lengths_are_variable_byte_integers() {
+        let mut encoded = Vec::new();
+        write_properties(&mut encoded, &[Property::SubscriptionIdentifier(128)]).unwrap();
+        assert_eq!(encoded, vec![0x03, 0x0B, 0x80, 0x01]);
+        encoded.clear();
+        write_properties(&mut encoded, &[]).unwrap();
+        assert_eq!(encoded, vec![0x00]);
+    }
+
+    #[test]
+    fn refuses_malformed_property_blocks() {
+        let kind = |bytes: &[u8]| { read(bytes).map(|_| ()).unwrap_err().kind() };
+        // Unknown property identifier
+        assert_eq!(kind(&[0x01, 0x00]), ErrorKind::InvalidData);
+        // Block length ending partway through a property
+        assert_eq!(kind(&[0x01, 0x21, 0x00, 0x0A]), ErrorKind::InvalidData);
+        // Property running past the end of the packet
+        assert_eq!(kind(&[0x03, 0x21, 0x00]), ErrorKind::UnexpectedEof);
+        // String property that isn't UTF-8
+        assert_eq!(kind(&[0x04, 0x1F, 0x00, 0x01, 0xFF]), ErrorKind::InvalidData);
+    }
+}
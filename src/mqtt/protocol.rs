@@ -0,0 +1,90 @@
+use mqtt::*;
+use std::error;
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+
+// The protocol revision a connection speaks, as announced by the name and level in its CONNECT
+// packet. Everything after the CONNECT is encoded and decoded according to it.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum ProtocolVersion {
+    V31,
+    #[default]
+    V311,
+    V5
+}
+
+impl ProtocolVersion {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProtocolVersion::V31 => "MQIsdp",
+            ProtocolVersion::V311 | ProtocolVersion::V5 => "MQTT"
+        }
+    }
+
+    pub fn level(&self) -> u8 {
+        match self {
+            ProtocolVersion::V31 => 3,
+            ProtocolVersion::V311 => 4,
+            ProtocolVersion::V5 => 5
+        }
+    }
+
+    // A known protocol name with a level we don't speak is an `UnsupportedProtocolVersion`,
+    // which the server answers with a CONNACK rather than just closing the connection
+    pub fn from_name_and_level(name: &str, level: u8) -> Result<Self> {
+        match (name, level) {
+            ("MQIsdp", 3) => Ok(ProtocolVersion::V31),
+            ("MQTT", 4) => Ok(ProtocolVersion::V311),
+            ("MQTT", 5) => Ok(ProtocolVersion::V5),
+            ("MQIsdp", _) | ("MQTT", _) => {
+                let unsupported = UnsupportedProtocolVersion { name: name.to_string(), level };
+                Err(Error::new(ErrorKind::InvalidData, unsupported))
+            },
+            _ => {
+                let msg = format!("'{}' is not a supported protocol name", name);
+                Err(Error::new(ErrorKind::InvalidData, msg))
+            }
+        }
+    }
+
+    // MQTT 3.1 limits client identifiers to between 1 and 23 characters; later versions leave
+    // the limit up to the server
+    pub fn accepts_client_id(&self, client_id: &str) -> bool {
+        match self {
+            ProtocolVersion::V31 => !client_id.is_empty() && client_id.chars().count() <= 23,
+            ProtocolVersion::V311 | ProtocolVersion::V5 => true
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UnsupportedProtocolVersion {
+    pub name: String,
+    pub level: u8
+}
+
+impl UnsupportedProtocolVersion {
+    // Finds the unsupported version behind a decoding error, if that's what caused it
+    pub fn from_error(error: &Error) -> Option<&Self> {
+        error.get_ref().and_then(|e| { e.downcast_ref::<UnsupportedProtocolVersion>() })
+    }
+
+    // https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc385349242
+    // The server must answer with return code 1 and then close the connection. The client's
+    // version is unknown, so this is encoded as MQTT 3.1.1, which 3.1 clients also understand.
+    pub fn connack(&self) -> OwnedMessage {
+        Message::Connack {
+            session_present: false,
+            return_code: ConnackReturnCode::UnacceptableProtocolVersion,
+            properties: Vec::new()
+        }
+    }
+}
+
+impl fmt::Display for UnsupportedProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} is not a supported protocol level for '{}'", self.level, self.name)
+    }
+}
+
+impl error::Error for UnsupportedProtocolVersion {}
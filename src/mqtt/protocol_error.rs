@@ -0,0 +1,133 @@
+use mqtt::*;
+use std::error;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+// A packet that breaks one of the specification's normative statements. These are carried
+// inside `std::io::Error`s (as `InvalidData` when decoding, `InvalidInput` when encoding), and
+// recovered with `ProtocolError::from_error`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ProtocolError {
+    InvalidUtf8,
+    NullCharacter,
+    ReservedFlags { packet_type: ControlPacketType, flags: [bool; 4] },
+    InvalidQos(u8),
+    PacketIdWithQosZero,
+    MissingPacketId,
+    ZeroPacketId(ControlPacketType),
+    DupWithQosZero,
+    ReservedConnectFlag,
+    WillQosWithoutWill,
+    InvalidWillQos,
+    WillRetainWithoutWill,
+    PasswordWithoutUsername,
+    EmptySubscribe,
+    EmptyUnsubscribe,
+    ReservedSubscriptionOptions(u8),
+    InvalidSubscriptionQos(u8),
+    EmptyTopic,
+    TopicTooLong(usize),
+    WildcardInTopicName,
+    MisplacedMultiLevelWildcard,
+    MisplacedSingleLevelWildcard
+}
+
+impl ProtocolError {
+    // https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc385349373
+    // The MQTT 3.1.1 conformance statement the packet breaks
+    pub fn rule(&self) -> &'static str {
+        match self {
+            ProtocolError::InvalidUtf8 => "MQTT-1.5.3-1",
+            ProtocolError::NullCharacter => "MQTT-1.5.3-2",
+            ProtocolError::ReservedFlags { .. } => "MQTT-2.2.2-2",
+            ProtocolError::InvalidQos(_) => "MQTT-3.3.1-4",
+            ProtocolError::PacketIdWithQosZero => "MQTT-2.3.1-5",
+            ProtocolError::MissingPacketId => "MQTT-2.3.1-1",
+            ProtocolError::ZeroPacketId(_) => "MQTT-2.3.1-1",
+            ProtocolError::DupWithQosZero => "MQTT-3.3.1-2",
+            ProtocolError::ReservedConnectFlag => "MQTT-3.1.2-3",
+            ProtocolError::WillQosWithoutWill => "MQTT-3.1.2-13",
+            ProtocolError::InvalidWillQos => "MQTT-3.1.2-14",
+            ProtocolError::WillRetainWithoutWill => "MQTT-3.1.2-15",
+            ProtocolError::PasswordWithoutUsername => "MQTT-3.1.2-22",
+            ProtocolError::EmptySubscribe => "MQTT-3.8.3-3",
+            ProtocolError::EmptyUnsubscribe => "MQTT-3.10.3-2",
+            ProtocolError::ReservedSubscriptionOptions(_) => "MQTT-3.8.3-4",
+            ProtocolError::InvalidSubscriptionQos(_) => "MQTT-3.8.3-4",
+            ProtocolError::EmptyTopic => "MQTT-4.7.3-1",
+            ProtocolError::TopicTooLong(_) => "MQTT-4.7.3-3",
+            ProtocolError::WildcardInTopicName => "MQTT-3.3.2-2",
+            ProtocolError::MisplacedMultiLevelWildcard => "MQTT-4.7.1-2",
+            ProtocolError::MisplacedSingleLevelWildcard => "MQTT-4.7.1-3"
+        }
+    }
+
+    // Finds the protocol error behind an I/O error, if that's what caused it
+    pub fn from_error(error: &Error) -> Option<&Self> {
+        error.get_ref().and_then(|e| { e.downcast_ref::<ProtocolError>() })
+    }
+
+    pub(crate) fn into_encoding_error(self) -> Error {
+        Error::new(ErrorKind::InvalidInput, self)
+    }
+}
+
+impl From<ProtocolError> for Error {
+    fn from(protocol_error: ProtocolError) -> Self {
+        Error::new(ErrorKind::InvalidData, protocol_error)
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::InvalidUtf8 =>
+                write!(f, "strings must be valid UTF-8")?,
+            ProtocolError::NullCharacter =>
+                write!(f, "strings must not contain U+0000")?,
+            ProtocolError::ReservedFlags { packet_type, flags } =>
+                write!(f, "{:?} packets must not set reserved fixed header flags, got {:?}", packet_type, flags)?,
+            ProtocolError::InvalidQos(qos) =>
+                write!(f, "qos must be 0, 1, or 2, got {}", qos)?,
+            ProtocolError::PacketIdWithQosZero =>
+                write!(f, "qos 0 publishes must not have a packet id")?,
+            ProtocolError::MissingPacketId =>
+                write!(f, "qos 1 and 2 publishes must have a packet id")?,
+            ProtocolError::ZeroPacketId(packet_type) =>
+                write!(f, "{:?} packets must have a non-zero packet id", packet_type)?,
+            ProtocolError::DupWithQosZero =>
+                write!(f, "qos 0 publishes must not set the dup flag")?,
+            ProtocolError::ReservedConnectFlag =>
+                write!(f, "the reserved connect flag must be 0")?,
+            ProtocolError::WillQosWithoutWill =>
+                write!(f, "will qos must be 0 when there is no will")?,
+            ProtocolError::InvalidWillQos =>
+                write!(f, "will qos must be 0, 1, or 2, got 3")?,
+            ProtocolError::WillRetainWithoutWill =>
+                write!(f, "will retain must be 0 when there is no will")?,
+            ProtocolError::PasswordWithoutUsername =>
+                write!(f, "a password must not be sent without a username")?,
+            ProtocolError::EmptySubscribe =>
+                write!(f, "subscribes must have at least one topic filter")?,
+            ProtocolError::EmptyUnsubscribe =>
+                write!(f, "unsubscribes must have at least one topic filter")?,
+            ProtocolError::ReservedSubscriptionOptions(options) =>
+                write!(f, "subscription options {:#010b} set reserved bits", options)?,
+            ProtocolError::InvalidSubscriptionQos(qos) =>
+                write!(f, "subscription qos must be 0, 1, or 2, got {}", qos)?,
+            ProtocolError::EmptyTopic =>
+                write!(f, "topics must be at least one character long")?,
+            ProtocolError::TopicTooLong(len) =>
+                write!(f, "topics must be at most 65535 bytes, got {}", len)?,
+            ProtocolError::WildcardInTopicName =>
+                write!(f, "topic names must not contain wildcards")?,
+            ProtocolError::MisplacedMultiLevelWildcard =>
+                write!(f, "'#' must be a topic filter's last level, on its own")?,
+            ProtocolError::MisplacedSingleLevelWildcard =>
+                write!(f, "'+' must be a topic filter level on its own")?
+        };
+        write!(f, " [{}]", self.rule())
+    }
+}
+
+impl error::Error for ProtocolError {}
@@ -1,8 +1,8 @@
 use mqtt::*;
 use std::cmp::Ordering;
-use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::io::{Read, Result, Write};
 
-#[derive(Copy, Clone, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum QualityOfService {
     AtMostOnce,
     AtLeastOnce,
@@ -12,12 +12,12 @@ pub enum QualityOfService {
 impl Ord for QualityOfService {
     fn cmp(&self, other: &QualityOfService) -> Ordering {
         match (self, other) {
-            (QualityOfService::AtLeastOnce, QualityOfService::AtLeastOnce) => Ordering::Equal,
-            (QualityOfService::AtLeastOnce, _) => Ordering::Less,
-
-            (QualityOfService::AtMostOnce, QualityOfService::AtLeastOnce) => Ordering::Greater,
             (QualityOfService::AtMostOnce, QualityOfService::AtMostOnce) => Ordering::Equal,
-            (QualityOfService::AtMostOnce, QualityOfService::ExactlyOnce) => Ordering::Less,
+            (QualityOfService::AtMostOnce, _) => Ordering::Less,
+
+            (QualityOfService::AtLeastOnce, QualityOfService::AtMostOnce) => Ordering::Greater,
+            (QualityOfService::AtLeastOnce, QualityOfService::AtLeastOnce) => Ordering::Equal,
+            (QualityOfService::AtLeastOnce, QualityOfService::ExactlyOnce) => Ordering::Less,
 
             (QualityOfService::ExactlyOnce, QualityOfService::ExactlyOnce) => Ordering::Equal,
             (QualityOfService::ExactlyOnce, _) => Ordering::Greater,
@@ -39,26 +39,39 @@ impl QualityOfService {
             QualityOfService::ExactlyOnce => (true, false)
         }
     }
+
+    pub fn from_byte(byte: u8) -> Result<Self> {
+        match byte {
+            0u8 => Ok(QualityOfService::AtMostOnce),
+            1u8 => Ok(QualityOfService::AtLeastOnce),
+            2u8 => Ok(QualityOfService::ExactlyOnce),
+            n => Err(ProtocolError::InvalidQos(n).into())
+        }
+    }
+
+    pub fn from_bits(high: bool, low: bool) -> Result<Self> {
+        match (high, low) {
+            (false, false) => Ok(QualityOfService::AtMostOnce),
+            (false, true) => Ok(QualityOfService::AtLeastOnce),
+            (true, false) => Ok(QualityOfService::ExactlyOnce),
+            (true, true) => Err(ProtocolError::InvalidQos(3).into())
+        }
+    }
 }
 
 impl Serde for QualityOfService {
-    fn ser(&self, sink: &mut Write) -> Result<usize> {
+    fn ser(&self, sink: &mut dyn Write) -> Result<usize> {
         let byte = match &self {
             QualityOfService::AtMostOnce => 0u8,
             QualityOfService::AtLeastOnce => 1u8,
             QualityOfService::ExactlyOnce => 2u8,
         };
-        sink.write(&[byte])
+        write_u8(sink, byte)
     }
 
-    fn de(source: &mut Read) -> Result<(QualityOfService, usize)> {
+    fn de(source: &mut dyn Read) -> Result<(QualityOfService, usize)> {
         let mut buffer = [0u8; 1];
-        let read = source.read(& mut buffer[..])?;
-        match buffer[0] {
-            0u8 => Ok((QualityOfService::AtMostOnce, read)),
-            1u8 => Ok((QualityOfService::AtLeastOnce, read)),
-            2u8 => Ok((QualityOfService::ExactlyOnce, read)),
-            _ => Err(Error::new(ErrorKind::Other, "qos must be 0, 1, or 2"))
-        }
+        source.read_exact(&mut buffer)?;
+        Ok((QualityOfService::from_byte(buffer[0])?, 1))
     }
 }
@@ -0,0 +1,158 @@
+use std::io::{Error, ErrorKind, Result};
+
+// https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901031
+// MQTT 5 acknowledgements, DISCONNECT and AUTH carry one of these. Several codes share a byte
+// (0x00 is "success", "normal disconnection" and "granted QoS 0" depending on the packet), so
+// only one name is given to each byte.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ReasonCode {
+    /* 0x00 */ Success,
+    /* 0x01 */ GrantedQos1,
+    /* 0x02 */ GrantedQos2,
+    /* 0x04 */ DisconnectWithWill,
+    /* 0x10 */ NoMatchingSubscribers,
+    /* 0x11 */ NoSubscriptionExisted,
+    /* 0x18 */ ContinueAuthentication,
+    /* 0x19 */ ReAuthenticate,
+    /* 0x80 */ UnspecifiedError,
+    /* 0x81 */ MalformedPacket,
+    /* 0x82 */ ProtocolError,
+    /* 0x83 */ ImplementationSpecificError,
+    /* 0x84 */ UnsupportedProtocolVersion,
+    /* 0x85 */ ClientIdentifierNotValid,
+    /* 0x86 */ BadUserNameOrPassword,
+    /* 0x87 */ NotAuthorized,
+    /* 0x88 */ ServerUnavailable,
+    /* 0x89 */ ServerBusy,
+    /* 0x8A */ Banned,
+    /* 0x8B */ ServerShuttingDown,
+    /* 0x8C */ BadAuthenticationMethod,
+    /* 0x8D */ KeepAliveTimeout,
+    /* 0x8E */ SessionTakenOver,
+    /* 0x8F */ TopicFilterInvalid,
+    /* 0x90 */ TopicNameInvalid,
+    /* 0x91 */ PacketIdentifierInUse,
+    /* 0x92 */ PacketIdentifierNotFound,
+    /* 0x93 */ ReceiveMaximumExceeded,
+    /* 0x94 */ TopicAliasInvalid,
+    /* 0x95 */ PacketTooLarge,
+    /* 0x96 */ MessageRateTooHigh,
+    /* 0x97 */ QuotaExceeded,
+    /* 0x98 */ AdministrativeAction,
+    /* 0x99 */ PayloadFormatInvalid,
+    /* 0x9A */ RetainNotSupported,
+    /* 0x9B */ QosNotSupported,
+    /* 0x9C */ UseAnotherServer,
+    /* 0x9D */ ServerMoved,
+    /* 0x9E */ SharedSubscriptionsNotSupported,
+    /* 0x9F */ ConnectionRateExceeded,
+    /* 0xA0 */ MaximumConnectTime,
+    /* 0xA1 */ SubscriptionIdentifiersNotSupported,
+    /* 0xA2 */ WildcardSubscriptionsNotSupported
+}
+
+impl ReasonCode {
+    pub fn to_byte(&self) -> u8 {
+        match self {
+            ReasonCode::Success => 0x00,
+            ReasonCode::GrantedQos1 => 0x01,
+            ReasonCode::GrantedQos2 => 0x02,
+            ReasonCode::DisconnectWithWill => 0x04,
+            ReasonCode::NoMatchingSubscribers => 0x10,
+            ReasonCode::NoSubscriptionExisted => 0x11,
+            ReasonCode::ContinueAuthentication => 0x18,
+            ReasonCode::ReAuthenticate => 0x19,
+            ReasonCode::UnspecifiedError => 0x80,
+            ReasonCode::MalformedPacket => 0x81,
+            ReasonCode::ProtocolError => 0x82,
+            ReasonCode::ImplementationSpecificError => 0x83,
+            ReasonCode::UnsupportedProtocolVersion => 0x84,
+            ReasonCode::ClientIdentifierNotValid => 0x85,
+            ReasonCode::BadUserNameOrPassword => 0x86,
+            ReasonCode::NotAuthorized => 0x87,
+            ReasonCode::ServerUnavailable => 0x88,
+            ReasonCode::ServerBusy => 0x89,
+            ReasonCode::Banned => 0x8A,
+            ReasonCode::ServerShuttingDown => 0x8B,
+            ReasonCode::BadAuthenticationMethod => 0x8C,
+            ReasonCode::KeepAliveTimeout => 0x8D,
+            ReasonCode::SessionTakenOver => 0x8E,
+            ReasonCode::TopicFilterInvalid => 0x8F,
+            ReasonCode::TopicNameInvalid => 0x90,
+            ReasonCode::PacketIdentifierInUse => 0x91,
+            ReasonCode::PacketIdentifierNotFound => 0x92,
+            ReasonCode::ReceiveMaximumExceeded => 0x93,
+            ReasonCode::TopicAliasInvalid => 0x94,
+            ReasonCode::PacketTooLarge => 0x95,
+            ReasonCode::MessageRateTooHigh => 0x96,
+            ReasonCode::QuotaExceeded => 0x97,
+            ReasonCode::AdministrativeAction => 0x98,
+            ReasonCode::PayloadFormatInvalid => 0x99,
+            ReasonCode::RetainNotSupported => 0x9A,
+            ReasonCode::QosNotSupported => 0x9B,
+            ReasonCode::UseAnotherServer => 0x9C,
+            ReasonCode::ServerMoved => 0x9D,
+            ReasonCode::SharedSubscriptionsNotSupported => 0x9E,
+            ReasonCode::ConnectionRateExceeded => 0x9F,
+            ReasonCode::MaximumConnectTime => 0xA0,
+            ReasonCode::SubscriptionIdentifiersNotSupported => 0xA1,
+            ReasonCode::WildcardSubscriptionsNotSupported => 0xA2
+        }
+    }
+
+    pub fn from_byte(b: u8) -> Result<Self> {
+        match b {
+            0x00 => Ok(ReasonCode::Success),
+            0x01 => Ok(ReasonCode::GrantedQos1),
+            0x02 => Ok(ReasonCode::GrantedQos2),
+            0x04 => Ok(ReasonCode::DisconnectWithWill),
+            0x10 => Ok(ReasonCode::NoMatchingSubscribers),
+            0x11 => Ok(ReasonCode::NoSubscriptionExisted),
+            0x18 => Ok(ReasonCode::ContinueAuthentication),
+            0x19 => Ok(ReasonCode::ReAuthenticate),
+            0x80 => Ok(ReasonCode::UnspecifiedError),
+            0x81 => Ok(ReasonCode::MalformedPacket),
+            0x82 => Ok(ReasonCode::ProtocolError),
+            0x83 => Ok(ReasonCode::ImplementationSpecificError),
+            0x84 => Ok(ReasonCode::UnsupportedProtocolVersion),
+            0x85 => Ok(ReasonCode::ClientIdentifierNotValid),
+            0x86 => Ok(ReasonCode::BadUserNameOrPassword),
+            0x87 => Ok(ReasonCode::NotAuthorized),
+            0x88 => Ok(ReasonCode::ServerUnavailable),
+            0x89 => Ok(ReasonCode::ServerBusy),
+            0x8A => Ok(ReasonCode::Banned),
+            0x8B => Ok(ReasonCode::ServerShuttingDown),
+            0x8C => Ok(ReasonCode::BadAuthenticationMethod),
+            0x8D => Ok(ReasonCode::KeepAliveTimeout),
+            0x8E => Ok(ReasonCode::SessionTakenOver),
+            0x8F => Ok(ReasonCode::TopicFilterInvalid),
+            0x90 => Ok(ReasonCode::TopicNameInvalid),
+            0x91 => Ok(ReasonCode::PacketIdentifierInUse),
+            0x92 => Ok(ReasonCode::PacketIdentifierNotFound),
+            0x93 => Ok(ReasonCode::ReceiveMaximumExceeded),
+            0x94 => Ok(ReasonCode::TopicAliasInvalid),
+            0x95 => Ok(ReasonCode::PacketTooLarge),
+            0x96 => Ok(ReasonCode::MessageRateTooHigh),
+            0x97 => Ok(ReasonCode::QuotaExceeded),
+            0x98 => Ok(ReasonCode::AdministrativeAction),
+            0x99 => Ok(ReasonCode::PayloadFormatInvalid),
+            0x9A => Ok(ReasonCode::RetainNotSupported),
+            0x9B => Ok(ReasonCode::QosNotSupported),
+            0x9C => Ok(ReasonCode::UseAnotherServer),
+            0x9D => Ok(ReasonCode::ServerMoved),
+            0x9E => Ok(ReasonCode::SharedSubscriptionsNotSupported),
+            0x9F => Ok(ReasonCode::ConnectionRateExceeded),
+            0xA0 => Ok(ReasonCode::MaximumConnectTime),
+            0xA1 => Ok(ReasonCode::SubscriptionIdentifiersNotSupported),
+            0xA2 => Ok(ReasonCode::WildcardSubscriptionsNotSupported),
+            n => {
+                let msg = format!("{:#04x} is not a valid reason code", n);
+                Err(Error::new(ErrorKind::InvalidData, msg))
+            }
+        }
+    }
+
+    pub fn is_failure(&self) -> bool {
+        self.to_byte() >= 0x80
+    }
+}
@@ -25,63 +25,90 @@ impl TryFrom<u32> for RemainingLength {
     }
 }
 
-impl Into<u32> for RemainingLength {
-    fn into(self) -> u32 {
-        self.0
+impl From<RemainingLength> for u32 {
+    fn from(remaining_length: RemainingLength) -> u32 {
+        remaining_length.0
     }
 }
 
 impl Serde for RemainingLength {
-    fn ser(&self, sink: &mut Write) -> Result<usize> {
+    fn ser(&self, sink: &mut dyn Write) -> Result<usize> {
         match *self {
-            RemainingLength(0) => sink.write(&[0u8]),
+            RemainingLength(0) => write_u8(sink, 0u8),
             RemainingLength(value) if value <= RemainingLength::MAX_SIZE => {
                 let mut output = Vec::<u8>::new();
                 let mut x = value;
                 while x > 0 {
                     let mut encoded: u8 = (x % 128) as u8;
-                    x = x / 128;
+                    x /= 128;
                     if x > 0 {
-                        encoded = encoded | 128u8;
+                        encoded |= 128u8;
                     }
                     output.push(encoded);
                 }
-                sink.write(output.as_slice())
+                sink.write_all(output.as_slice())?;
+                Ok(output.len())
             },
             _ => RemainingLength::overflow_error()
         }
     }
 
-    fn de(source: &mut Read) -> Result<(Self, usize)> {
+    fn de(source: &mut dyn Read) -> Result<(Self, usize)> {
         let mut value = 0u32;
-        let mut bytes_read = 032;
         let mut mult = 1u32;
-        for b in source.bytes() {
-            bytes_read += 1;
-            if bytes_read == 5 {
-                return Err(Error::new(ErrorKind::InvalidData, "Remaining length is too large"))
-            } else {
-                match b {
-                    Ok(b) => {
-                        mult *= 128u32;
-                        let decoded = (b & 127u8) as u32 * mult;
-                        value += decoded;
-                        let final_byte = b & 128u8 == 0;
-                        if final_byte {
-                            break
-                        }
-                    },
-                    Err(e) => return Err(e)
-                }
+        let mut buf = [0u8; 1];
+        for bytes_read in 1..5 {
+            source.read_exact(&mut buf)?;
+            value += (buf[0] & 127u8) as u32 * mult;
+            let final_byte = buf[0] & 128u8 == 0;
+            if final_byte {
+                return Ok((RemainingLength(value), bytes_read))
             }
+            mult *= 128u32;
         }
-        Ok((RemainingLength(value), bytes_read as usize))
+        Err(Error::new(ErrorKind::InvalidData, "Remaining length is too large"))
     }
 }
 
 impl RemainingLength {
     pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
-        Err(Error::new(ErrorKind::InvalidData, "Unimplemented"))
+        RemainingLength::parse(bytes).map(|(remaining_length, _)| { remaining_length })
+    }
+
+    // Decodes the remaining length at the start of `bytes`, returning it and its size. Fails with
+    // `ErrorKind::UnexpectedEof` if `bytes` ends partway through it.
+    pub fn parse(bytes: &[u8]) -> Result<(Self, usize)> {
+        let mut value = 0u32;
+        let mut mult = 1u32;
+        for (idx, b) in bytes.iter().take(4).enumerate() {
+            value += (b & 127u8) as u32 * mult;
+            let final_byte = b & 128u8 == 0;
+            if final_byte {
+                return Ok((RemainingLength(value), idx + 1))
+            }
+            mult *= 128u32;
+        }
+        if bytes.len() < 4 {
+            Err(Error::new(ErrorKind::UnexpectedEof, "Remaining length ended early"))
+        } else {
+            Err(Error::new(ErrorKind::InvalidData, "Remaining length is too large"))
+        }
+    }
+
+    // Other variable byte integers in a packet, like MQTT 5 property lengths, use the same encoding
+    pub(crate) fn de_source(source: &mut dyn Source) -> Result<(Self, usize)> {
+        let mut value = 0u32;
+        let mut mult = 1u32;
+        for bytes_read in 1..5 {
+            let (b, _) = read_u8(source)?;
+            value += (b & 127u8) as u32 * mult;
+            let final_byte = b & 128u8 == 0;
+            if final_byte {
+                return Ok((RemainingLength(value), bytes_read))
+            }
+            mult *= 128u32;
+        }
+        Err(Error::new(ErrorKind::InvalidData, "Variable byte integer is too large"))
     }
 
     pub fn size(&self) -> usize {
@@ -0,0 +1,230 @@
+use mqtt::*;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+
+// https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc385349265
+// The last PUBLISH sent to each topic with the retain flag set, for new subscribers to get
+// straight away rather than waiting for the next one. Indexed by topic level, like
+// `Subscriptions`, so that a subscription only visits the topics its filter could match.
+#[derive(Default)]
+pub struct RetainedMessages {
+    root: Node,
+    len: usize
+}
+
+#[derive(Default)]
+struct Node {
+    children: HashMap<String, Node>,
+    // The message retained for the topic that ends at this level
+    message: Option<OwnedMessage>
+}
+
+impl Node {
+    fn is_empty(&self) -> bool {
+        self.children.is_empty() && self.message.is_none()
+    }
+
+    // Clears the topic made of `levels`, pruning any levels left with nothing under them.
+    // Returns whether it had a message retained.
+    fn remove<'t, I: Iterator<Item = &'t str>>(&mut self, mut levels: I) -> bool {
+        match levels.next() {
+            None => self.message.take().is_some(),
+            Some(level) => match self.children.entry(level.to_string()) {
+                Entry::Vacant(_) => false,
+                Entry::Occupied(mut child) => {
+                    let removed = child.get_mut().remove(levels);
+                    if child.get().is_empty() {
+                        child.remove();
+                    }
+                    removed
+                }
+            }
+        }
+    }
+
+    // https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718107
+    // `levels` is what's left of the filter after this node. Wildcards don't match a '$'
+    // topic at its first level [MQTT-4.7.2-1].
+    fn collect<'s>(&'s self, levels: &[&str], first: bool, matched: &mut Vec<&'s OwnedMessage>) {
+        match levels.split_first() {
+            None => matched.extend(self.message.as_ref()),
+            Some((&"#", _)) => {
+                // "a/#" matches "a" as well as everything under it
+                matched.extend(self.message.as_ref());
+                for (level, child) in &self.children {
+                    if !(first && level.starts_with('$')) {
+                        child.collect_all(matched);
+                    }
+                }
+            },
+            Some((&"+", rest)) => {
+                for (level, child) in &self.children {
+                    if !(first && level.starts_with('$')) {
+                        child.collect(rest, false, matched);
+                    }
+                }
+            },
+            Some((level, rest)) => {
+                if let Some(child) = self.children.get(*level) {
+                    child.collect(rest, false, matched);
+                }
+            }
+        }
+    }
+
+    fn collect_all<'s>(&'s self, matched: &mut Vec<&'s OwnedMessage>) {
+        matched.extend(self.message.as_ref());
+        for child in self.children.values() {
+            child.collect_all(matched);
+        }
+    }
+}
+
+impl RetainedMessages {
+    pub fn new() -> Self {
+        RetainedMessages::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Keeps `msg` in place of whatever its topic had retained before [MQTT-3.3.1-5]. A PUBLISH
+    // with an empty payload only clears the topic [MQTT-3.3.1-10, MQTT-3.3.1-11]. Anything that
+    // isn't a PUBLISH is ignored.
+    pub fn retain(&mut self, msg: OwnedMessage) {
+        if let Message::Publish{ qos, topic, payload, properties, .. } = msg {
+            if payload.is_empty() {
+                if self.root.remove(topic.levels()) {
+                    self.len -= 1;
+                }
+                return
+            }
+            let node = topic.levels().fold(&mut self.root, |node, level| {
+                node.children.entry(level.to_string()).or_default()
+            });
+            let retained = Message::Publish {
+                dup: false,
+                qos,
+                retain: true,
+                topic,
+                packet_id: None,
+                payload,
+                properties
+            };
+            if node.message.replace(retained).is_none() {
+                self.len += 1;
+            }
+        }
+    }
+
+    // Every retained PUBLISH whose topic `filter` matches
+    pub fn matching<'s>(&'s self, filter: &TopicFilter) -> impl Iterator<Item = &'s OwnedMessage> + 's {
+        let levels: Vec<&str> = filter.levels().collect();
+        let mut matched = Vec::new();
+        self.root.collect(&levels, true, &mut matched);
+        matched.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqtt::*;
+    use std::borrow::Cow;
+
+    fn publish(topic: &'static str, payload: &'static [u8]) -> OwnedMessage {
+        Message::Publish {
+            dup: false,
+            qos: QualityOfService::AtLeastOnce,
+            retain: true,
+            topic: TopicName::new(topic).unwrap(),
+            packet_id: Some(1),
+            payload: Cow::Borrowed(payload),
+            properties: Vec::new()
+        }
+    }
+
+    // The payloads retained for whatever `filter` matches, sorted
+    fn matching(retained: &RetainedMessages, filter: &'static str) -> Vec<Vec<u8>> {
+        let mut payloads: Vec<Vec<u8>> = retained.matching(&TopicFilter::new(filter).unwrap()).map(|msg| {
+            match *msg {
+                Message::Publish{ ref payload, .. } => payload.to_vec(),
+                _ => panic!("only PUBLISHes are retained")
+            }
+        }).collect();
+        payloads.sort();
+        payloads
+    }
+
+    #[test]
+    fn a_new_message_replaces_the_old() {
+        let mut retained = RetainedMessages::new();
+        retained.retain(publish("a/b", b"old"));
+        retained.retain(publish("a/b", b"new"));
+        assert_eq!(retained.len(), 1);
+        assert_eq!(matching(&retained, "a/b"), vec![b"new".to_vec()]);
+    }
+
+    #[test]
+    fn an_empty_payload_clears_the_topic() {
+        let mut retained = RetainedMessages::new();
+        retained.retain(publish("a/b", b"kept"));
+        retained.retain(publish("a", b"kept"));
+        retained.retain(publish("a/b", b""));
+        assert_eq!(retained.len(), 1);
+        assert!(matching(&retained, "a/b").is_empty());
+        // Clearing a topic with nothing retained changes nothing
+        retained.retain(publish("x/y", b""));
+        assert_eq!(retained.len(), 1);
+        retained.retain(publish("a", b""));
+        assert!(retained.is_empty());
+        assert!(retained.root.is_empty());
+    }
+
+    #[test]
+    fn retained_messages_are_kept_as_publishes_to_be_sent_again() {
+        let mut retained = RetainedMessages::new();
+        retained.retain(publish("a", b"hello"));
+        let filter = TopicFilter::new("a").unwrap();
+        let first = retained.matching(&filter).next();
+        match first {
+            Some(&Message::Publish{ dup: false, retain: true, packet_id: None, .. }) => (),
+            _ => panic!("expected a retained PUBLISH without a packet id")
+        }
+    }
+
+    #[test]
+    fn filters_match_retained_topics_level_by_level() {
+        let mut retained = RetainedMessages::new();
+        for &topic in &["a", "a/b", "a/c", "a/b/c", "b/b", "/a", "a/"] {
+            retained.retain(publish(topic, topic.as_bytes()));
+        }
+        assert_eq!(matching(&retained, "a/b"), vec![b"a/b".to_vec()]);
+        assert_eq!(matching(&retained, "a/+"), vec![b"a/".to_vec(), b"a/b".to_vec(), b"a/c".to_vec()]);
+        assert_eq!(matching(&retained, "+/b"), vec![b"a/b".to_vec(), b"b/b".to_vec()]);
+        assert_eq!(matching(&retained, "+/+/c"), vec![b"a/b/c".to_vec()]);
+        assert_eq!(
+            matching(&retained, "a/#"),
+            vec![b"a".to_vec(), b"a/".to_vec(), b"a/b".to_vec(), b"a/b/c".to_vec(), b"a/c".to_vec()]
+        );
+        assert_eq!(matching(&retained, "+/a"), vec![b"/a".to_vec()]);
+        assert_eq!(matching(&retained, "#").len(), 7);
+        assert!(matching(&retained, "c/#").is_empty());
+    }
+
+    #[test]
+    fn wildcards_dont_match_system_topics_at_the_first_level() {
+        let mut retained = RetainedMessages::new();
+        retained.retain(publish("$SYS/uptime", b"1"));
+        retained.retain(publish("a/$b", b"2"));
+        assert!(matching(&retained, "#").iter().all(|payload| { payload == b"2" }));
+        assert!(matching(&retained, "+/uptime").is_empty());
+        assert_eq!(matching(&retained, "$SYS/#"), vec![b"1".to_vec()]);
+        assert_eq!(matching(&retained, "$SYS/+"), vec![b"1".to_vec()]);
+        assert_eq!(matching(&retained, "a/+"), vec![b"2".to_vec()]);
+    }
+}
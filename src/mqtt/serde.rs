@@ -1,11 +1,145 @@
-use std::io::Read;
-use std::io::Result;
-use std::io::Write;
+use std::borrow::Cow;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use mqtt::*;
 
 pub trait Serde: Sized {
     // Returns either an error, or the bytes written to `sink`
-    fn ser(&self, sink: &mut Write) -> Result<usize>;
+    fn ser(&self, sink: &mut dyn Write) -> Result<usize>;
 
     // Returns either an error, or a constructed object and the bytes consumed from `source`
-    fn de(source: &mut Read) -> Result<(Self, usize)>;
+    fn de(source: &mut dyn Read) -> Result<(Self, usize)>;
+}
+
+// Something a packet body can be decoded from. Decoding from a reader copies every string and
+// payload out of it, decoding from a slice borrows them from the slice instead.
+pub(crate) trait Source<'a> {
+    fn fill(&mut self, buf: &mut [u8]) -> Result<()>;
+
+    fn take_bytes(&mut self, len: usize) -> Result<Cow<'a, [u8]>>;
+}
+
+pub(crate) struct ReadSource<'r>(pub &'r mut dyn Read);
+
+impl<'r> Source<'static> for ReadSource<'r> {
+    fn fill(&mut self, buf: &mut [u8]) -> Result<()> {
+        self.0.read_exact(buf)
+    }
+
+    // `len` comes from the peer, so the buffer only grows as the bytes actually arrive
+    fn take_bytes(&mut self, len: usize) -> Result<Cow<'static, [u8]>> {
+        let mut buf = Vec::new();
+        (&mut *self.0).take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() < len {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "packet ended early"))
+        }
+        Ok(Cow::Owned(buf))
+    }
+}
+
+pub(crate) struct SliceSource<'a>(pub &'a [u8]);
+
+impl<'a> Source<'a> for SliceSource<'a> {
+    fn fill(&mut self, buf: &mut [u8]) -> Result<()> {
+        let bytes = self.take_bytes(buf.len())?;
+        buf.copy_from_slice(&bytes);
+        Ok(())
+    }
+
+    fn take_bytes(&mut self, len: usize) -> Result<Cow<'a, [u8]>> {
+        if len > self.0.len() {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "packet ended early"))
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(Cow::Borrowed(taken))
+    }
+}
+
+// Reads a big-endian two-byte integer, as used for packet ids, keep-alives and length prefixes
+pub(crate) fn read_u16(source: &mut dyn Source) -> Result<(u16, usize)> {
+    let mut buf = [0u8; 2];
+    source.fill(&mut buf)?;
+    Ok((((buf[0] as u16) << 8) | buf[1] as u16, 2))
+}
+
+pub(crate) fn read_u32(source: &mut dyn Source) -> Result<(u32, usize)> {
+    let mut buf = [0u8; 4];
+    source.fill(&mut buf)?;
+    Ok((buf.iter().fold(0u32, |acc, b| { (acc << 8) | *b as u32 }), 4))
+}
+
+pub(crate) fn read_u8(source: &mut dyn Source) -> Result<(u8, usize)> {
+    let mut buf = [0u8; 1];
+    source.fill(&mut buf)?;
+    Ok((buf[0], 1))
+}
+
+// Reads two-byte-length-prefixed binary data
+pub(crate) fn read_binary<'a>(source: &mut dyn Source<'a>) -> Result<(Cow<'a, [u8]>, usize)> {
+    let (len, read) = read_u16(source)?;
+    let bytes = source.take_bytes(len as usize)?;
+    Ok((bytes, read + len as usize))
+}
+
+// https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718016
+pub(crate) fn read_string<'a>(source: &mut dyn Source<'a>) -> Result<(Cow<'a, str>, usize)> {
+    let (bytes, read) = read_binary(source)?;
+    let string = match bytes {
+        Cow::Borrowed(bytes) => ::std::str::from_utf8(bytes).ok().map(Cow::Borrowed),
+        Cow::Owned(bytes) => String::from_utf8(bytes).ok().map(Cow::Owned)
+    };
+    match string {
+        Some(ref s) if s.contains('\u{0}') => Err(ProtocolError::NullCharacter.into()),
+        Some(s) => Ok((s, read)),
+        None => Err(ProtocolError::InvalidUtf8.into())
+    }
+}
+
+pub(crate) fn write_u8(sink: &mut dyn Write, value: u8) -> Result<usize> {
+    sink.write_all(&[value])?;
+    Ok(1)
+}
+
+pub(crate) fn write_u16(sink: &mut dyn Write, value: u16) -> Result<usize> {
+    sink.write_all(&[(value >> 8) as u8, value as u8])?;
+    Ok(2)
+}
+
+pub(crate) fn write_u32(sink: &mut dyn Write, value: u32) -> Result<usize> {
+    sink.write_all(&value.to_be_bytes())?;
+    Ok(4)
+}
+
+pub(crate) fn write_binary(sink: &mut dyn Write, bytes: &[u8]) -> Result<usize> {
+    if bytes.len() > u16::MAX as usize {
+        let msg = format!("{} bytes is too long for a length-prefixed field", bytes.len());
+        return Err(Error::new(ErrorKind::InvalidInput, msg))
+    }
+    let written = write_u16(sink, bytes.len() as u16)?;
+    sink.write_all(bytes)?;
+    Ok(written + bytes.len())
+}
+
+pub(crate) fn write_string(sink: &mut dyn Write, s: &str) -> Result<usize> {
+    if s.contains('\u{0}') {
+        return Err(ProtocolError::NullCharacter.into_encoding_error())
+    }
+    write_binary(sink, s.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reading_bytes_only_allocates_what_arrives() {
+        // Setting aside everything claimed here up front would abort the test
+        let mut bytes = &b"short"[..];
+        let err = ReadSource(&mut bytes).take_bytes(usize::MAX / 4).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+        let mut bytes = &b"exactly, and more"[..];
+        assert_eq!(&*ReadSource(&mut bytes).take_bytes(7).unwrap(), b"exactly");
+        assert_eq!(bytes, b", and more");
+    }
 }
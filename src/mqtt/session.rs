@@ -1,159 +1,971 @@
 use mqtt::*;
-use std::collections::{BTreeMap, BTreeSet, HashMap};
-use std::net::SocketAddr;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::io::{Error, ErrorKind, Result};
+use std::time::{Duration, Instant};
+use std::cmp;
 
-pub struct Session<'a> {
-    filters: BTreeMap<QualityOfService, BTreeSet<&'a str>>,
-    will: Option<Will<'a>>
+// A session expiry interval that means the session never expires
+const NEVER_EXPIRES: u32 = u32::MAX;
+
+// https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc385349231
+// What the broker keeps for a client. A persistent session (one that didn't ask for a clean
+// session, or for MQTT 5 one with a session expiry interval) outlives its connection, so
+// everything it hasn't finished with is still here when the client comes back.
+pub struct Session {
+    version: ProtocolVersion,
+    // How many seconds the session outlives its connection: 0 if it ends with it, or
+    // NEVER_EXPIRES
+    expiry_interval: u32,
+    // When the session ends, if the client has gone and it's due to
+    expires: Option<Instant>,
+    filters: BTreeMap<OwnedTopicFilter, SubscriptionOptions>,
+    username: Option<String>,
+    will: Option<OwnedWill>,
+    // The connection the client is currently using
+    connection: Option<(PeerAddr, Outgoing)>,
+    next_packet_id: PacketId,
+    // QoS 1 and 2 PUBLISHes, and the PUBRELs that follow QoS 2 ones, that have been sent to the
+    // client but not acknowledged, in the order they were sent
+    unacknowledged: VecDeque<InFlight>,
+    // How many messages can be unacknowledged at once
+    max_inflight: usize,
+    // PUBLISHes that arrived while the client was away, or QoS 1 and 2 ones that arrived while
+    // it had too many messages unacknowledged, without packet ids yet
+    queued: VecDeque<OwnedMessage>,
+    // How many messages can be queued at once
+    max_queued: usize,
+    // The ids of QoS 2 PUBLISHes from the client that are waiting for their PUBREL
+    awaiting_release: BTreeSet<PacketId>
+}
+
+// A message waiting to be acknowledged, and when it was last sent
+struct InFlight {
+    msg: OwnedMessage,
+    sent: Instant
 }
 
-impl<'a> Session<'a> {
-    fn new(will: Option<Will<'a>>) -> Self {
-        let filters = BTreeMap::new();
-        Session{ filters, will }
+impl Session {
+    fn new(version: ProtocolVersion, expiry_interval: u32) -> Self {
+        Session {
+            version,
+            expiry_interval,
+            expires: None,
+            filters: BTreeMap::new(),
+            username: None,
+            will: None,
+            connection: None,
+            next_packet_id: 1,
+            unacknowledged: VecDeque::new(),
+            max_inflight: 1,
+            queued: VecDeque::new(),
+            max_queued: 0,
+            awaiting_release: BTreeSet::new()
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    fn identity<'s>(&'s self, client_id: &'s str) -> ClientIdentity<'s> {
+        ClientIdentity{ client_id, username: self.username.as_deref() }
+    }
+
+    // Queues `msg` to be written to the client. If the connection has already gone, so has
+    // the message.
+    fn send(&self, msg: OwnedMessage) {
+        if let Some((_, ref outgoing)) = self.connection {
+            let _ = outgoing.unbounded_send(msg);
+        }
+    }
+
+    // https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc385349761
+    // Packet ids run from 1 to 65535 and then wrap around, skipping 0 and any id that's still
+    // waiting to be acknowledged [MQTT-2.3.1-2]. There's none to give out if all of them are.
+    fn next_packet_id(&mut self) -> Option<PacketId> {
+        for _ in 0..u16::MAX {
+            let packet_id = self.next_packet_id;
+            self.next_packet_id = packet_id.checked_add(1).unwrap_or(1);
+            if self.unacknowledged(packet_id).is_none() {
+                return Some(packet_id)
+            }
+        }
+        None
+    }
+
+    fn subscribe(&mut self, topic_filter: OwnedTopicFilter, options: SubscriptionOptions) -> Option<SubscriptionOptions> {
+        self.filters.insert(topic_filter, options)
+    }
+
+    fn unsubscribe(&mut self, topic_filter: &OwnedTopicFilter) -> bool {
+        self.filters.remove(topic_filter).is_some()
+    }
+
+    // Sends a PUBLISH to the client. QoS 1 and 2 messages wait their turn behind any that are
+    // already queued, and are kept until they're acknowledged; QoS 0 ones go straight out if
+    // the client is there, and are queued for it if it isn't [MQTT-3.1.2-5]. Returns false if
+    // the queue was full and the message dropped.
+    fn deliver(&mut self, msg: OwnedMessage) -> bool {
+        if self.is_connected() && is_qos_0(&msg) {
+            self.send(msg);
+            return true
+        }
+        let queued = self.enqueue(msg);
+        self.send_queued();
+        queued
+    }
+
+    // A full queue makes room by dropping its oldest QoS 0 message. Without one, `msg` is
+    // dropped instead, rather than anything the client is owed at a higher QoS.
+    fn enqueue(&mut self, msg: OwnedMessage) -> bool {
+        if self.queued.len() >= self.max_queued {
+            match self.queued.iter().position(is_qos_0) {
+                Some(index) => { self.queued.remove(index); },
+                None => return false
+            }
+        }
+        self.queued.push_back(msg);
+        true
+    }
+
+    // Sends queued messages for as long as the client is connected, giving each QoS 1 and 2
+    // one a packet id while the client has room for more unacknowledged messages
+    fn send_queued(&mut self) {
+        while self.is_connected() && !self.queued.is_empty() {
+            if is_qos_0(&self.queued[0]) {
+                let msg = self.queued.pop_front().unwrap();
+                self.send(msg);
+                continue
+            }
+            if self.unacknowledged.len() >= self.max_inflight {
+                return
+            }
+            let next_packet_id = match self.next_packet_id() {
+                Some(next_packet_id) => next_packet_id,
+                None => return
+            };
+            let mut msg = self.queued.pop_front().unwrap();
+            if let Message::Publish{ ref mut packet_id, .. } = msg {
+                *packet_id = Some(next_packet_id);
+            }
+            self.unacknowledged.push_back(InFlight{ msg: msg.clone(), sent: Instant::now() });
+            self.send(msg);
+        }
+    }
+
+    // Sends an unacknowledged message again. A PUBLISH is marked as a duplicate, so that the
+    // client knows it may have seen it before [MQTT-3.3.1-1].
+    fn resend(&mut self, index: usize) {
+        let in_flight = &mut self.unacknowledged[index];
+        if let Message::Publish{ ref mut dup, .. } = in_flight.msg {
+            *dup = true;
+        }
+        in_flight.sent = Instant::now();
+        let msg = in_flight.msg.clone();
+        self.send(msg);
+    }
+
+    // https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc385349361
+    // When a client comes back to its session, whatever it hadn't acknowledged is sent again,
+    // in the same order [MQTT-4.4.0-1], before what was queued while it was away
+    fn resume(&mut self) {
+        for index in 0..self.unacknowledged.len() {
+            self.resend(index);
+        }
+        self.send_queued();
+    }
+
+    // Sends again whatever has waited `interval` or longer for an acknowledgement
+    fn retry(&mut self, interval: Duration) {
+        if !self.is_connected() || self.version == ProtocolVersion::V5 {
+            return
+        }
+        for index in 0..self.unacknowledged.len() {
+            if self.unacknowledged[index].sent.elapsed() >= interval {
+                self.resend(index);
+            }
+        }
+    }
+
+    // Finds the unacknowledged PUBLISH or PUBREL with `packet_id`
+    fn unacknowledged(&self, packet_id: PacketId) -> Option<usize> {
+        self.unacknowledged.iter().position(|in_flight| {
+            match in_flight.msg {
+                Message::Publish{ packet_id: Some(id), .. } |
+                Message::Pubrel{ packet_id: id, .. } => id == packet_id,
+                _ => false
+            }
+        })
+    }
+
+    // https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc385349373
+    // A PUBACK finishes delivering a QoS 1 PUBLISH, and a PUBCOMP a QoS 2 one that's been
+    // released. Returns whether `ack` finished anything; an acknowledgement for a packet id
+    // that isn't waiting for that kind of acknowledgement changes nothing.
+    fn acknowledge(&mut self, ack: ControlPacketType, packet_id: PacketId) -> bool {
+        let index = match self.unacknowledged(packet_id) {
+            Some(index) => index,
+            None => return false
+        };
+        let finished = matches!(
+            (ack, &self.unacknowledged[index].msg),
+            (ControlPacketType::Puback, &Message::Publish{ qos: QualityOfService::AtLeastOnce, .. }) |
+            (ControlPacketType::Pubcomp, &Message::Pubrel{ .. })
+        );
+        if finished {
+            self.unacknowledged.remove(index);
+            self.send_queued();
+        }
+        finished
+    }
+
+    // A PUBREC means the client has a QoS 2 message, and from now on it's the PUBREL that needs
+    // acknowledging [MQTT-4.3.3-1]. A PUBREC for a message that's already been released is
+    // answered with the same PUBREL again. Returns the PUBREL to send, if any.
+    fn release(&mut self, packet_id: PacketId) -> Option<OwnedMessage> {
+        let pubrel = Message::Pubrel { packet_id, reason_code: ReasonCode::Success, properties: Vec::new() };
+        let index = self.unacknowledged(packet_id)?;
+        match self.unacknowledged[index].msg {
+            Message::Publish{ qos: QualityOfService::ExactlyOnce, .. } | Message::Pubrel{ .. } => {
+                self.unacknowledged[index] = InFlight{ msg: pubrel.clone(), sent: Instant::now() };
+                Some(pubrel)
+            },
+            _ => None
+        }
     }
 
-    fn subscribe(&mut self, qos: QualityOfService, topic_filter: &'a str) -> bool {
-        self.filters
-            .entry(qos).or_insert(BTreeSet::new())
-            .insert(topic_filter)
+    // An MQTT 5 PUBREC with a failure reason code ends a QoS 2 delivery there and then
+    fn abandon(&mut self, packet_id: PacketId) {
+        if let Some(index) = self.unacknowledged(packet_id) {
+            if let Message::Publish{ qos: QualityOfService::ExactlyOnce, .. } = self.unacknowledged[index].msg {
+                self.unacknowledged.remove(index);
+                self.send_queued();
+            }
+        }
     }
 }
 
-pub struct Sessions<'a> {
-    sessions: HashMap<SocketAddr, Session<'a>>
+fn is_qos_0(msg: &OwnedMessage) -> bool {
+    matches!(msg, Message::Publish{ qos: QualityOfService::AtMostOnce, .. })
+}
+
+// Every session by client identifier, along with an index of all of their subscriptions for
+// routing publishes, and the messages retained for new subscribers. Messages arrive from
+// connections, which are mapped to the client that opened them.
+pub struct Sessions {
+    config: Config,
+    authenticator: Box<dyn Authenticator>,
+    authorizer: Box<dyn Authorizer>,
+    sessions: HashMap<String, Session>,
+    connections: HashMap<PeerAddr, String>,
+    subscriptions: Subscriptions<String>,
+    retained: RetainedMessages,
+    next_assigned_id: u64
 }
 
-impl<'a> Sessions<'a> {
+impl Default for Sessions {
+    fn default() -> Self {
+        Sessions::new()
+    }
+}
+
+impl Sessions {
     pub fn new() -> Self {
-        Sessions{ sessions: HashMap::new() }
+        Sessions::with_config(Config::default())
+    }
+
+    pub fn with_config(config: Config) -> Self {
+        Sessions {
+            config,
+            authenticator: Box::new(AllowAnonymous),
+            authorizer: Box::new(AllowAll),
+            sessions: HashMap::new(),
+            connections: HashMap::new(),
+            subscriptions: Subscriptions::new(),
+            retained: RetainedMessages::new(),
+            next_assigned_id: 0
+        }
     }
 
-    pub fn handle_message<'msg: 'a>(&mut self, addr: &SocketAddr, msg: Message<'msg>) -> Result<()> {
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    // Decides who can connect. Anyone can, until this is set.
+    pub fn set_authenticator(&mut self, authenticator: Box<dyn Authenticator>) {
+        self.authenticator = authenticator;
+    }
+
+    // Decides who can publish and subscribe to what. Everyone can do anything, until this is
+    // set.
+    pub fn set_authorizer(&mut self, authorizer: Box<dyn Authorizer>) {
+        self.authorizer = authorizer;
+    }
+
+    // Every client a message `publisher` sent to `topic` goes to, with the options of their
+    // subscriptions to it combined
+    pub fn subscribers(&self, topic: &TopicName, publisher: Option<&str>) -> HashMap<String, SubscriptionOptions> {
+        self.subscriptions.matches(topic, publisher)
+    }
+
+    // Handles the CONNECT that opens a connection, answering it with a CONNACK on `outgoing`.
+    // The connection's later messages go to `handle_message`. Returns how long the connection
+    // can go without sending anything before it's closed, if there's a limit.
+    pub fn handle_connect(&mut self,
+                          addr: &PeerAddr,
+                          peer: &PeerCredentials,
+                          outgoing: Outgoing,
+                          msg: OwnedMessage
+    ) -> Result<Option<Duration>> {
         match msg {
             Message::Connect{
+                protocol_version,
                 client_id,
                 username,
                 password,
                 will,
                 clean_session,
-                keep_alive
+                keep_alive,
+                properties
             } =>
                 self.connect(
                     addr,
+                    peer,
+                    outgoing,
+                    protocol_version,
                     client_id,
                     username,
                     password,
                     will,
                     clean_session,
-                    keep_alive
+                    keep_alive,
+                    properties
                 ),
-            Message::Publish{ dup, qos, retain, topic, packet_id, payload } =>
-                self.publish(addr, dup, qos, retain, topic, packet_id, payload),
-            Message::Puback(packet_id) =>
+            _ => Sessions::raise_not_connected()
+        }
+    }
+
+    pub fn handle_message(&mut self, addr: &PeerAddr, msg: OwnedMessage) -> Result<()> {
+        match msg {
+            Message::Connect{ .. } =>
+                Sessions::raise_already_connected(),
+            Message::Publish{ dup, qos, retain, topic, packet_id, payload, properties } =>
+                self.publish(addr, dup, qos, retain, topic, packet_id, payload, properties),
+            Message::Puback{ packet_id, .. } =>
                 self.puback(addr, packet_id),
-            Message::Pubrec(packet_id) =>
-                self.pubrec(addr, packet_id),
-            Message::Pubrel(packet_id) =>
+            Message::Pubrec{ packet_id, reason_code, .. } =>
+                self.pubrec(addr, packet_id, reason_code),
+            Message::Pubrel{ packet_id, .. } =>
                 self.pubrel(addr, packet_id),
-            Message::Pubcomp(packet_id) =>
+            Message::Pubcomp{ packet_id, .. } =>
                 self.pubcomp(addr, packet_id),
-            Message::Subscribe{ packet_id, topic_filters } =>
+            Message::Subscribe{ packet_id, topic_filters, .. } =>
                 self.subscribe(addr, packet_id, topic_filters),
-            Message::Unsubscribe{ packet_id, topic_filters } =>
+            Message::Unsubscribe{ packet_id, topic_filters, .. } =>
                 self.unsubscribe(addr, packet_id, topic_filters),
             Message::Pingreq =>
                 self.pingreq(addr),
-            Message::Disconnect =>
-                self.disconnect(addr),
+            Message::Disconnect{ reason_code, properties } =>
+                self.disconnect(addr, reason_code, properties),
+            Message::Auth{ .. } =>
+                Sessions::raise_unsupported_auth(),
             _ => Sessions::raise_wrong_direction()
         }
     }
 
+    // Sends again whatever has been waiting too long for an acknowledgement, if the broker is
+    // configured to do that
+    pub fn retry_unacknowledged(&mut self) {
+        if let Some(interval) = self.config.retry_interval {
+            for session in self.sessions.values_mut() {
+                session.retry(interval);
+            }
+        }
+    }
+
+    // The connection to `addr` has closed, whether or not it sent a DISCONNECT first; if it
+    // didn't, its will is published. A connection whose session was taken over by another has
+    // nothing left to clean up.
+    pub fn handle_disconnected(&mut self, addr: &PeerAddr) {
+        let client_id = match self.connections.remove(addr) {
+            Some(client_id) => client_id,
+            None => return
+        };
+        let (expiry_interval, will) = match self.sessions.get_mut(&client_id) {
+            Some(session) => {
+                session.connection = None;
+                (session.expiry_interval, session.will.take())
+            },
+            None => return
+        };
+        self.publish_will(&client_id, will);
+        match expiry_interval {
+            0 => { self.remove_session(&client_id); },
+            NEVER_EXPIRES => (),
+            seconds => if let Some(session) = self.sessions.get_mut(&client_id) {
+                session.expires = Some(Instant::now() + Duration::from_secs(u64::from(seconds)));
+            }
+        }
+    }
+
+    // https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901048
+    // Ends every session whose client has been gone longer than its session expiry interval
+    pub fn expire_sessions(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<String> = self.sessions
+            .iter()
+            .filter(|&(_, session)| { session.expires.is_some_and(|expires| { expires <= now }) })
+            .map(|(client_id, _)| { client_id.clone() })
+            .collect();
+        for client_id in expired {
+            println!("session expired\t{}", client_id);
+            self.remove_session(&client_id);
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn connect(&mut self,
-                   addr: &SocketAddr,
-                   client_id: &'a str,
-                   username: &'a str,
-                   password: &'a str,
-                   will: Option<Will<'a>>,
+                   addr: &PeerAddr,
+                   peer: &PeerCredentials,
+                   outgoing: Outgoing,
+                   protocol_version: ProtocolVersion,
+                   client_id: Cow<'static, str>,
+                   username: Option<Cow<'static, str>>,
+                   password: Option<Cow<'static, [u8]>>,
+                   will: Option<OwnedWill>,
                    clean_session: bool,
-                   keep_alive: u16
-    ) -> Result<()> {
-        println!("connect\t{}", addr.to_string());
-        if self.sessions.contains_key(addr) {
-            self.sessions.remove(addr);
-            Sessions::raise_already_connected()
+                   keep_alive: u16,
+                   properties: Properties<'static>
+    ) -> Result<Option<Duration>> {
+        println!("connect\t{}\t{}", addr, client_id);
+        // A client its listener has identified is known by that, whatever its CONNECT says
+        let client_id = peer.client_id.clone().map_or(client_id, Cow::Owned);
+        let username = peer.username.clone().map(Cow::Owned).or(username);
+        // https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc385349242
+        // A client that leaves its identifier empty is given one, as long as it isn't asking
+        // for a session it could never resume [MQTT-3.1.3-6, MQTT-3.1.3-8]
+        let assigned = client_id.is_empty() && protocol_version != ProtocolVersion::V31;
+        if !protocol_version.accepts_client_id(&client_id) || (assigned && !clean_session) {
+            Sessions::refuse(&outgoing, ConnackReturnCode::IdentifierRejected);
+            return Sessions::raise_identifier_rejected(&client_id)
+        }
+        if self.connections.contains_key(addr) {
+            return Sessions::raise_already_connected()
+        }
+        let credentials = Credentials {
+            client_id: &client_id,
+            username: username.as_deref(),
+            password: password.as_deref(),
+            addr,
+            peer
+        };
+        if let Err(return_code) = self.authenticator.authenticate(&credentials) {
+            Sessions::refuse(&outgoing, return_code);
+            return Sessions::raise_refused(&client_id, return_code)
+        }
+        let client_id = if assigned {
+            self.assign_client_id()
+        } else {
+            client_id.into_owned()
+        };
+        let username = username.map(Cow::into_owned);
+
+        // A client can't leave behind a will it wouldn't be allowed to publish itself
+        if let Some(ref will) = will {
+            let identity = ClientIdentity{ client_id: &client_id, username: username.as_deref() };
+            if !self.authorizer.authorize_publish(&identity, &will.topic) {
+                Sessions::refuse(&outgoing, ConnackReturnCode::NotAuthorized);
+                return Sessions::raise_refused(&client_id, ConnackReturnCode::NotAuthorized)
+            }
+        }
+
+        // https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc385349241
+        // A client connecting again takes its session over from whichever connection it was
+        // using, which is closed [MQTT-3.1.4-2]. A clean session starts again from nothing.
+        let mut previous_will = None;
+        if let Some(previous) = self.sessions.get_mut(&client_id) {
+            if let Some((previous_addr, previous_outgoing)) = previous.connection.take() {
+                println!("takeover\t{}\t{}\t{}", client_id, previous_addr, addr);
+                if previous.version == ProtocolVersion::V5 {
+                    let _ = previous_outgoing.unbounded_send(Message::Disconnect {
+                        reason_code: ReasonCode::SessionTakenOver,
+                        properties: Vec::new()
+                    });
+                }
+                self.connections.remove(&previous_addr);
+                previous_will = previous.will.take();
+            }
+        }
+        self.publish_will(&client_id, previous_will);
+        let expired = self.sessions.get(&client_id).and_then(|session| { session.expires }).is_some_and(|expires| {
+            expires <= Instant::now()
+        });
+        if clean_session || expired {
+            self.remove_session(&client_id);
+        }
+        let session_present = self.sessions.contains_key(&client_id);
+
+        // https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901048
+        // MQTT 5 keeps starting clean apart from how long the session lasts, which is no
+        // longer than the connection unless the client asks [MQTT-3.1.2-23]. Before that, a
+        // clean session was one that did both.
+        let expiry_interval = if protocol_version == ProtocolVersion::V5 {
+            properties.iter().filter_map(|property| {
+                match *property {
+                    Property::SessionExpiryInterval(expiry_interval) => Some(expiry_interval),
+                    _ => None
+                }
+            }).next().unwrap_or(0)
+        } else if clean_session {
+            0
         } else {
-            self.sessions.insert(addr.clone(), Session::new(will.clone()));
-            Ok(())
+            NEVER_EXPIRES
+        };
+
+        // https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901049
+        // MQTT 5 clients say how many messages they're willing to have unacknowledged
+        let receive_maximum = properties.iter().filter_map(|property| {
+            match *property {
+                Property::ReceiveMaximum(receive_maximum) => Some(receive_maximum as usize),
+                _ => None
+            }
+        }).next();
+        let max_inflight = receive_maximum.map_or(self.config.max_inflight, |receive_maximum| {
+            cmp::min(receive_maximum, self.config.max_inflight)
+        });
+
+        let session = self.sessions
+            .entry(client_id.clone())
+            .or_insert_with(|| Session::new(protocol_version, expiry_interval));
+        session.version = protocol_version;
+        session.expiry_interval = expiry_interval;
+        session.expires = None;
+        session.max_inflight = cmp::max(max_inflight, 1);
+        session.max_queued = self.config.max_queued;
+        session.username = username;
+        session.will = will;
+        session.connection = Some((*addr, outgoing));
+
+        // https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc385349238
+        // The broker can cap the keep alive, including a client's 0 that would have none. Only
+        // MQTT 5 clients can be told they've been given a different one.
+        let server_keep_alive = match self.config.max_keep_alive {
+            Some(max_keep_alive) if keep_alive == 0 || keep_alive > max_keep_alive => max_keep_alive,
+            _ => keep_alive
+        };
+        let mut properties = Vec::new();
+        if protocol_version == ProtocolVersion::V5 {
+            if assigned {
+                properties.push(Property::AssignedClientIdentifier(Cow::Owned(client_id.clone())));
+            }
+            if server_keep_alive != keep_alive {
+                properties.push(Property::ServerKeepAlive(server_keep_alive));
+            }
+            if self.config.max_packet_size < MAX_PACKET_SIZE {
+                properties.push(Property::MaximumPacketSize(self.config.max_packet_size as u32));
+            }
+            if self.config.max_qos != QualityOfService::ExactlyOnce {
+                let (high, low) = self.config.max_qos.bits();
+                properties.push(Property::MaximumQos(((high as u8) << 1) | low as u8));
+            }
+        }
+        session.send(Message::Connack {
+            session_present,
+            return_code: ConnackReturnCode::Accepted,
+            properties
+        });
+        session.resume();
+        self.connections.insert(*addr, client_id);
+
+        // A client that has kept quiet for one and a half times its keep alive is gone
+        // [MQTT-3.1.2-24]
+        match server_keep_alive {
+            0 => Ok(None),
+            seconds => Ok(Some(Duration::from_millis(u64::from(seconds) * 1500)))
         }
     }
 
-    fn publish(&self,
-               addr: &SocketAddr,
-               dup: bool,
+    // Assigned identifiers are longer than the 23 characters every server has to accept, so
+    // few clients will pick one themselves, and any that's in use is skipped
+    fn assign_client_id(&mut self) -> String {
+        loop {
+            self.next_assigned_id += 1;
+            let client_id = format!("auto-{:020}", self.next_assigned_id);
+            if !self.sessions.contains_key(&client_id) {
+                return client_id
+            }
+        }
+    }
+
+    // Answers a CONNECT with a refusal; the connection closes once `outgoing` is dropped
+    fn refuse(outgoing: &Outgoing, return_code: ConnackReturnCode) {
+        let _ = outgoing.unbounded_send(Message::Connack {
+            session_present: false,
+            return_code,
+            properties: Vec::new()
+        });
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn publish(&mut self,
+               addr: &PeerAddr,
+               _dup: bool,
                qos: QualityOfService,
                retain: bool,
-               topic: &'a str,
+               topic: OwnedTopicName,
                packet_id: Option<PacketId>,
-               payload: &'a str) -> Result<()> {
-        println!("publish\t{}", addr.to_string());
+               payload: Cow<'static, [u8]>,
+               properties: Properties<'static>) -> Result<()> {
+        println!("publish\t{}\t{}", addr, topic);
+        let client_id = self.client_id(addr)?;
+        let (version, allowed) = match self.sessions.get(&client_id) {
+            Some(session) => (session.version, self.authorizer.authorize_publish(&session.identity(&client_id), &topic)),
+            None => return Sessions::raise_not_connected()
+        };
+        // https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc385349323
+        // MQTT 3.1.1 has no way to tell a client its publish was refused, so it's acknowledged as
+        // usual and dropped. MQTT 5 clients are told.
+        if !allowed {
+            println!("publish refused\t{}\t{}", addr, topic);
+        }
+        let reason_code = if allowed || version != ProtocolVersion::V5 {
+            ReasonCode::Success
+        } else {
+            ReasonCode::NotAuthorized
+        };
+        match (qos, packet_id) {
+            (QualityOfService::AtLeastOnce, Some(packet_id)) =>
+                self.reply(addr, Message::Puback { packet_id, reason_code, properties: Vec::new() })?,
+            (QualityOfService::ExactlyOnce, Some(packet_id)) if reason_code.is_failure() =>
+                return self.reply(addr, Message::Pubrec { packet_id, reason_code, properties: Vec::new() }),
+            // https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc385349374
+            // A QoS 2 PUBLISH is only passed on the first time it arrives. Until its PUBREL
+            // comes, the same packet id means the client is sending it again [MQTT-4.3.3-2].
+            (QualityOfService::ExactlyOnce, Some(packet_id)) => {
+                let first_time = self.session_mut(addr)?.awaiting_release.insert(packet_id);
+                self.reply(addr, Message::Pubrec { packet_id, reason_code: ReasonCode::Success, properties: Vec::new() })?;
+                if !first_time {
+                    println!("duplicate\t{}\t{}", addr, packet_id);
+                    return Ok(())
+                }
+            },
+            _ => ()
+        }
+        if allowed {
+            self.route(&client_id, qos, retain, topic, payload, properties);
+        }
         Ok(())
     }
 
-    fn puback(&self, addr: &SocketAddr, packet_id: PacketId) -> Result<()> {
-        println!("puback\t{}", addr.to_string());
+    // Passes a message from `publisher` on to everyone subscribed to its topic, and keeps it for
+    // later subscribers if it's retained
+    fn route(&mut self,
+             publisher: &str,
+             qos: QualityOfService,
+             retain: bool,
+             topic: OwnedTopicName,
+             payload: Cow<'static, [u8]>,
+             properties: Properties<'static>) {
+        if retain {
+            self.retained.retain(Message::Publish {
+                dup: false,
+                qos,
+                retain,
+                topic: topic.clone(),
+                packet_id: None,
+                payload: payload.clone(),
+                properties: Sessions::forwarded_properties(&properties)
+            });
+        }
+        // Subscribers that were already there get the message like any other [MQTT-3.3.1-9]
+        for (subscriber, options) in self.subscribers(&topic, Some(publisher)) {
+            if let Some(session) = self.sessions.get_mut(&subscriber) {
+                if !self.authorizer.authorize_delivery(&session.identity(&subscriber), &topic) {
+                    continue
+                }
+                // Deliveries go out at the lower of the publish's QoS and the subscription's,
+                // and only keep the retain flag for MQTT 5 subscriptions asking for it
+                // [MQTT-3.3.1-12, MQTT-3.3.1-13]
+                let delivered = session.deliver(Message::Publish {
+                    dup: false,
+                    qos: cmp::min(qos, options.qos),
+                    retain: retain && options.retain_as_published,
+                    topic: topic.clone(),
+                    packet_id: None,
+                    payload: payload.clone(),
+                    properties: Sessions::forwarded_properties(&properties)
+                });
+                if !delivered {
+                    println!("queue full\t{}", subscriber);
+                }
+            }
+        }
+    }
+
+    // https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc385349232
+    // A client's will is published when its connection ends any way other than a DISCONNECT
+    // [MQTT-3.1.2-8], exactly as if the client had sent it
+    fn publish_will(&mut self, client_id: &str, will: Option<OwnedWill>) {
+        if let Some(will) = will {
+            println!("will\t{}", will.topic);
+            self.route(client_id, will.qos, will.retain, will.topic, will.message, will.properties);
+        }
+    }
+
+    // https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901117
+    // Topic aliases only mean something on the connection they were sent on, and subscription
+    // identifiers are the server's to set, so neither is passed on to subscribers. A will's
+    // delay interval is only for the server.
+    fn forwarded_properties(properties: &[Property<'static>]) -> Properties<'static> {
+        properties
+            .iter()
+            .filter(|property| {
+                !matches!(
+                    property,
+                    Property::TopicAlias(_) | Property::SubscriptionIdentifier(_) | Property::WillDelayInterval(_)
+                )
+            })
+            .cloned()
+            .collect()
+    }
+
+    // The identifier of the client that opened the connection to `addr`
+    fn client_id(&self, addr: &PeerAddr) -> Result<String> {
+        match self.connections.get(addr) {
+            Some(client_id) => Ok(client_id.clone()),
+            None => Err(Sessions::not_connected())
+        }
+    }
+
+    // The session of the client that opened the connection to `addr`
+    fn session_mut(&mut self, addr: &PeerAddr) -> Result<&mut Session> {
+        match self.connections.get(addr) {
+            Some(client_id) => self.sessions.get_mut(client_id).ok_or_else(Sessions::not_connected),
+            None => Err(Sessions::not_connected())
+        }
+    }
+
+    // Sends `msg` back to the client at `addr`
+    fn reply(&self, addr: &PeerAddr, msg: OwnedMessage) -> Result<()> {
+        match self.connections.get(addr).and_then(|client_id| self.sessions.get(client_id)) {
+            Some(session) => {
+                session.send(msg);
+                Ok(())
+            },
+            None => Sessions::raise_not_connected()
+        }
+    }
+
+    fn puback(&mut self, addr: &PeerAddr, packet_id: PacketId) -> Result<()> {
+        println!("puback\t{}", addr);
+        if !self.session_mut(addr)?.acknowledge(ControlPacketType::Puback, packet_id) {
+            println!("unexpected puback\t{}\t{}", addr, packet_id);
+        }
         Ok(())
     }
 
-    fn pubrec(&self, addr: &SocketAddr, packet_id: PacketId) -> Result<()> {
-        println!("pubrec\t{}", addr.to_string());
+    fn pubrec(&mut self, addr: &PeerAddr, packet_id: PacketId, reason_code: ReasonCode) -> Result<()> {
+        println!("pubrec\t{}", addr);
+        let session = self.session_mut(addr)?;
+        if reason_code.is_failure() {
+            session.abandon(packet_id);
+            return Ok(())
+        }
+        // A PUBREC for a message we don't have still gets a PUBREL, so that the client can
+        // finish with the packet id
+        let pubrel = session.release(packet_id).unwrap_or(Message::Pubrel {
+            packet_id,
+            reason_code: ReasonCode::PacketIdentifierNotFound,
+            properties: Vec::new()
+        });
+        session.send(pubrel);
         Ok(())
     }
-    
-    fn pubrel(&self, addr: &SocketAddr, packet_id: PacketId) -> Result<()> {
-        println!("pubrel\t{}", addr.to_string());
+
+    // https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc385349374
+    // Once a QoS 2 PUBLISH is released, its packet id can carry a new message [MQTT-4.3.3-2]
+    fn pubrel(&mut self, addr: &PeerAddr, packet_id: PacketId) -> Result<()> {
+        println!("pubrel\t{}", addr);
+        let session = self.session_mut(addr)?;
+        let reason_code = if session.awaiting_release.remove(&packet_id) {
+            ReasonCode::Success
+        } else {
+            ReasonCode::PacketIdentifierNotFound
+        };
+        session.send(Message::Pubcomp { packet_id, reason_code, properties: Vec::new() });
         Ok(())
     }
 
-    fn pubcomp(&self, addr: &SocketAddr, packet_id: PacketId) -> Result<()> {
-        println!("pubcomp\t{}", addr.to_string());
+    fn pubcomp(&mut self, addr: &PeerAddr, packet_id: PacketId) -> Result<()> {
+        println!("pubcomp\t{}", addr);
+        if !self.session_mut(addr)?.acknowledge(ControlPacketType::Pubcomp, packet_id) {
+            println!("unexpected pubcomp\t{}\t{}", addr, packet_id);
+        }
         Ok(())
     }
 
     fn subscribe(
         &mut self,
-        addr: &SocketAddr,
+        addr: &PeerAddr,
         packet_id: PacketId,
-        topic_filters: &'a[(&'a str, QualityOfService)]
+        topic_filters: Vec<(OwnedSubscribeFilter, SubscriptionOptions)>
     ) -> Result<()> {
-        println!("subscribe\t{}", addr.to_string());
+        println!("subscribe\t{}", addr);
+        let client_id = self.client_id(addr)?;
+        let session = match self.sessions.get_mut(&client_id) {
+            Some(session) => session,
+            None => return Sessions::raise_not_connected()
+        };
+        let username = session.username.clone();
+        let identity = ClientIdentity{ client_id: &client_id, username: username.as_deref() };
+        let mut return_codes = Vec::with_capacity(topic_filters.len());
+        let mut retained_for = Vec::new();
+        for (topic_filter, mut options) in topic_filters {
+            let return_code = Sessions::grant(&self.config, &*self.authorizer, &identity, &topic_filter, &options);
+            return_codes.push(return_code);
+            let topic_filter = match (topic_filter, return_code.qos()) {
+                (SubscribeFilter::Valid(topic_filter), Some(granted_qos)) => {
+                    options.qos = granted_qos;
+                    topic_filter
+                },
+                (topic_filter, _) => {
+                    println!("subscription refused\t{}\t{}\t{:?}", addr, topic_filter, return_code);
+                    continue
+                }
+            };
+            let previous = self.subscriptions.subscribe(client_id.clone(), &topic_filter, options);
+            // https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901169
+            let send_retained = match options.retain_handling {
+                RetainHandling::SendOnSubscribe => true,
+                RetainHandling::SendOnNewSubscribe => previous.is_none(),
+                RetainHandling::DoNotSend => false
+            };
+            if send_retained {
+                retained_for.push((topic_filter.clone(), options.qos));
+            }
+            session.subscribe(topic_filter, options);
+        }
+        session.send(Message::Suback { packet_id, return_codes, properties: Vec::new() });
+
+        // https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc385349265
+        // New subscriptions get whatever's retained for their topics, flagged as retained
+        // [MQTT-3.3.1-6, MQTT-3.3.1-8]
+        for (topic_filter, granted_qos) in retained_for {
+            for retained in self.retained.matching(&topic_filter) {
+                if let Message::Publish{ ref topic, .. } = *retained {
+                    if !self.authorizer.authorize_delivery(&identity, topic) {
+                        continue
+                    }
+                }
+                let mut msg = retained.clone();
+                if let Message::Publish{ ref mut qos, .. } = msg {
+                    *qos = cmp::min(*qos, granted_qos);
+                }
+                if !session.deliver(msg) {
+                    println!("queue full\t{}", client_id);
+                }
+            }
+        }
         Ok(())
     }
 
+    // https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc385349285
+    // Decides what a subscription to `topic_filter` gets: at most the broker's maximum QoS
+    // [MQTT-3.9.3-2], or a failure for a filter that isn't valid, or that the broker doesn't
+    // support or the client isn't allowed. Refusals are a plain 0x80 failure to MQTT 3.1.1
+    // clients.
+    fn grant(
+        config: &Config,
+        authorizer: &dyn Authorizer,
+        identity: &ClientIdentity,
+        topic_filter: &SubscribeFilter,
+        options: &SubscriptionOptions
+    ) -> SubackReturn {
+        let topic_filter = match topic_filter {
+            SubscribeFilter::Valid(topic_filter) => topic_filter,
+            SubscribeFilter::Invalid(..) => return SubackReturn::Refused(ReasonCode::TopicFilterInvalid)
+        };
+        // https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901250
+        if topic_filter.starts_with("$share/") {
+            return SubackReturn::Refused(ReasonCode::SharedSubscriptionsNotSupported)
+        }
+        if !authorizer.authorize_subscribe(identity, topic_filter) {
+            return SubackReturn::Refused(ReasonCode::NotAuthorized)
+        }
+        SubackReturn::from_qos(cmp::min(options.qos, config.max_qos))
+    }
+
     fn unsubscribe(
         &mut self,
-        addr: &SocketAddr,
+        addr: &PeerAddr,
         packet_id: PacketId,
-        topic_filters: &'a[&'a str]
+        topic_filters: Vec<OwnedTopicFilter>
     ) -> Result<()> {
-        println!("unsubscribe\t{}", addr.to_string());
+        println!("unsubscribe\t{}", addr);
+        let client_id = self.client_id(addr)?;
+        let session = match self.sessions.get_mut(&client_id) {
+            Some(session) => session,
+            None => return Sessions::raise_not_connected()
+        };
+        // Reason codes only go out to MQTT 5 clients
+        let mut reason_codes = Vec::with_capacity(topic_filters.len());
+        for topic_filter in topic_filters {
+            if session.unsubscribe(&topic_filter) {
+                self.subscriptions.unsubscribe(&client_id, &topic_filter);
+                reason_codes.push(ReasonCode::Success);
+            } else {
+                reason_codes.push(ReasonCode::NoSubscriptionExisted);
+            }
+        }
+        session.send(Message::Unsuback { packet_id, reason_codes, properties: Vec::new() });
         Ok(())
     }
 
-    fn pingreq(&self, addr: &SocketAddr) -> Result<()> {
-        println!("pingreq\t{}", addr.to_string());
-        Ok(())
+    fn pingreq(&self, addr: &PeerAddr) -> Result<()> {
+        println!("pingreq\t{}", addr);
+        self.reply(addr, Message::Pingresp)
     }
 
-    fn disconnect(&mut self, addr: &SocketAddr) -> Result<()> {
-        println!("disconnect\t{}", addr.to_string());
-        self.sessions.remove(addr);
+    // https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc385349264
+    // A client that disconnects properly doesn't have its will published [MQTT-3.14.4-3],
+    // unless it's an MQTT 5 client asking for it to be. An MQTT 5 client can also change how
+    // long its session lasts, as long as it didn't start out ending with the connection
+    // [MQTT-3.14.2-2].
+    fn disconnect(&mut self, addr: &PeerAddr, reason_code: ReasonCode, properties: Properties<'static>) -> Result<()> {
+        println!("disconnect\t{}", addr);
+        let session = self.session_mut(addr)?;
+        let expiry_interval = properties.iter().filter_map(|property| {
+            match *property {
+                Property::SessionExpiryInterval(expiry_interval) => Some(expiry_interval),
+                _ => None
+            }
+        }).next();
+        if let Some(expiry_interval) = expiry_interval {
+            if session.expiry_interval == 0 && expiry_interval != 0 {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "a session that ends with its connection can't be given an expiry interval [MQTT-3.14.2-2]"
+                ))
+            }
+            session.expiry_interval = expiry_interval;
+        }
+        if reason_code != ReasonCode::DisconnectWithWill {
+            session.will = None;
+        }
+        self.handle_disconnected(addr);
         Ok(())
     }
 
-    fn raise_wrong_direction() -> Result<()> {
+    fn remove_session(&mut self, client_id: &str) -> Option<Session> {
+        let (client_id, session) = self.sessions.remove_entry(client_id)?;
+        for topic_filter in session.filters.keys() {
+            self.subscriptions.unsubscribe(&client_id, topic_filter);
+        }
+        Some(session)
+    }
+
+    fn raise_wrong_direction<T>() -> Result<T> {
         Err(
             Error::new(
                 ErrorKind::InvalidData,
@@ -162,12 +974,665 @@ impl<'a> Sessions<'a> {
         )
     }
 
-    fn raise_already_connected() -> Result<()> {
+    fn raise_unsupported_auth<T>() -> Result<T> {
         Err(
             Error::new(
                 ErrorKind::InvalidData,
-                "received a connect message from an already-connected address"
+                "enhanced authentication is not supported"
             )
         )
     }
+
+    fn raise_not_connected<T>() -> Result<T> {
+        Err(Sessions::not_connected())
+    }
+
+    fn not_connected() -> Error {
+        Error::new(
+            ErrorKind::InvalidData,
+            "received a message from an address that hasn't connected"
+        )
+    }
+
+    fn raise_identifier_rejected<T>(client_id: &str) -> Result<T> {
+        Err(
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("client identifier '{}' was rejected", client_id)
+            )
+        )
+    }
+
+    fn raise_refused<T>(client_id: &str, return_code: ConnackReturnCode) -> Result<T> {
+        Err(
+            Error::new(
+                ErrorKind::PermissionDenied,
+                format!("client '{}' was refused: {:?}", client_id, return_code)
+            )
+        )
+    }
+
+    fn raise_already_connected<T>() -> Result<T> {
+        Err(
+            Error::new(
+                ErrorKind::InvalidData,
+                "received a second connect message on the same connection"
+            )
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::sync::mpsc;
+    use futures::{future, Async, Future, Stream};
+    use mqtt::*;
+    use std::borrow::Cow;
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    // One end of a connection to the sessions under test, standing in for the socket
+    struct Client {
+        addr: PeerAddr,
+        received: mpsc::UnboundedReceiver<OwnedMessage>,
+        // What came in after the CONNACK, such as messages queued while the client was away
+        pending: Vec<OwnedMessage>
+    }
+
+    impl Client {
+        // Connects `client_id` over a fresh connection, and returns the CONNACK's session
+        // present flag
+        fn connect(sessions: &mut Sessions,
+                   port: u16,
+                   version: ProtocolVersion,
+                   client_id: &str,
+                   clean_session: bool,
+                   properties: Properties<'static>
+        ) -> (Client, bool) {
+            Client::connect_with_will(sessions, port, version, client_id, clean_session, properties, None)
+        }
+
+        fn connect_with_will(sessions: &mut Sessions,
+                             port: u16,
+                             version: ProtocolVersion,
+                             client_id: &str,
+                             clean_session: bool,
+                             properties: Properties<'static>,
+                             will: Option<OwnedWill>
+        ) -> (Client, bool) {
+            let addr = PeerAddr::from(SocketAddr::from(([127, 0, 0, 1], port)));
+            let (outgoing, received) = mpsc::unbounded();
+            let connect = Message::Connect {
+                protocol_version: version,
+                client_id: Cow::Owned(client_id.to_string()),
+                username: None,
+                password: None,
+                will,
+                clean_session,
+                keep_alive: 0,
+                properties
+            };
+            sessions.handle_connect(&addr, &PeerCredentials::default(), outgoing, connect).unwrap();
+            let mut client = Client{ addr, received, pending: Vec::new() };
+            let mut received = client.received();
+            match received.first() {
+                Some(&Message::Connack{ session_present, return_code: ConnackReturnCode::Accepted, .. }) => {
+                    client.pending = received.split_off(1);
+                    (client, session_present)
+                },
+                _ => panic!("expected an accepting CONNACK")
+            }
+        }
+
+        // Everything sent to the client since last time
+        fn received(&mut self) -> Vec<OwnedMessage> {
+            let received = &mut self.received;
+            let messages = future::poll_fn(|| {
+                let mut messages = Vec::new();
+                while let Ok(Async::Ready(Some(msg))) = received.poll() {
+                    messages.push(msg);
+                }
+                Ok::<_, ()>(Async::Ready(messages))
+            }).wait().unwrap();
+            let mut pending = ::std::mem::take(&mut self.pending);
+            pending.extend(messages);
+            pending
+        }
+
+        // Whether the sessions have let go of the connection, once everything sent before has
+        // been taken out of the way
+        fn is_closed(&mut self) -> bool {
+            let received = &mut self.received;
+            future::poll_fn(|| {
+                loop {
+                    match received.poll() {
+                        Ok(Async::Ready(Some(_))) => (),
+                        Ok(Async::Ready(None)) => return Ok::<_, ()>(Async::Ready(true)),
+                        _ => return Ok(Async::Ready(false))
+                    }
+                }
+            }).wait().unwrap()
+        }
+
+        fn send(&self, sessions: &mut Sessions, msg: OwnedMessage) {
+            sessions.handle_message(&self.addr, msg).unwrap();
+        }
+
+        fn disconnect(&self, sessions: &mut Sessions, properties: Properties<'static>) {
+            self.send(sessions, Message::Disconnect{ reason_code: ReasonCode::Success, properties });
+        }
+    }
+
+    fn session_expiry(seconds: u32) -> Properties<'static> {
+        vec![Property::SessionExpiryInterval(seconds)]
+    }
+
+    #[test]
+    fn v5_session_without_an_expiry_interval_ends_with_its_connection() {
+        let mut sessions = Sessions::new();
+        let (client, _) = Client::connect(&mut sessions, 1, ProtocolVersion::V5, "v5", false, Vec::new());
+        client.disconnect(&mut sessions, Vec::new());
+        let (_, session_present) = Client::connect(&mut sessions, 2, ProtocolVersion::V5, "v5", false, Vec::new());
+        assert!(!session_present);
+    }
+
+    #[test]
+    fn v5_clean_start_with_an_expiry_interval_outlives_its_connection() {
+        let mut sessions = Sessions::new();
+        let (client, session_present) =
+            Client::connect(&mut sessions, 1, ProtocolVersion::V5, "v5", true, session_expiry(60));
+        assert!(!session_present);
+        client.disconnect(&mut sessions, Vec::new());
+        let (_, session_present) = Client::connect(&mut sessions, 2, ProtocolVersion::V5, "v5", false, Vec::new());
+        assert!(session_present);
+    }
+
+    #[test]
+    fn v5_session_ends_once_its_expiry_interval_has_passed() {
+        let mut sessions = Sessions::new();
+        let (client, _) = Client::connect(&mut sessions, 1, ProtocolVersion::V5, "v5", false, session_expiry(60));
+        client.disconnect(&mut sessions, Vec::new());
+        sessions.expire_sessions();
+        assert!(sessions.sessions.contains_key("v5"));
+        sessions.sessions.get_mut("v5").unwrap().expires = Some(Instant::now());
+        sessions.expire_sessions();
+        assert!(!sessions.sessions.contains_key("v5"));
+    }
+
+    #[test]
+    fn v5_disconnect_can_shorten_the_expiry_interval_but_not_add_one() {
+        let mut sessions = Sessions::new();
+        let (client, _) = Client::connect(&mut sessions, 1, ProtocolVersion::V5, "a", false, session_expiry(60));
+        client.disconnect(&mut sessions, session_expiry(0));
+        assert!(!sessions.sessions.contains_key("a"));
+
+        let (client, _) = Client::connect(&mut sessions, 2, ProtocolVersion::V5, "b", false, Vec::new());
+        let disconnect = Message::Disconnect{ reason_code: ReasonCode::Success, properties: session_expiry(60) };
+        assert!(sessions.handle_message(&client.addr, disconnect).is_err());
+    }
+
+    #[test]
+    fn v311_sessions_persist_unless_clean() {
+        let mut sessions = Sessions::new();
+        let (client, _) = Client::connect(&mut sessions, 1, ProtocolVersion::V311, "kept", false, Vec::new());
+        client.disconnect(&mut sessions, Vec::new());
+        let (client, session_present) =
+            Client::connect(&mut sessions, 2, ProtocolVersion::V311, "kept", false, Vec::new());
+        assert!(session_present);
+        client.disconnect(&mut sessions, Vec::new());
+        let (_, session_present) = Client::connect(&mut sessions, 3, ProtocolVersion::V311, "kept", true, Vec::new());
+        assert!(!session_present);
+    }
+
+    #[test]
+    fn a_second_connect_closes_the_first_connection() {
+        let mut sessions = Sessions::new();
+        for (port, version) in [(1, ProtocolVersion::V311), (3, ProtocolVersion::V5)] {
+            let (mut previous, _) = Client::connect(&mut sessions, port, version, "c", true, Vec::new());
+            let (mut current, _) = Client::connect(&mut sessions, port + 1, version, "c", true, Vec::new());
+            if version == ProtocolVersion::V5 {
+                match previous.received().as_slice() {
+                    [Message::Disconnect{ reason_code: ReasonCode::SessionTakenOver, .. }] => (),
+                    _ => panic!("expected a DISCONNECT saying the session was taken over")
+                }
+            }
+            assert!(previous.is_closed());
+            assert!(!current.is_closed());
+            // Only the new connection speaks for the client now
+            assert!(sessions.handle_message(&previous.addr, Message::Pingreq).is_err());
+            current.send(&mut sessions, Message::Pingreq);
+            assert!(matches!(current.received().as_slice(), [Message::Pingresp]));
+            current.disconnect(&mut sessions, Vec::new());
+        }
+    }
+
+    #[test]
+    fn taking_over_a_session_resumes_it() {
+        let mut sessions = Sessions::new();
+        let (_previous, session_present) = Client::connect(&mut sessions, 1, ProtocolVersion::V311, "c", false, Vec::new());
+        assert!(!session_present);
+        let (_current, session_present) = Client::connect(&mut sessions, 2, ProtocolVersion::V311, "c", false, Vec::new());
+        assert!(session_present);
+        // Unless the new connection asks to start clean
+        let (_, session_present) = Client::connect(&mut sessions, 3, ProtocolVersion::V311, "c", true, Vec::new());
+        assert!(!session_present);
+    }
+
+    #[test]
+    fn reconnecting_from_another_address_keeps_subscriptions() {
+        let mut sessions = Sessions::new();
+        let (previous, _) = Client::connect(&mut sessions, 1, ProtocolVersion::V311, "sub", false, Vec::new());
+        previous.send(&mut sessions, subscribe(&[("t", QualityOfService::AtMostOnce)]));
+        let (mut current, _) = Client::connect(&mut sessions, 2, ProtocolVersion::V311, "sub", false, Vec::new());
+        let (publisher, _) = Client::connect(&mut sessions, 3, ProtocolVersion::V311, "pub", true, Vec::new());
+        publisher.send(&mut sessions, publish(QualityOfService::AtMostOnce, None, b"hello"));
+        assert!(matches!(current.received().as_slice(), [Message::Publish{ .. }]));
+    }
+
+    fn subscribe(filters: &[(&'static str, QualityOfService)]) -> OwnedMessage {
+        Message::Subscribe {
+            packet_id: 1,
+            topic_filters: filters.iter().map(|&(filter, qos)| {
+                (SubscribeFilter::new(filter), SubscriptionOptions::new(qos))
+            }).collect(),
+            properties: Vec::new()
+        }
+    }
+
+    #[test]
+    fn invalid_filters_are_refused_on_their_own() {
+        let mut sessions = Sessions::with_config(Config{ max_qos: QualityOfService::AtLeastOnce, ..Config::default() });
+        let filters = [
+            ("a/#", QualityOfService::ExactlyOnce),
+            ("a/#/b", QualityOfService::AtMostOnce),
+            ("a/+x", QualityOfService::AtMostOnce),
+            ("b", QualityOfService::AtMostOnce)
+        ];
+        let (mut v311, _) = Client::connect(&mut sessions, 1, ProtocolVersion::V311, "v311", true, Vec::new());
+        v311.send(&mut sessions, subscribe(&filters));
+        let (mut v5, _) = Client::connect(&mut sessions, 2, ProtocolVersion::V5, "v5", true, Vec::new());
+        v5.send(&mut sessions, subscribe(&filters));
+
+        for (client, version) in [(&mut v311, ProtocolVersion::V311), (&mut v5, ProtocolVersion::V5)] {
+            match client.received().first() {
+                Some(&Message::Suback{ packet_id: 1, ref return_codes, .. }) => {
+                    let bytes: Vec<u8> = return_codes.iter().map(|code| { code.to_byte(version) }).collect();
+                    let refused = if version == ProtocolVersion::V5 { 0x8F } else { 0x80 };
+                    assert_eq!(bytes, vec![1, refused, refused, 0]);
+                },
+                _ => panic!("expected a SUBACK")
+            }
+        }
+        assert_eq!(sessions.subscribers(&TopicName::new("a/b").unwrap(), None).len(), 2);
+    }
+
+    fn publish(qos: QualityOfService, packet_id: Option<PacketId>, payload: &'static [u8]) -> OwnedMessage {
+        Message::Publish {
+            dup: false,
+            qos,
+            retain: false,
+            topic: TopicName::new("t").unwrap(),
+            packet_id,
+            payload: Cow::Borrowed(payload),
+            properties: Vec::new()
+        }
+    }
+
+    #[test]
+    fn unacknowledged_messages_are_sent_again_after_the_retry_interval() {
+        let config = Config{ retry_interval: Some(Duration::from_secs(0)), ..Config::default() };
+        let mut sessions = Sessions::with_config(config);
+        let (mut subscriber, _) = Client::connect(&mut sessions, 1, ProtocolVersion::V311, "sub", true, Vec::new());
+        subscriber.send(&mut sessions, subscribe(&[("t", QualityOfService::AtLeastOnce)]));
+        let (publisher, _) = Client::connect(&mut sessions, 2, ProtocolVersion::V311, "pub", true, Vec::new());
+        publisher.send(&mut sessions, publish(QualityOfService::AtMostOnce, None, b"hello"));
+        publisher.send(&mut sessions, publish(QualityOfService::AtLeastOnce, Some(1), b"hello"));
+        subscriber.received();
+
+        sessions.retry_unacknowledged();
+        match subscriber.received().as_slice() {
+            [Message::Publish{ dup: true, qos: QualityOfService::AtLeastOnce, .. }] => (),
+            _ => panic!("expected the QoS 1 PUBLISH again, marked as a duplicate")
+        }
+    }
+
+    fn subscribe_with(topic_filter: &'static str, options: SubscriptionOptions) -> OwnedMessage {
+        Message::Subscribe {
+            packet_id: 1,
+            topic_filters: vec![(SubscribeFilter::new(topic_filter), options)],
+            properties: Vec::new()
+        }
+    }
+
+    #[test]
+    fn no_local_subscriptions_skip_the_publishers_own_messages() {
+        let mut sessions = Sessions::new();
+        let (mut client, _) = Client::connect(&mut sessions, 1, ProtocolVersion::V5, "v5", true, Vec::new());
+        let no_local = SubscriptionOptions{ no_local: true, ..SubscriptionOptions::new(QualityOfService::AtMostOnce) };
+        client.send(&mut sessions, subscribe_with("t", no_local));
+        client.received();
+        client.send(&mut sessions, publish(QualityOfService::AtMostOnce, None, b"mine"));
+        assert!(client.received().is_empty());
+
+        // Another matching subscription without No Local still gets it
+        client.send(&mut sessions, subscribe_with("#", SubscriptionOptions::new(QualityOfService::AtMostOnce)));
+        client.received();
+        client.send(&mut sessions, publish(QualityOfService::AtMostOnce, None, b"mine"));
+        assert_eq!(client.received().len(), 1);
+    }
+
+    #[test]
+    fn only_retain_as_published_subscriptions_keep_the_retain_flag() {
+        let mut sessions = Sessions::new();
+        let as_published = SubscriptionOptions {
+            retain_as_published: true,
+            retain_handling: RetainHandling::DoNotSend,
+            ..SubscriptionOptions::new(QualityOfService::AtMostOnce)
+        };
+        let (mut kept, _) = Client::connect(&mut sessions, 1, ProtocolVersion::V5, "kept", true, Vec::new());
+        kept.send(&mut sessions, subscribe_with("t", as_published));
+        let (mut cleared, _) = Client::connect(&mut sessions, 2, ProtocolVersion::V5, "cleared", true, Vec::new());
+        cleared.send(&mut sessions, subscribe_with("t", SubscriptionOptions::new(QualityOfService::AtMostOnce)));
+        kept.received();
+        cleared.received();
+
+        let (publisher, _) = Client::connect(&mut sessions, 3, ProtocolVersion::V5, "pub", true, Vec::new());
+        let mut msg = publish(QualityOfService::AtMostOnce, None, b"hello");
+        if let Message::Publish{ ref mut retain, .. } = msg {
+            *retain = true;
+        }
+        publisher.send(&mut sessions, msg);
+        for (client, expected) in [(&mut kept, true), (&mut cleared, false)] {
+            match client.received().as_slice() {
+                [Message::Publish{ retain, .. }] => assert_eq!(*retain, expected),
+                _ => panic!("expected one PUBLISH")
+            }
+        }
+    }
+
+    #[test]
+    fn retained_messages_are_sent_on_subscribe_with_the_retain_flag() {
+        let mut sessions = Sessions::new();
+        let (publisher, _) = Client::connect(&mut sessions, 1, ProtocolVersion::V311, "pub", true, Vec::new());
+        let mut msg = publish(QualityOfService::AtLeastOnce, Some(1), b"hello");
+        if let Message::Publish{ ref mut retain, .. } = msg {
+            *retain = true;
+        }
+        publisher.send(&mut sessions, msg);
+
+        let (mut subscriber, _) = Client::connect(&mut sessions, 2, ProtocolVersion::V311, "sub", true, Vec::new());
+        subscriber.send(&mut sessions, subscribe(&[("#", QualityOfService::AtMostOnce)]));
+        match subscriber.received().as_slice() {
+            [Message::Suback{ .. }, Message::Publish{ retain: true, qos: QualityOfService::AtMostOnce, payload, .. }] =>
+                assert_eq!(&**payload, b"hello"),
+            _ => panic!("expected the SUBACK, then the retained PUBLISH at the granted QoS")
+        }
+    }
+
+    // The packet id of each PUBLISH in `received`
+    fn packet_ids(received: &[OwnedMessage]) -> Vec<PacketId> {
+        received.iter().map(|msg| {
+            match *msg {
+                Message::Publish{ packet_id: Some(packet_id), .. } => packet_id,
+                _ => panic!("expected a QoS 1 or 2 PUBLISH")
+            }
+        }).collect()
+    }
+
+    #[test]
+    fn duplicate_qos_2_publishes_are_routed_once() {
+        let mut sessions = Sessions::new();
+        let (mut subscriber, _) = Client::connect(&mut sessions, 1, ProtocolVersion::V311, "sub", true, Vec::new());
+        subscriber.send(&mut sessions, subscribe(&[("t", QualityOfService::ExactlyOnce)]));
+        subscriber.received();
+        let (mut publisher, _) = Client::connect(&mut sessions, 2, ProtocolVersion::V311, "pub", true, Vec::new());
+
+        publisher.send(&mut sessions, publish(QualityOfService::ExactlyOnce, Some(7), b"once"));
+        let mut again = publish(QualityOfService::ExactlyOnce, Some(7), b"once");
+        if let Message::Publish{ ref mut dup, .. } = again {
+            *dup = true;
+        }
+        publisher.send(&mut sessions, again);
+        match publisher.received().as_slice() {
+            [Message::Pubrec{ packet_id: 7, .. }, Message::Pubrec{ packet_id: 7, .. }] => (),
+            _ => panic!("expected a PUBREC for each PUBLISH")
+        }
+        assert_eq!(subscriber.received().len(), 1);
+
+        // Once released, the packet id carries a new message
+        publisher.send(&mut sessions, Message::Pubrel{ packet_id: 7, reason_code: ReasonCode::Success, properties: Vec::new() });
+        match publisher.received().as_slice() {
+            [Message::Pubcomp{ packet_id: 7, reason_code: ReasonCode::Success, .. }] => (),
+            _ => panic!("expected a PUBCOMP")
+        }
+        publisher.send(&mut sessions, publish(QualityOfService::ExactlyOnce, Some(7), b"twice"));
+        assert_eq!(subscriber.received().len(), 1);
+    }
+
+    #[test]
+    fn qos_2_deliveries_finish_with_pubrec_pubrel_pubcomp() {
+        let mut sessions = Sessions::new();
+        let (mut subscriber, _) = Client::connect(&mut sessions, 1, ProtocolVersion::V311, "sub", true, Vec::new());
+        subscriber.send(&mut sessions, subscribe(&[("t", QualityOfService::ExactlyOnce)]));
+        subscriber.received();
+        let (publisher, _) = Client::connect(&mut sessions, 2, ProtocolVersion::V311, "pub", true, Vec::new());
+        publisher.send(&mut sessions, publish(QualityOfService::ExactlyOnce, Some(1), b"hello"));
+
+        let packet_id = packet_ids(&subscriber.received())[0];
+        subscriber.send(&mut sessions, Message::Pubrec{ packet_id, reason_code: ReasonCode::Success, properties: Vec::new() });
+        match subscriber.received().as_slice() {
+            [Message::Pubrel{ packet_id: id, .. }] if *id == packet_id => (),
+            _ => panic!("expected a PUBREL")
+        }
+        assert_eq!(sessions.sessions["sub"].unacknowledged.len(), 1);
+        subscriber.send(&mut sessions, Message::Pubcomp{ packet_id, reason_code: ReasonCode::Success, properties: Vec::new() });
+        assert!(sessions.sessions["sub"].unacknowledged.is_empty());
+        assert!(subscriber.received().is_empty());
+    }
+
+    #[test]
+    fn puback_makes_room_in_the_inflight_window() {
+        let mut sessions = Sessions::with_config(Config{ max_inflight: 2, ..Config::default() });
+        let (mut subscriber, _) = Client::connect(&mut sessions, 1, ProtocolVersion::V311, "sub", true, Vec::new());
+        subscriber.send(&mut sessions, subscribe(&[("t", QualityOfService::AtLeastOnce)]));
+        subscriber.received();
+        let (publisher, _) = Client::connect(&mut sessions, 2, ProtocolVersion::V311, "pub", true, Vec::new());
+        for packet_id in 1..4 {
+            publisher.send(&mut sessions, publish(QualityOfService::AtLeastOnce, Some(packet_id), b"hello"));
+        }
+
+        let in_flight = packet_ids(&subscriber.received());
+        assert_eq!(in_flight.len(), 2);
+        subscriber.send(&mut sessions, Message::Puback{ packet_id: in_flight[0], reason_code: ReasonCode::Success, properties: Vec::new() });
+        assert_eq!(packet_ids(&subscriber.received()).len(), 1);
+        // A PUBACK for something that isn't in flight doesn't make room
+        subscriber.send(&mut sessions, Message::Puback{ packet_id: in_flight[0], reason_code: ReasonCode::Success, properties: Vec::new() });
+        assert!(subscriber.received().is_empty());
+    }
+
+    #[test]
+    fn full_queues_drop_qos_0_messages_first() {
+        let mut sessions = Sessions::with_config(Config{ max_queued: 3, ..Config::default() });
+        let (subscriber, _) = Client::connect(&mut sessions, 1, ProtocolVersion::V311, "sub", false, Vec::new());
+        subscriber.send(&mut sessions, subscribe(&[("t", QualityOfService::AtLeastOnce)]));
+        subscriber.disconnect(&mut sessions, Vec::new());
+
+        let (publisher, _) = Client::connect(&mut sessions, 2, ProtocolVersion::V311, "pub", true, Vec::new());
+        publisher.send(&mut sessions, publish(QualityOfService::AtMostOnce, None, b"a"));
+        publisher.send(&mut sessions, publish(QualityOfService::AtLeastOnce, Some(1), b"b"));
+        publisher.send(&mut sessions, publish(QualityOfService::AtMostOnce, None, b"c"));
+        // Each of these pushes out a QoS 0 message, oldest first
+        publisher.send(&mut sessions, publish(QualityOfService::AtLeastOnce, Some(2), b"d"));
+        publisher.send(&mut sessions, publish(QualityOfService::AtLeastOnce, Some(3), b"e"));
+        // And with only QoS 1 messages left, this one is dropped
+        publisher.send(&mut sessions, publish(QualityOfService::AtLeastOnce, Some(4), b"f"));
+        assert_eq!(sessions.sessions["sub"].queued.len(), 3);
+
+        let (mut subscriber, session_present) =
+            Client::connect(&mut sessions, 3, ProtocolVersion::V311, "sub", false, Vec::new());
+        assert!(session_present);
+        let payloads: Vec<Vec<u8>> = subscriber.received().into_iter().map(|msg| {
+            match msg {
+                Message::Publish{ payload, .. } => payload.into_owned(),
+                _ => panic!("expected a PUBLISH")
+            }
+        }).collect();
+        assert_eq!(payloads, vec![b"b".to_vec(), b"d".to_vec(), b"e".to_vec()]);
+    }
+
+    #[test]
+    fn queued_qos_0_messages_go_out_without_a_packet_id() {
+        let mut sessions = Sessions::with_config(Config{ max_inflight: 1, ..Config::default() });
+        let (subscriber, _) = Client::connect(&mut sessions, 1, ProtocolVersion::V311, "sub", false, Vec::new());
+        subscriber.send(&mut sessions, subscribe(&[("t", QualityOfService::AtLeastOnce)]));
+        subscriber.disconnect(&mut sessions, Vec::new());
+
+        let (publisher, _) = Client::connect(&mut sessions, 2, ProtocolVersion::V311, "pub", true, Vec::new());
+        publisher.send(&mut sessions, publish(QualityOfService::AtLeastOnce, Some(1), b"a"));
+        publisher.send(&mut sessions, publish(QualityOfService::AtLeastOnce, Some(2), b"b"));
+        publisher.send(&mut sessions, publish(QualityOfService::AtMostOnce, None, b"c"));
+
+        let (mut subscriber, _) = Client::connect(&mut sessions, 3, ProtocolVersion::V311, "sub", false, Vec::new());
+        let in_flight = packet_ids(&subscriber.received());
+        assert_eq!(in_flight.len(), 1);
+        let packet_id = in_flight[0];
+        subscriber.send(&mut sessions, Message::Puback{ packet_id, reason_code: ReasonCode::Success, properties: Vec::new() });
+        match subscriber.received().as_slice() {
+            [Message::Publish{ packet_id: Some(_), .. }, Message::Publish{ packet_id: None, .. }] => (),
+            _ => panic!("expected the second QoS 1 PUBLISH, then the QoS 0 one")
+        }
+    }
+
+    fn will() -> OwnedWill {
+        Will {
+            retain: false,
+            qos: QualityOfService::AtMostOnce,
+            topic: TopicName::new("will").unwrap(),
+            message: Cow::Borrowed(b"gone"),
+            properties: Vec::new()
+        }
+    }
+
+    // A client subscribed to the topic of `will()`
+    fn will_watcher(sessions: &mut Sessions) -> Client {
+        let (mut watcher, _) = Client::connect(sessions, 99, ProtocolVersion::V311, "watcher", true, Vec::new());
+        watcher.send(sessions, subscribe(&[("will", QualityOfService::AtMostOnce)]));
+        watcher.received();
+        watcher
+    }
+
+    fn wills(watcher: &mut Client) -> usize {
+        watcher.received().iter().filter(|msg| {
+            match **msg {
+                Message::Publish{ ref topic, ref payload, .. } => &**topic == "will" && **payload == b"gone"[..],
+                _ => false
+            }
+        }).count()
+    }
+
+    #[test]
+    fn wills_are_published_when_the_connection_ends_without_a_disconnect() {
+        // Which is how both network errors and expired keep alives end up here
+        let mut sessions = Sessions::new();
+        let mut watcher = will_watcher(&mut sessions);
+        let (client, _) =
+            Client::connect_with_will(&mut sessions, 1, ProtocolVersion::V311, "c", true, Vec::new(), Some(will()));
+        sessions.handle_disconnected(&client.addr);
+        assert_eq!(wills(&mut watcher), 1);
+        sessions.handle_disconnected(&client.addr);
+        assert_eq!(wills(&mut watcher), 0);
+    }
+
+    #[test]
+    fn wills_are_published_when_the_session_is_taken_over() {
+        let mut sessions = Sessions::new();
+        let mut watcher = will_watcher(&mut sessions);
+        let (previous, _) =
+            Client::connect_with_will(&mut sessions, 1, ProtocolVersion::V311, "c", false, Vec::new(), Some(will()));
+        Client::connect(&mut sessions, 2, ProtocolVersion::V311, "c", false, Vec::new());
+        assert_eq!(wills(&mut watcher), 1);
+        // The old connection closing afterwards has nothing left to publish
+        sessions.handle_disconnected(&previous.addr);
+        assert_eq!(wills(&mut watcher), 0);
+    }
+
+    #[test]
+    fn wills_are_discarded_after_a_disconnect() {
+        let mut sessions = Sessions::new();
+        let mut watcher = will_watcher(&mut sessions);
+        for (port, version) in [(1, ProtocolVersion::V311), (2, ProtocolVersion::V5)] {
+            let (client, _) = Client::connect_with_will(&mut sessions, port, version, "c", true, Vec::new(), Some(will()));
+            client.disconnect(&mut sessions, Vec::new());
+            sessions.handle_disconnected(&client.addr);
+            assert_eq!(wills(&mut watcher), 0);
+        }
+    }
+
+    #[test]
+    fn v5_disconnect_with_will_publishes_it() {
+        let mut sessions = Sessions::new();
+        let mut watcher = will_watcher(&mut sessions);
+        let (client, _) =
+            Client::connect_with_will(&mut sessions, 1, ProtocolVersion::V5, "c", true, Vec::new(), Some(will()));
+        client.send(&mut sessions, Message::Disconnect{ reason_code: ReasonCode::DisconnectWithWill, properties: Vec::new() });
+        assert_eq!(wills(&mut watcher), 1);
+    }
+
+    #[test]
+    fn wills_the_acl_forbids_are_refused_at_connect() {
+        let mut sessions = Sessions::new();
+        sessions.set_authorizer(Box::new(AclFile::parse("topic readwrite allowed/#").unwrap()));
+        let addr = PeerAddr::from(SocketAddr::from(([127, 0, 0, 1], 1)));
+        let (outgoing, mut received) = mpsc::unbounded();
+        let connect = Message::Connect {
+            protocol_version: ProtocolVersion::V311,
+            client_id: Cow::Borrowed("c"),
+            username: None,
+            password: None,
+            will: Some(will()),
+            clean_session: true,
+            keep_alive: 0,
+            properties: Vec::new()
+        };
+        assert!(sessions.handle_connect(&addr, &PeerCredentials::default(), outgoing, connect).is_err());
+        match received.poll() {
+            Ok(Async::Ready(Some(Message::Connack{ return_code: ConnackReturnCode::NotAuthorized, .. }))) => (),
+            _ => panic!("expected a CONNACK refusing the client")
+        }
+        assert!(!sessions.sessions.contains_key("c"));
+    }
+
+    // Only lets in clients that send a password, and an empty one at that
+    struct EmptyPasswordOnly;
+
+    impl Authenticator for EmptyPasswordOnly {
+        fn authenticate(&self, credentials: &Credentials) -> ::std::result::Result<(), ConnackReturnCode> {
+            match credentials.password {
+                Some(&[]) => Ok(()),
+                _ => Err(ConnackReturnCode::BadUsernameOrPassword)
+            }
+        }
+    }
+
+    #[test]
+    fn authenticators_see_an_empty_password() {
+        let mut sessions = Sessions::new();
+        sessions.set_authenticator(Box::new(EmptyPasswordOnly));
+        for (port, password, accepted) in [(1, Some(Cow::Borrowed(&b""[..])), true), (2, None, false)] {
+            let addr = PeerAddr::from(SocketAddr::from(([127, 0, 0, 1], port)));
+            let (outgoing, _received) = mpsc::unbounded();
+            let connect = Message::Connect {
+                protocol_version: ProtocolVersion::V5,
+                client_id: Cow::Borrowed("c"),
+                username: None,
+                password,
+                will: None,
+                clean_session: true,
+                keep_alive: 0,
+                properties: Vec::new()
+            };
+            assert_eq!(sessions.handle_connect(&addr, &PeerCredentials::default(), outgoing, connect).is_ok(), accepted);
+            sessions.handle_disconnected(&addr);
+        }
+    }
 }
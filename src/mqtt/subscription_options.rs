@@ -0,0 +1,75 @@
+use mqtt::*;
+use std::io::Result;
+
+// https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901169
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RetainHandling {
+    SendOnSubscribe,
+    SendOnNewSubscribe,
+    DoNotSend
+}
+
+// The byte following each topic filter in a SUBSCRIBE. MQTT 3.1.1 only has the QoS bits, the
+// rest are MQTT 5 additions and keep their defaults on a 3.1.1 connection.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SubscriptionOptions {
+    pub qos: QualityOfService,
+    pub no_local: bool,
+    pub retain_as_published: bool,
+    pub retain_handling: RetainHandling
+}
+
+impl SubscriptionOptions {
+    pub fn new(qos: QualityOfService) -> Self {
+        SubscriptionOptions {
+            qos,
+            no_local: false,
+            retain_as_published: false,
+            retain_handling: RetainHandling::SendOnSubscribe
+        }
+    }
+
+    pub fn to_byte(&self, version: ProtocolVersion) -> u8 {
+        let (high, low) = self.qos.bits();
+        let qos = ((high as u8) << 1) | low as u8;
+        match version {
+            ProtocolVersion::V31 | ProtocolVersion::V311 => qos,
+            ProtocolVersion::V5 => {
+                let retain_handling = match self.retain_handling {
+                    RetainHandling::SendOnSubscribe => 0u8,
+                    RetainHandling::SendOnNewSubscribe => 1u8,
+                    RetainHandling::DoNotSend => 2u8
+                };
+                qos | (self.no_local as u8) << 2 | (self.retain_as_published as u8) << 3 | retain_handling << 4
+            }
+        }
+    }
+
+    pub fn from_byte(version: ProtocolVersion, byte: u8) -> Result<Self> {
+        // A requested QoS of 3 is a malformed SUBSCRIBE, like its reserved bits [MQTT-3.8.3-4]
+        let qos = QualityOfService::from_byte(byte & 0b0000_0011)
+            .map_err(|_| { ProtocolError::InvalidSubscriptionQos(byte & 0b0000_0011) })?;
+        match version {
+            ProtocolVersion::V31 | ProtocolVersion::V311 if byte & 0b1111_1100 != 0 =>
+                Err(ProtocolError::ReservedSubscriptionOptions(byte).into()),
+            ProtocolVersion::V31 | ProtocolVersion::V311 => Ok(SubscriptionOptions::new(qos)),
+            ProtocolVersion::V5 => {
+                let retain_handling = match (byte >> 4) & 0b11 {
+                    0 => RetainHandling::SendOnSubscribe,
+                    1 => RetainHandling::SendOnNewSubscribe,
+                    2 => RetainHandling::DoNotSend,
+                    _ => return Err(ProtocolError::ReservedSubscriptionOptions(byte).into())
+                };
+                if byte & 0b1100_0000 != 0 {
+                    return Err(ProtocolError::ReservedSubscriptionOptions(byte).into())
+                }
+                Ok(SubscriptionOptions {
+                    qos,
+                    no_local: byte & 0b0000_0100 != 0,
+                    retain_as_published: byte & 0b0000_1000 != 0,
+                    retain_handling
+                })
+            }
+        }
+    }
+}
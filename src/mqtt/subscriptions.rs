@@ -0,0 +1,254 @@
+use mqtt::*;
+use std::borrow::Borrow;
+use std::cmp;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::hash::Hash;
+
+// Every subscription on the broker, indexed by topic level so that routing a publish only
+// visits the levels its topic could match, rather than every subscription. `K` identifies
+// whoever holds a subscription.
+pub struct Subscriptions<K> {
+    root: Node<K>,
+    len: usize
+}
+
+struct Node<K> {
+    children: HashMap<String, Node<K>>,
+    // The subscriptions whose filter ends at this level
+    subscribers: HashMap<K, SubscriptionOptions>
+}
+
+impl<K: Clone + Eq + Hash> Node<K> {
+    fn new() -> Self {
+        Node{ children: HashMap::new(), subscribers: HashMap::new() }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.children.is_empty() && self.subscribers.is_empty()
+    }
+
+    // Removes `key`'s subscription to the filter made of `levels`, pruning any levels left with
+    // nothing under them. Returns whether there was one.
+    fn remove<'f, I: Iterator<Item = &'f str>>(&mut self, key: &K, mut levels: I) -> bool {
+        match levels.next() {
+            None => self.subscribers.remove(key).is_some(),
+            Some(level) => match self.children.entry(level.to_string()) {
+                Entry::Vacant(_) => false,
+                Entry::Occupied(mut child) => {
+                    let removed = child.get_mut().remove(key, levels);
+                    if child.get().is_empty() {
+                        child.remove();
+                    }
+                    removed
+                }
+            }
+        }
+    }
+
+    // https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718107
+    // `levels` is what's left of the topic after this node. Wildcards don't match a '$' topic
+    // at its first level.
+    fn collect<Q>(&self, levels: &[&str], first: bool, system: bool, publisher: Option<&Q>, matched: &mut HashMap<K, SubscriptionOptions>)
+    where K: Borrow<Q>, Q: Eq + ?Sized
+    {
+        let wildcards = !(first && system);
+        if wildcards {
+            if let Some(child) = self.children.get("#") {
+                Node::add(&child.subscribers, publisher, matched);
+            }
+        }
+        match levels.split_first() {
+            None => Node::add(&self.subscribers, publisher, matched),
+            Some((level, rest)) => {
+                if wildcards {
+                    if let Some(child) = self.children.get("+") {
+                        child.collect(rest, false, system, publisher, matched);
+                    }
+                }
+                if let Some(child) = self.children.get(*level) {
+                    child.collect(rest, false, system, publisher, matched);
+                }
+            }
+        }
+    }
+
+    // https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901169
+    // A subscriber with several matching filters gets the highest QoS among them, and keeps the
+    // retain flag if any of them asks it to. A No Local subscription doesn't match the
+    // publisher's own messages [MQTT-3.8.3-3].
+    fn add<Q>(subscribers: &HashMap<K, SubscriptionOptions>, publisher: Option<&Q>, matched: &mut HashMap<K, SubscriptionOptions>)
+    where K: Borrow<Q>, Q: Eq + ?Sized
+    {
+        for (key, options) in subscribers {
+            if options.no_local && publisher == Some(key.borrow()) {
+                continue
+            }
+            let merged = matched.entry(key.clone()).or_insert(*options);
+            merged.qos = cmp::max(merged.qos, options.qos);
+            merged.retain_as_published |= options.retain_as_published;
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash> Default for Subscriptions<K> {
+    fn default() -> Self {
+        Subscriptions::new()
+    }
+}
+
+impl<K: Clone + Eq + Hash> Subscriptions<K> {
+    pub fn new() -> Self {
+        Subscriptions{ root: Node::new(), len: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Subscribing again to the same filter replaces the earlier subscription's options, which
+    // are returned
+    pub fn subscribe(&mut self, key: K, filter: &TopicFilter, options: SubscriptionOptions) -> Option<SubscriptionOptions> {
+        let mut node = &mut self.root;
+        for level in filter.levels() {
+            node = node.children.entry(level.to_string()).or_insert_with(Node::new);
+        }
+        let previous = node.subscribers.insert(key, options);
+        if previous.is_none() {
+            self.len += 1;
+        }
+        previous
+    }
+
+    pub fn unsubscribe(&mut self, key: &K, filter: &TopicFilter) -> bool {
+        let removed = self.root.remove(key, filter.levels());
+        if removed {
+            self.len -= 1;
+        }
+        removed
+    }
+
+    // Everyone subscribed to a filter matching a message `publisher` sent to `topic`, with the
+    // options of their matching subscriptions combined
+    pub fn matches<Q>(&self, topic: &TopicName, publisher: Option<&Q>) -> HashMap<K, SubscriptionOptions>
+    where K: Borrow<Q>, Q: Eq + ?Sized
+    {
+        let levels: Vec<&str> = topic.levels().collect();
+        let mut matched = HashMap::new();
+        self.root.collect(&levels, true, topic.is_system(), publisher, &mut matched);
+        matched
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqtt::*;
+
+    fn filter(filter: &'static str) -> TopicFilter<'static> {
+        TopicFilter::new(filter).unwrap()
+    }
+
+    fn matches(subscriptions: &Subscriptions<String>, topic: &'static str, publisher: Option<&str>) -> Vec<(String, QualityOfService)> {
+        let mut matched: Vec<(String, QualityOfService)> = subscriptions
+            .matches(&TopicName::new(topic).unwrap(), publisher)
+            .into_iter()
+            .map(|(key, options)| { (key, options.qos) })
+            .collect();
+        matched.sort_by(|a, b| { a.0.cmp(&b.0) });
+        matched
+    }
+
+    #[test]
+    fn overlapping_subscriptions_merge_to_the_highest_qos() {
+        let mut subscriptions = Subscriptions::new();
+        subscriptions.subscribe("a".to_string(), &filter("t/+"), SubscriptionOptions::new(QualityOfService::AtLeastOnce));
+        subscriptions.subscribe("a".to_string(), &filter("#"), SubscriptionOptions::new(QualityOfService::ExactlyOnce));
+        subscriptions.subscribe("a".to_string(), &filter("t/x"), SubscriptionOptions::new(QualityOfService::AtMostOnce));
+        subscriptions.subscribe("b".to_string(), &filter("t/x"), SubscriptionOptions::new(QualityOfService::AtMostOnce));
+        assert_eq!(subscriptions.len(), 4);
+        assert_eq!(matches(&subscriptions, "t/x", None), vec![
+            ("a".to_string(), QualityOfService::ExactlyOnce),
+            ("b".to_string(), QualityOfService::AtMostOnce)
+        ]);
+        assert_eq!(matches(&subscriptions, "t/y", None), vec![("a".to_string(), QualityOfService::ExactlyOnce)]);
+    }
+
+    #[test]
+    fn subscribing_again_replaces_the_options() {
+        let mut subscriptions = Subscriptions::new();
+        let first = SubscriptionOptions::new(QualityOfService::ExactlyOnce);
+        assert_eq!(subscriptions.subscribe("a".to_string(), &filter("t"), first), None);
+        let second = SubscriptionOptions::new(QualityOfService::AtMostOnce);
+        assert_eq!(subscriptions.subscribe("a".to_string(), &filter("t"), second), Some(first));
+        assert_eq!(subscriptions.len(), 1);
+        assert_eq!(matches(&subscriptions, "t", None), vec![("a".to_string(), QualityOfService::AtMostOnce)]);
+    }
+
+    #[test]
+    fn any_matching_subscription_can_keep_the_retain_flag() {
+        let mut subscriptions = Subscriptions::new();
+        let as_published = SubscriptionOptions {
+            retain_as_published: true,
+            ..SubscriptionOptions::new(QualityOfService::AtMostOnce)
+        };
+        subscriptions.subscribe("a".to_string(), &filter("t"), SubscriptionOptions::new(QualityOfService::AtLeastOnce));
+        subscriptions.subscribe("a".to_string(), &filter("+"), as_published);
+        subscriptions.subscribe("b".to_string(), &filter("t"), SubscriptionOptions::new(QualityOfService::AtLeastOnce));
+        let matched = subscriptions.matches(&TopicName::new("t").unwrap(), None::<&str>);
+        assert!(matched["a"].retain_as_published);
+        assert_eq!(matched["a"].qos, QualityOfService::AtLeastOnce);
+        assert!(!matched["b"].retain_as_published);
+    }
+
+    #[test]
+    fn no_local_subscriptions_skip_only_the_publisher() {
+        let mut subscriptions = Subscriptions::new();
+        let no_local = SubscriptionOptions{ no_local: true, ..SubscriptionOptions::new(QualityOfService::AtLeastOnce) };
+        subscriptions.subscribe("a".to_string(), &filter("t"), no_local);
+        subscriptions.subscribe("b".to_string(), &filter("t"), no_local);
+        assert_eq!(matches(&subscriptions, "t", Some("a")), vec![("b".to_string(), QualityOfService::AtLeastOnce)]);
+        assert_eq!(matches(&subscriptions, "t", None).len(), 2);
+        // A matching subscription without No Local still counts
+        subscriptions.subscribe("a".to_string(), &filter("#"), SubscriptionOptions::new(QualityOfService::AtMostOnce));
+        assert_eq!(matches(&subscriptions, "t", Some("a")), vec![
+            ("a".to_string(), QualityOfService::AtMostOnce),
+            ("b".to_string(), QualityOfService::AtLeastOnce)
+        ]);
+    }
+
+    #[test]
+    fn wildcards_match_level_by_level_but_not_system_topics() {
+        let mut subscriptions = Subscriptions::new();
+        for &(key, topic_filter) in &[("hash", "#"), ("plus", "+/b"), ("parent", "a/#"), ("sys", "$SYS/#")] {
+            subscriptions.subscribe(key.to_string(), &filter(topic_filter), SubscriptionOptions::new(QualityOfService::AtMostOnce));
+        }
+        let keys = |topic| -> Vec<String> { matches(&subscriptions, topic, None).into_iter().map(|(key, _)| { key }).collect() };
+        assert_eq!(keys("a"), vec!["hash", "parent"]);
+        assert_eq!(keys("a/b"), vec!["hash", "parent", "plus"]);
+        assert_eq!(keys("a/b/c"), vec!["hash", "parent"]);
+        assert_eq!(keys("$SYS/b"), vec!["sys"]);
+    }
+
+    #[test]
+    fn unsubscribing_leaves_no_empty_levels_behind() {
+        let mut subscriptions = Subscriptions::new();
+        let options = SubscriptionOptions::new(QualityOfService::AtMostOnce);
+        subscriptions.subscribe("a".to_string(), &filter("x/y/z"), options);
+        subscriptions.subscribe("a".to_string(), &filter("x"), options);
+        subscriptions.subscribe("b".to_string(), &filter("x/y/z"), options);
+
+        assert!(!subscriptions.unsubscribe(&"a".to_string(), &filter("x/y")));
+        assert!(subscriptions.unsubscribe(&"a".to_string(), &filter("x/y/z")));
+        assert!(!subscriptions.unsubscribe(&"a".to_string(), &filter("x/y/z")));
+        assert!(subscriptions.root.children["x"].children["y"].children.contains_key("z"));
+        assert!(subscriptions.unsubscribe(&"b".to_string(), &filter("x/y/z")));
+        assert!(subscriptions.root.children["x"].children.is_empty());
+        assert!(subscriptions.unsubscribe(&"a".to_string(), &filter("x")));
+        assert!(subscriptions.root.is_empty());
+        assert!(subscriptions.is_empty());
+    }
+}
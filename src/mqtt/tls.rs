@@ -0,0 +1,289 @@
+use futures::Future;
+use mqtt::*;
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient,
+    AllowAnyAuthenticatedClient,
+    Certificate,
+    NoClientAuth,
+    PrivateKey,
+    RootCertStore,
+    ServerConfig,
+    Session
+};
+use tokio_rustls::server::TlsStream;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+// What the name in a client's certificate is used as, in place of what its CONNECT says
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CertificateIdentity {
+    Username,
+    ClientId
+}
+
+// The certificate and key a TLS listener identifies itself with, all PEM files, and how it
+// treats its clients' certificates. Clients are only asked for a certificate if there's a CA to
+// check it against.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    pub ca_file: Option<PathBuf>,
+    // Refuses clients without a certificate the CA has signed, rather than leaving them to
+    // authenticate some other way
+    pub require_certificate: bool,
+    pub identity: Option<CertificateIdentity>
+}
+
+impl TlsConfig {
+    pub fn new<P: Into<PathBuf>>(cert_file: P, key_file: P) -> Self {
+        TlsConfig {
+            cert_file: cert_file.into(),
+            key_file: key_file.into(),
+            ca_file: None,
+            require_certificate: false,
+            identity: None
+        }
+    }
+}
+
+// Does the server's half of the TLS handshake on each connection a listener accepts
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    acceptor: ::tokio_rustls::TlsAcceptor,
+    identity: Option<CertificateIdentity>
+}
+
+impl TlsAcceptor {
+    pub fn new(config: &TlsConfig) -> Result<Self> {
+        let verifier = match config.ca_file {
+            Some(ref ca_file) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(ca_file)? {
+                    roots.add(&cert).map_err(|e| { invalid(ca_file, &format!("{:?}", e)) })?;
+                }
+                if config.require_certificate {
+                    AllowAnyAuthenticatedClient::new(roots)
+                } else {
+                    AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+                }
+            },
+            None if config.require_certificate =>
+                return Err(Error::new(ErrorKind::InvalidInput, "requiring client certificates needs a CA file")),
+            None => NoClientAuth::new()
+        };
+        let mut server_config = ServerConfig::new(verifier);
+        server_config
+            .set_single_cert(load_certs(&config.cert_file)?, load_key(&config.key_file)?)
+            .map_err(|e| { invalid(&config.key_file, &e.to_string()) })?;
+        Ok(TlsAcceptor {
+            acceptor: ::tokio_rustls::TlsAcceptor::from(Arc::new(server_config)),
+            identity: config.identity
+        })
+    }
+
+    // Finishes the handshake on `socket`, and says who the client is if its certificate is
+    // being used as its identity. Such a client has to have a certificate with a name in it, if
+    // it has one at all.
+    pub fn accept<S>(&self, socket: S) -> impl Future<Item = (TlsStream<S>, PeerCredentials), Error = Error> + Send
+    where S: AsyncRead + AsyncWrite + Send + 'static
+    {
+        let identity = self.identity;
+        self.acceptor.accept(socket).and_then(move |stream| {
+            let mut peer = PeerCredentials::default();
+            let certs = stream.get_ref().1.get_peer_certificates().unwrap_or_default();
+            if let (Some(identity), Some(cert)) = (identity, certs.first()) {
+                let name = certificate_name(cert).ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidData, "client certificate has no common name or subject alternative name")
+                })?;
+                match identity {
+                    CertificateIdentity::Username => peer.username = Some(name),
+                    CertificateIdentity::ClientId => peer.client_id = Some(name)
+                }
+            }
+            Ok((stream, peer))
+        })
+    }
+}
+
+// The subject's common name, or failing that its first DNS name or email address
+fn certificate_name(cert: &Certificate) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(&cert.0).ok()?;
+    let common_name = cert.subject()
+        .iter_common_name()
+        .filter_map(|common_name| { common_name.as_str().ok() })
+        .next();
+    if let Some(common_name) = common_name {
+        return Some(common_name.to_string())
+    }
+    let alt_names = cert.subject_alternative_name().ok()??;
+    alt_names.value.general_names.iter().filter_map(|name| {
+        match *name {
+            GeneralName::DNSName(name) | GeneralName::RFC822Name(name) => Some(name.to_string()),
+            _ => None
+        }
+    }).next()
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let certs = pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .map_err(|()| { invalid(path, "not a PEM file") })?;
+    if certs.is_empty() {
+        return Err(invalid(path, "no certificates found"))
+    }
+    Ok(certs)
+}
+
+// Takes a PKCS #8 key, or failing that an RSA one
+fn load_key(path: &Path) -> Result<PrivateKey> {
+    let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(path)?))
+        .map_err(|()| { invalid(path, "not a PEM file") })?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut BufReader::new(File::open(path)?))
+            .map_err(|()| { invalid(path, "not a PEM file") })?;
+    }
+    keys.into_iter().next().ok_or_else(|| { invalid(path, "no PKCS #8 or RSA private key found") })
+}
+
+fn invalid(path: &Path, problem: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), problem))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // Certificates made for these tests, good for a hundred years: a CA that signed the server's
+    // and two clients', one of which only has its name as a subject alternative name, and
+    // another CA that signed a third client's
+    fn testdata(name: &str) -> PathBuf {
+        [env!("CARGO_MANIFEST_DIR"), "testdata", "tls", name].iter().collect()
+    }
+
+    #[test]
+    fn names_come_from_the_common_name_then_the_subject_alternative_name() {
+        let alice = load_certs(&testdata("alice.pem")).unwrap();
+        assert_eq!(certificate_name(&alice[0]), Some("alice".to_string()));
+        let san_only = load_certs(&testdata("san-only.pem")).unwrap();
+        assert_eq!(certificate_name(&san_only[0]), Some("bob@example.com".to_string()));
+    }
+
+    // Handshakes are tested over Unix socket pairs, as there's no counting on TCP in a test
+    #[cfg(unix)]
+    mod handshakes {
+        use super::*;
+        use std::io::Write;
+        use std::os::unix::net;
+        use std::sync::mpsc;
+        use std::thread;
+        use std::time::Duration;
+        use tokio::net::UnixStream;
+        use tokio::reactor::Handle;
+        use tokio::runtime::current_thread::Runtime;
+        use tokio_rustls::rustls::{ClientConfig, ClientSession, ProtocolVersion as TlsVersion};
+        use tokio_rustls::webpki::DNSNameRef;
+        use tokio_rustls::TlsConnector;
+
+        fn config(require_certificate: bool, identity: Option<CertificateIdentity>) -> TlsConfig {
+            TlsConfig {
+                ca_file: Some(testdata("ca.pem")),
+                require_certificate,
+                identity,
+                ..TlsConfig::new(testdata("server.pem"), testdata("server.key"))
+            }
+        }
+
+        fn client_config(client: Option<&str>) -> ClientConfig {
+            let mut config = ClientConfig::new();
+            for cert in load_certs(&testdata("ca.pem")).unwrap() {
+                config.root_store.add(&cert).unwrap();
+            }
+            if let Some(client) = client {
+                let cert = load_certs(&testdata(&format!("{}.pem", client))).unwrap();
+                let key = load_key(&testdata(&format!("{}.key", client))).unwrap();
+                config.set_single_client_cert(cert, key);
+            }
+            config
+        }
+
+        // Both halves of a handshake over a socket pair, with `client`'s certificate if there is
+        // one. Returns what the server made of it.
+        fn handshake(config: &TlsConfig, client: Option<&str>) -> Result<PeerCredentials> {
+            let acceptor = TlsAcceptor::new(config).unwrap();
+            let connector = TlsConnector::from(Arc::new(client_config(client)));
+            let (client, server) = net::UnixStream::pair().unwrap();
+            let mut runtime = Runtime::new().unwrap();
+            let client = UnixStream::from_std(client, &Handle::default()).unwrap();
+            let server = UnixStream::from_std(server, &Handle::default()).unwrap();
+            let localhost = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+            let accepted = acceptor.accept(server).map(|(_, peer)| { peer }).then(Ok::<_, ()>);
+            let connected = connector.connect(localhost, client).then(Ok::<_, ()>);
+            let (accepted, _) = runtime.block_on(accepted.join(connected)).unwrap();
+            accepted
+        }
+
+        #[test]
+        fn clients_are_identified_by_their_certificates() {
+            let peer = handshake(&config(true, Some(CertificateIdentity::Username)), Some("alice")).unwrap();
+            assert_eq!((peer.username, peer.client_id), (Some("alice".to_string()), None));
+            let peer = handshake(&config(true, Some(CertificateIdentity::ClientId)), Some("san-only")).unwrap();
+            assert_eq!((peer.username, peer.client_id), (None, Some("bob@example.com".to_string())));
+            // Without an identity to use them as, certificates are only checked
+            let peer = handshake(&config(true, None), Some("alice")).unwrap();
+            assert_eq!((peer.username, peer.client_id), (None, None));
+        }
+
+        #[test]
+        fn untrusted_client_certificates_are_refused() {
+            for &require_certificate in &[true, false] {
+                let refused = handshake(&config(require_certificate, Some(CertificateIdentity::Username)), Some("rogue"));
+                assert_eq!(refused.unwrap_err().kind(), ErrorKind::InvalidData);
+            }
+        }
+
+        #[test]
+        fn clients_without_a_certificate_are_only_let_in_when_it_isnt_required() {
+            assert!(handshake(&config(true, None), None).is_err());
+            let peer = handshake(&config(false, Some(CertificateIdentity::Username)), None).unwrap();
+            assert_eq!((peer.username, peer.client_id), (None, None));
+        }
+
+        // https://rustsec.org/advisories/RUSTSEC-2024-0336
+        // A close_notify in the middle of the handshake leaves it waiting for the rest, which the
+        // connect timeout puts an end to, rather than spinning
+        #[test]
+        fn a_close_notify_during_the_handshake_doesnt_spin() {
+            let (mut client, server) = net::UnixStream::pair().unwrap();
+            // Over TLS 1.2, where the alert is still in the clear this early on
+            let mut tls_1_2 = client_config(None);
+            tls_1_2.versions = vec![TlsVersion::TLSv1_2];
+            let mut session = ClientSession::new(
+                &Arc::new(tls_1_2),
+                DNSNameRef::try_from_ascii_str("localhost").unwrap()
+            );
+            while session.wants_write() {
+                session.write_tls(&mut client).unwrap();
+            }
+            // A warning-level close_notify alert
+            client.write_all(&[0x15, 0x03, 0x03, 0x00, 0x02, 0x01, 0x00]).unwrap();
+
+            let (done, finished) = mpsc::channel();
+            thread::spawn(move || {
+                let acceptor = TlsAcceptor::new(&config(false, None)).unwrap();
+                let mut runtime = Runtime::new().unwrap();
+                let server = UnixStream::from_std(server, &Handle::default()).unwrap();
+                let result = runtime.block_on(handshake_timeout(acceptor.accept(server), Duration::from_millis(100)));
+                let _ = done.send(result.map(|_| ()));
+            });
+            let result = finished.recv_timeout(Duration::from_secs(5)).expect("the handshake never finished");
+            assert_eq!(result.unwrap_err().kind(), ErrorKind::TimedOut);
+            drop(client);
+        }
+    }
+}
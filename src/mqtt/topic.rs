@@ -0,0 +1,295 @@
+use mqtt::*;
+use std::borrow::Cow;
+use std::fmt;
+use std::ops::Deref;
+use std::result;
+use std::str::Split;
+
+// https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718106
+// A topic that can be published to: no wildcards, at least one character and at most 65535
+// bytes. Empty levels ("a//b") are allowed, and so is a leading '$', which marks a topic as
+// reserved for the server and hides it from wildcards at the first level.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct TopicName<'a>(Cow<'a, str>);
+
+// A topic a client can subscribe to: like a topic name, but a level may be a '+' to match any
+// one level, and the last level may be a '#' to match any number of levels, including none
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct TopicFilter<'a>(Cow<'a, str>);
+
+// https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc385349285
+// One of a SUBSCRIBE's topic filters. A filter that isn't valid is kept, rather than failing the
+// whole packet, so that it can be refused with its own SUBACK return code.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SubscribeFilter<'a> {
+    Valid(TopicFilter<'a>),
+    Invalid(Cow<'a, str>, ProtocolError)
+}
+
+pub type OwnedTopicName = TopicName<'static>;
+pub type OwnedTopicFilter = TopicFilter<'static>;
+pub type OwnedSubscribeFilter = SubscribeFilter<'static>;
+
+fn check_topic(topic: &str) -> result::Result<(), ProtocolError> {
+    if topic.is_empty() {
+        Err(ProtocolError::EmptyTopic)
+    } else if topic.len() > u16::MAX as usize {
+        Err(ProtocolError::TopicTooLong(topic.len()))
+    } else if topic.contains('\u{0}') {
+        Err(ProtocolError::NullCharacter)
+    } else {
+        Ok(())
+    }
+}
+
+impl<'a> TopicName<'a> {
+    pub fn new<T: Into<Cow<'a, str>>>(topic: T) -> result::Result<Self, ProtocolError> {
+        let topic = topic.into();
+        check_topic(&topic)?;
+        if topic.contains(['+', '#']) {
+            return Err(ProtocolError::WildcardInTopicName)
+        }
+        Ok(TopicName(topic))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn levels(&self) -> Split<'_, char> {
+        self.0.split('/')
+    }
+
+    pub fn is_system(&self) -> bool {
+        self.0.starts_with('$')
+    }
+
+    pub fn into_owned(self) -> OwnedTopicName {
+        TopicName(Cow::Owned(self.0.into_owned()))
+    }
+
+    pub fn borrowed(&self) -> TopicName<'_> {
+        TopicName(Cow::Borrowed(&self.0))
+    }
+}
+
+impl<'a> TopicFilter<'a> {
+    pub fn new<T: Into<Cow<'a, str>>>(filter: T) -> result::Result<Self, ProtocolError> {
+        let filter = filter.into();
+        check_topic(&filter)?;
+        let mut levels = filter.split('/').peekable();
+        while let Some(level) = levels.next() {
+            if level.contains('#') && (level != "#" || levels.peek().is_some()) {
+                return Err(ProtocolError::MisplacedMultiLevelWildcard)
+            }
+            if level.contains('+') && level != "+" {
+                return Err(ProtocolError::MisplacedSingleLevelWildcard)
+            }
+        }
+        Ok(TopicFilter(filter))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn levels(&self) -> Split<'_, char> {
+        self.0.split('/')
+    }
+
+    pub fn has_wildcards(&self) -> bool {
+        self.0.contains(['+', '#'])
+    }
+
+    // https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718107
+    // Walks both topics a level at a time, without allocating
+    pub fn matches(&self, name: &TopicName) -> bool {
+        if name.is_system() && self.0.starts_with(['+', '#']) {
+            return false
+        }
+        let mut filter_levels = self.levels();
+        let mut name_levels = name.levels();
+        loop {
+            match (filter_levels.next(), name_levels.next()) {
+                (Some("#"), _) => return true,
+                (Some("+"), Some(_)) => (),
+                (Some(filter_level), Some(name_level)) if filter_level == name_level => (),
+                (None, None) => return true,
+                _ => return false
+            }
+        }
+    }
+
+    pub fn into_owned(self) -> OwnedTopicFilter {
+        TopicFilter(Cow::Owned(self.0.into_owned()))
+    }
+
+    pub fn borrowed(&self) -> TopicFilter<'_> {
+        TopicFilter(Cow::Borrowed(&self.0))
+    }
+}
+
+impl<'a> SubscribeFilter<'a> {
+    pub fn new<T: Into<Cow<'a, str>>>(filter: T) -> Self {
+        let filter = filter.into();
+        match TopicFilter::new(filter.clone()) {
+            Ok(filter) => SubscribeFilter::Valid(filter),
+            Err(e) => SubscribeFilter::Invalid(filter, e)
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            SubscribeFilter::Valid(filter) => filter.as_str(),
+            SubscribeFilter::Invalid(filter, _) => filter
+        }
+    }
+
+    pub fn into_owned(self) -> OwnedSubscribeFilter {
+        match self {
+            SubscribeFilter::Valid(filter) => SubscribeFilter::Valid(filter.into_owned()),
+            SubscribeFilter::Invalid(filter, e) => SubscribeFilter::Invalid(Cow::Owned(filter.into_owned()), e)
+        }
+    }
+
+    pub fn borrowed(&self) -> SubscribeFilter<'_> {
+        match self {
+            SubscribeFilter::Valid(filter) => SubscribeFilter::Valid(filter.borrowed()),
+            SubscribeFilter::Invalid(filter, e) => SubscribeFilter::Invalid(Cow::Borrowed(filter), e.clone())
+        }
+    }
+}
+
+impl<'a> From<TopicFilter<'a>> for SubscribeFilter<'a> {
+    fn from(filter: TopicFilter<'a>) -> Self {
+        SubscribeFilter::Valid(filter)
+    }
+}
+
+impl<'a> Deref for TopicName<'a> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<'a> Deref for TopicFilter<'a> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<'a> fmt::Display for TopicName<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl<'a> Deref for SubscribeFilter<'a> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl<'a> fmt::Display for TopicFilter<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl<'a> fmt::Display for SubscribeFilter<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqtt::*;
+
+    fn matches(filter: &str, name: &str) -> bool {
+        TopicFilter::new(filter).unwrap().matches(&TopicName::new(name).unwrap())
+    }
+
+    #[test]
+    fn wildcards_must_be_whole_levels() {
+        assert_eq!(TopicFilter::new("a/#/b").unwrap_err(), ProtocolError::MisplacedMultiLevelWildcard);
+        assert_eq!(TopicFilter::new("a#").unwrap_err(), ProtocolError::MisplacedMultiLevelWildcard);
+        assert_eq!(TopicFilter::new("a+").unwrap_err(), ProtocolError::MisplacedSingleLevelWildcard);
+        assert_eq!(TopicFilter::new("a/+b/c").unwrap_err(), ProtocolError::MisplacedSingleLevelWildcard);
+        for filter in &["#", "+", "a/#", "+/+", "a/+/c", "+/#"] {
+            assert!(TopicFilter::new(*filter).is_ok(), "{} should be valid", filter);
+        }
+        assert_eq!(TopicName::new("a/+").unwrap_err(), ProtocolError::WildcardInTopicName);
+        assert_eq!(TopicName::new("a/#").unwrap_err(), ProtocolError::WildcardInTopicName);
+    }
+
+    #[test]
+    fn multi_level_wildcard_matches_its_parent_and_everything_under_it() {
+        assert!(matches("#", "a"));
+        assert!(matches("#", "a/b/c"));
+        assert!(matches("a/#", "a"));
+        assert!(matches("a/#", "a/b/c"));
+        assert!(!matches("a/#", "b/a"));
+    }
+
+    #[test]
+    fn single_level_wildcard_matches_exactly_one_level() {
+        assert!(matches("a/+", "a/b"));
+        assert!(!matches("a/+", "a"));
+        assert!(!matches("a/+", "a/b/c"));
+        assert!(matches("+/+", "/a"));
+    }
+
+    #[test]
+    fn empty_levels_are_levels() {
+        assert!(TopicName::new("a//b").is_ok());
+        assert!(TopicName::new("/").is_ok());
+        assert!(matches("a//b", "a//b"));
+        assert!(!matches("a/b", "a//b"));
+        assert!(matches("a/+/b", "a//b"));
+        assert!(matches("/+", "/"));
+        assert!(!matches("+", "/"));
+        assert!(matches("+/+", "/"));
+    }
+
+    #[test]
+    fn wildcards_at_the_first_level_skip_system_topics() {
+        assert!(!matches("#", "$SYS/broker/uptime"));
+        assert!(!matches("+/broker/uptime", "$SYS/broker/uptime"));
+        assert!(matches("$SYS/#", "$SYS/broker/uptime"));
+        assert!(matches("$SYS/+/uptime", "$SYS/broker/uptime"));
+        assert!(matches("a/#", "a/$b"));
+    }
+
+    #[test]
+    fn topics_are_between_1_and_65535_bytes() {
+        assert_eq!(TopicName::new("").unwrap_err(), ProtocolError::EmptyTopic);
+        assert_eq!(TopicFilter::new("").unwrap_err(), ProtocolError::EmptyTopic);
+        let longest = "a".repeat(65535);
+        assert!(TopicName::new(longest.as_str()).is_ok());
+        assert!(TopicFilter::new(longest.as_str()).is_ok());
+        let too_long = "a".repeat(65536);
+        assert_eq!(TopicName::new(too_long.as_str()).unwrap_err(), ProtocolError::TopicTooLong(65536));
+        assert_eq!(TopicFilter::new(too_long.as_str()).unwrap_err(), ProtocolError::TopicTooLong(65536));
+        assert_eq!(TopicName::new("a\u{0}").unwrap_err(), ProtocolError::NullCharacter);
+    }
+
+    #[test]
+    fn invalid_subscribe_filters_keep_their_text_and_reason() {
+        match SubscribeFilter::new("a/#/b") {
+            SubscribeFilter::Invalid(ref filter, ProtocolError::MisplacedMultiLevelWildcard) =>
+                assert_eq!(filter, "a/#/b"),
+            ref other => panic!("expected an invalid filter, got {:?}", other)
+        }
+        let invalid = SubscribeFilter::new("a+").into_owned();
+        assert_eq!(invalid.as_str(), "a+");
+        assert_eq!(invalid.to_string(), "a+");
+        assert_eq!(SubscribeFilter::new("a/+"), SubscribeFilter::Valid(TopicFilter::new("a/+").unwrap()));
+    }
+}
@@ -0,0 +1,99 @@
+use mqtt::*;
+use std::fs::{self, DirBuilder};
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use tokio::net::{UnixListener, UnixStream};
+
+// Listens on a Unix socket at `path`, replacing any socket a previous run left there, and gives
+// it `mode` permissions if there are any. Anything at `path` that isn't a socket is left alone.
+//
+// The socket is bound and given its permissions in a directory only this process can get into,
+// then moved to `path`, so no client can connect while it still has whatever the umask gave it.
+pub fn bind_unix<P: AsRef<Path>>(path: P, mode: Option<u32>) -> Result<UnixListener> {
+    let path = path.as_ref();
+    match fs::symlink_metadata(path) {
+        Ok(ref metadata) if metadata.file_type().is_socket() => (),
+        Ok(_) => return Err(Error::new(ErrorKind::AlreadyExists, format!("{} exists and isn't a socket", path.display()))),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+        Err(e) => return Err(e)
+    }
+    let mut private_dir = path.as_os_str().to_owned();
+    private_dir.push(format!(".{}.bind", process::id()));
+    let private_dir = PathBuf::from(private_dir);
+    DirBuilder::new().mode(0o700).create(&private_dir)?;
+    let private_path = private_dir.join("socket");
+    let bound = UnixListener::bind(&private_path).and_then(|listener| {
+        if let Some(mode) = mode {
+            fs::set_permissions(&private_path, fs::Permissions::from_mode(mode))?;
+        }
+        // Replaces a socket left at `path` in one step
+        fs::rename(&private_path, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&private_path);
+    fs::remove_dir(&private_dir)?;
+    bound
+}
+
+// The user and group of the process that connected to `socket` [SO_PEERCRED], which can't be
+// faked by the process itself
+pub fn unix_peer_credentials(socket: &UnixStream) -> Result<PeerCredentials> {
+    let ucred = socket.peer_cred()?;
+    Ok(PeerCredentials{ uid: Some(ucred.uid), gid: Some(ucred.gid), ..PeerCredentials::default() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{future, Future};
+    use std::env;
+    use std::os::unix::net;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("mqtt-unix-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn binds_with_the_given_mode_and_replaces_a_stale_socket() {
+        let dir = scratch_dir("bind");
+        let path = dir.join("mqtt.sock");
+        drop(net::UnixListener::bind(&path).unwrap());
+
+        let _listener = bind_unix(&path, Some(0o600)).unwrap();
+        let metadata = fs::symlink_metadata(&path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        // Only the socket is left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn leaves_anything_but_a_socket_alone() {
+        let dir = scratch_dir("file");
+        let path = dir.join("mqtt.sock");
+        fs::write(&path, b"not a socket").unwrap();
+        assert_eq!(bind_unix(&path, None).unwrap_err().kind(), ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&path).unwrap(), b"not a socket");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reads_the_peer_credentials_of_a_connection() {
+        let dir = scratch_dir("peer");
+        let path = dir.join("mqtt.sock");
+        let listener = bind_unix(&path, None).unwrap();
+        let _client = net::UnixStream::connect(&path).unwrap();
+        let (socket, _) = future::poll_fn(|| { listener.poll_accept() }).wait().unwrap();
+        let peer = unix_peer_credentials(&socket).unwrap();
+        let metadata = fs::metadata("/proc/self").unwrap();
+        assert_eq!(peer.uid, Some(::std::os::unix::fs::MetadataExt::uid(&metadata)));
+        assert!(peer.gid.is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
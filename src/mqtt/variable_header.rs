@@ -1,64 +1,323 @@
-use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::borrow::Cow;
+use std::io::{Result, Write};
 use mqtt::*;
 
 pub type PacketId = u16;
 
-#[derive(Copy, Clone)]
+// Properties only exist on MQTT 5 connections, and are ignored when encoding for MQTT 3.1.1.
+// Likewise reason codes on acknowledgements, DISCONNECT and AUTH. Like `Payload`, it isn't
+// `Serde`, since its shape depends on the fixed header.
+#[derive(Clone)]
 pub enum VariableHeader<'a> {
     Connect {
+        protocol_version: ProtocolVersion,
         username: bool,
         password: bool,
         will_retain: bool,
         will_qos: QualityOfService,
         will_flag: bool,
         clean_session: bool,
-        keep_alive: u16
+        keep_alive: u16,
+        properties: Cow<'a, [Property<'a>]>
     },
     Connack {
         session_present: bool,
-        return_code: ConnackReturnCode
+        return_code: ConnackReturnCode,
+        properties: Cow<'a, [Property<'a>]>
     },
     Publish {
-        topic_name: &'a str,
-        packet_id: Option<PacketId>
+        topic_name: TopicName<'a>,
+        packet_id: Option<PacketId>,
+        properties: Cow<'a, [Property<'a>]>
     },
-    Puback(PacketId),
-    Pubrec(PacketId),
-    Pubrel(PacketId),
-    Pubcomp(PacketId),
-    Subscribe(PacketId),
-    Suback(PacketId),
-    Unsubscribe(PacketId),
-    Unsuback(PacketId),
+    Puback(PacketId, ReasonCode, Cow<'a, [Property<'a>]>),
+    Pubrec(PacketId, ReasonCode, Cow<'a, [Property<'a>]>),
+    Pubrel(PacketId, ReasonCode, Cow<'a, [Property<'a>]>),
+    Pubcomp(PacketId, ReasonCode, Cow<'a, [Property<'a>]>),
+    Subscribe(PacketId, Cow<'a, [Property<'a>]>),
+    Suback(PacketId, Cow<'a, [Property<'a>]>),
+    Unsubscribe(PacketId, Cow<'a, [Property<'a>]>),
+    Unsuback(PacketId, Cow<'a, [Property<'a>]>),
+    Disconnect(ReasonCode, Cow<'a, [Property<'a>]>),
+    Auth(ReasonCode, Cow<'a, [Property<'a>]>)
 }
 
 impl<'a> VariableHeader<'a> {
-    pub fn len(&self) -> u32 {
+    pub fn len(&self, version: ProtocolVersion) -> u32 {
+        let properties_len = |properties: &[Property]| {
+            match version {
+                ProtocolVersion::V31 | ProtocolVersion::V311 => 0,
+                ProtocolVersion::V5 => properties_size(properties)
+            }
+        };
+        let len = match self {
+            VariableHeader::Connect{ protocol_version, properties, .. } =>
+                2 + protocol_version.name().len() + 4 + properties_len(properties),
+            VariableHeader::Connack{ properties, .. } =>
+                2 + properties_len(properties),
+            VariableHeader::Publish{ topic_name, packet_id, properties } =>
+                2 + topic_name.len() + packet_id.map_or(0, |_| { 2 }) + properties_len(properties),
+            VariableHeader::Puback(_, reason_code, properties) |
+            VariableHeader::Pubrec(_, reason_code, properties) |
+            VariableHeader::Pubrel(_, reason_code, properties) |
+            VariableHeader::Pubcomp(_, reason_code, properties) =>
+                2 + VariableHeader::reason_len(version, *reason_code, properties),
+            VariableHeader::Subscribe(_, properties) |
+            VariableHeader::Suback(_, properties) |
+            VariableHeader::Unsubscribe(_, properties) |
+            VariableHeader::Unsuback(_, properties) =>
+                2 + properties_len(properties),
+            VariableHeader::Disconnect(reason_code, properties) |
+            VariableHeader::Auth(reason_code, properties) =>
+                VariableHeader::reason_len(version, *reason_code, properties)
+        };
+        len as u32
+    }
+
+    pub fn is_empty(&self, version: ProtocolVersion) -> bool {
+        self.len(version) == 0
+    }
+
+    // MQTT 5 lets a successful reason code with no properties be left out altogether, and
+    // properties be left out after any reason code
+    fn reason_len(version: ProtocolVersion, reason_code: ReasonCode, properties: &[Property]) -> usize {
+        match version {
+            ProtocolVersion::V31 | ProtocolVersion::V311 => 0,
+            ProtocolVersion::V5 if properties.is_empty() && reason_code == ReasonCode::Success => 0,
+            ProtocolVersion::V5 if properties.is_empty() => 1,
+            ProtocolVersion::V5 => 1 + properties_size(properties)
+        }
+    }
+
+    fn ser_reason(
+        sink: &mut dyn Write,
+        version: ProtocolVersion,
+        reason_code: ReasonCode,
+        properties: &[Property]
+    ) -> Result<usize> {
+        match VariableHeader::reason_len(version, reason_code, properties) {
+            0 => Ok(0),
+            1 => write_u8(sink, reason_code.to_byte()),
+            _ => Ok(write_u8(sink, reason_code.to_byte())? + write_properties(sink, properties)?)
+        }
+    }
+
+    fn ser_properties(sink: &mut dyn Write, version: ProtocolVersion, properties: &[Property]) -> Result<usize> {
+        match version {
+            ProtocolVersion::V31 | ProtocolVersion::V311 => Ok(0),
+            ProtocolVersion::V5 => write_properties(sink, properties)
+        }
+    }
+
+    pub fn ser_with(&self, version: ProtocolVersion, sink: &mut dyn Write) -> Result<usize> {
         match self {
-            VariableHeader::Connect{
-                username:_,
-                password: _,
-                will_retain: _,
-                will_qos: _,
-                will_flag: _,
-                clean_session: _,
-                keep_alive: _
-            } => 10u32,
-            VariableHeader::Publish{ topic_name, packet_id: Some(_) } =>
-                (topic_name.len() + 2) as u32,
-            VariableHeader::Publish{ topic_name, packet_id: None } =>
-                topic_name.len() as u32,
-            _ => 2u32
+            VariableHeader::Connect {
+                protocol_version,
+                username,
+                password,
+                will_retain,
+                will_qos,
+                will_flag,
+                clean_session,
+                keep_alive,
+                properties
+            } => {
+                let (will_qos_high, will_qos_low) = will_qos.bits();
+                let flags = [*username, *password, *will_retain, will_qos_high, will_qos_low, *will_flag, *clean_session, false]
+                    .iter()
+                    .fold(0u8, |acc, bit| { (acc << 1) | (*bit as u8) });
+                let mut written = write_string(sink, protocol_version.name())?;
+                written += write_u8(sink, protocol_version.level())?;
+                written += write_u8(sink, flags)?;
+                written += write_u16(sink, *keep_alive)?;
+                written += VariableHeader::ser_properties(sink, *protocol_version, properties)?;
+                Ok(written)
+            },
+            VariableHeader::Connack { session_present, return_code, properties } => {
+                // MQTT 3.1 has no session present flag, the whole byte is reserved
+                let session_present = *session_present && version != ProtocolVersion::V31;
+                let mut written = write_u8(sink, session_present as u8)?;
+                written += write_u8(sink, return_code.to_byte(version)?)?;
+                written += VariableHeader::ser_properties(sink, version, properties)?;
+                Ok(written)
+            },
+            VariableHeader::Publish { topic_name, packet_id, properties } => {
+                let mut written = write_string(sink, topic_name)?;
+                written += packet_id.map_or(Ok(0), |id| { write_u16(sink, id) })?;
+                written += VariableHeader::ser_properties(sink, version, properties)?;
+                Ok(written)
+            },
+            VariableHeader::Puback(packet_id, reason_code, properties) |
+            VariableHeader::Pubrec(packet_id, reason_code, properties) |
+            VariableHeader::Pubrel(packet_id, reason_code, properties) |
+            VariableHeader::Pubcomp(packet_id, reason_code, properties) => {
+                let written = write_u16(sink, *packet_id)?;
+                Ok(written + VariableHeader::ser_reason(sink, version, *reason_code, properties)?)
+            },
+            VariableHeader::Subscribe(packet_id, properties) |
+            VariableHeader::Suback(packet_id, properties) |
+            VariableHeader::Unsubscribe(packet_id, properties) |
+            VariableHeader::Unsuback(packet_id, properties) => {
+                let written = write_u16(sink, *packet_id)?;
+                Ok(written + VariableHeader::ser_properties(sink, version, properties)?)
+            },
+            VariableHeader::Disconnect(reason_code, properties) |
+            VariableHeader::Auth(reason_code, properties) =>
+                VariableHeader::ser_reason(sink, version, *reason_code, properties)
         }
     }
-}
 
-impl<'a> Serde for VariableHeader<'a> {
-    fn ser(&self, sink: &mut Write) -> Result<usize> {
-        Err(Error::new(ErrorKind::Other, "not implemented"))
+    // Decodes the variable header of the packet described by `header`, if it has one.
+    // `source` must already be bounded by the fixed header's remaining length. A CONNECT
+    // announces its own protocol version, every other packet is decoded as `version`.
+    pub(crate) fn de_with(
+        header: &FixedHeader,
+        version: ProtocolVersion,
+        source: &mut dyn Source<'a>
+    ) -> Result<(Option<Self>, usize)> {
+        let remaining_length: u32 = header.remaining_length.into();
+        match (header.control_packet_type, version) {
+            (ControlPacketType::Connect, _) => {
+                let (protocol_name, mut read) = read_string(source)?;
+                let (level, level_size) = read_u8(source)?;
+                let protocol_version = ProtocolVersion::from_name_and_level(&protocol_name, level)?;
+                let (flags, flags_size) = read_u8(source)?;
+                let (keep_alive, keep_alive_size) = read_u16(source)?;
+                VariableHeader::check_connect_flags(protocol_version, flags)?;
+                read += level_size + flags_size + keep_alive_size;
+                let (properties, properties_size) = VariableHeader::de_properties(source, protocol_version)?;
+                read += properties_size;
+                let connect = VariableHeader::Connect {
+                    protocol_version,
+                    username: flags & 0b1000_0000 != 0,
+                    password: flags & 0b0100_0000 != 0,
+                    will_retain: flags & 0b0010_0000 != 0,
+                    will_qos: QualityOfService::from_bits(flags & 0b0001_0000 != 0, flags & 0b0000_1000 != 0)?,
+                    will_flag: flags & 0b0000_0100 != 0,
+                    clean_session: flags & 0b0000_0010 != 0,
+                    keep_alive,
+                    properties
+                };
+                Ok((Some(connect), read))
+            },
+            (ControlPacketType::Connack, _) => {
+                let (flags, flags_size) = read_u8(source)?;
+                let (code, code_size) = read_u8(source)?;
+                let (properties, properties_size) = VariableHeader::de_properties(source, version)?;
+                let connack = VariableHeader::Connack {
+                    session_present: flags & 1 != 0 && version != ProtocolVersion::V31,
+                    return_code: ConnackReturnCode::from_byte(version, code)?,
+                    properties
+                };
+                Ok((Some(connack), flags_size + code_size + properties_size))
+            },
+            (ControlPacketType::Publish, _) => {
+                let (topic_name, mut read) = read_string(source)?;
+                let qos = QualityOfService::from_bits(header.flags[1], header.flags[2])?;
+                let packet_id = if qos == QualityOfService::AtMostOnce {
+                    None
+                } else {
+                    let (packet_id, packet_id_size) = read_u16(source)?;
+                    read += packet_id_size;
+                    Some(packet_id)
+                };
+                let (properties, properties_size) = VariableHeader::de_properties(source, version)?;
+                read += properties_size;
+                let topic_name = TopicName::new(topic_name)?;
+                Ok((Some(VariableHeader::Publish { topic_name, packet_id, properties }), read))
+            },
+            (ControlPacketType::Puback, _) =>
+                VariableHeader::de_ack(source, version, remaining_length, VariableHeader::Puback),
+            (ControlPacketType::Pubrec, _) =>
+                VariableHeader::de_ack(source, version, remaining_length, VariableHeader::Pubrec),
+            (ControlPacketType::Pubrel, _) =>
+                VariableHeader::de_ack(source, version, remaining_length, VariableHeader::Pubrel),
+            (ControlPacketType::Pubcomp, _) =>
+                VariableHeader::de_ack(source, version, remaining_length, VariableHeader::Pubcomp),
+            (ControlPacketType::Subscribe, _) =>
+                VariableHeader::de_packet_id(source, version, VariableHeader::Subscribe),
+            (ControlPacketType::Suback, _) =>
+                VariableHeader::de_packet_id(source, version, VariableHeader::Suback),
+            (ControlPacketType::Unsubscribe, _) =>
+                VariableHeader::de_packet_id(source, version, VariableHeader::Unsubscribe),
+            (ControlPacketType::Unsuback, _) =>
+                VariableHeader::de_packet_id(source, version, VariableHeader::Unsuback),
+            (ControlPacketType::Disconnect, ProtocolVersion::V5) => {
+                let (reason_code, properties, read) = VariableHeader::de_reason(source, remaining_length)?;
+                Ok((Some(VariableHeader::Disconnect(reason_code, properties)), read))
+            },
+            (ControlPacketType::Auth, ProtocolVersion::V5) => {
+                let (reason_code, properties, read) = VariableHeader::de_reason(source, remaining_length)?;
+                Ok((Some(VariableHeader::Auth(reason_code, properties)), read))
+            },
+            _ => Ok((None, 0))
+        }
+    }
+
+    // https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc385349229
+    fn check_connect_flags(protocol_version: ProtocolVersion, flags: u8) -> Result<()> {
+        let will_flag = flags & 0b0000_0100 != 0;
+        if flags & 0b0000_0001 != 0 {
+            Err(ProtocolError::ReservedConnectFlag.into())
+        } else if !will_flag && flags & 0b0001_1000 != 0 {
+            Err(ProtocolError::WillQosWithoutWill.into())
+        } else if flags & 0b0001_1000 == 0b0001_1000 {
+            Err(ProtocolError::InvalidWillQos.into())
+        } else if !will_flag && flags & 0b0010_0000 != 0 {
+            Err(ProtocolError::WillRetainWithoutWill.into())
+        } else if protocol_version != ProtocolVersion::V5 && flags & 0b1100_0000 == 0b0100_0000 {
+            Err(ProtocolError::PasswordWithoutUsername.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn de_properties(source: &mut dyn Source<'a>, version: ProtocolVersion) -> Result<(Cow<'a, [Property<'a>]>, usize)> {
+        match version {
+            ProtocolVersion::V31 | ProtocolVersion::V311 => Ok((Cow::Borrowed(&[]), 0)),
+            ProtocolVersion::V5 => read_properties(source)
+        }
+    }
+
+    // An optional reason code followed by optional properties, as `remaining` allows
+    fn de_reason(source: &mut dyn Source<'a>, remaining: u32) -> Result<(ReasonCode, Cow<'a, [Property<'a>]>, usize)> {
+        if remaining == 0 {
+            return Ok((ReasonCode::Success, Cow::Borrowed(&[]), 0))
+        }
+        let (code, code_size) = read_u8(source)?;
+        let reason_code = ReasonCode::from_byte(code)?;
+        if remaining == 1 {
+            return Ok((reason_code, Cow::Borrowed(&[]), code_size))
+        }
+        let (properties, properties_size) = read_properties(source)?;
+        Ok((reason_code, properties, code_size + properties_size))
+    }
+
+    fn de_ack(
+        source: &mut dyn Source<'a>,
+        version: ProtocolVersion,
+        remaining_length: u32,
+        variant: fn(PacketId, ReasonCode, Cow<'a, [Property<'a>]>) -> VariableHeader<'a>
+    ) -> Result<(Option<Self>, usize)> {
+        let (packet_id, read) = read_u16(source)?;
+        match version {
+            ProtocolVersion::V31 | ProtocolVersion::V311 =>
+                Ok((Some(variant(packet_id, ReasonCode::Success, Cow::Borrowed(&[]))), read)),
+            ProtocolVersion::V5 => {
+                let (reason_code, properties, reason_size) =
+                    VariableHeader::de_reason(source, remaining_length.saturating_sub(2))?;
+                Ok((Some(variant(packet_id, reason_code, properties)), read + reason_size))
+            }
+        }
     }
 
-    fn de(source: &mut Read) -> Result<(Self, usize)> {
-        Err(Error::new(ErrorKind::Other, "not implemented"))
+    fn de_packet_id(
+        source: &mut dyn Source<'a>,
+        version: ProtocolVersion,
+        variant: fn(PacketId, Cow<'a, [Property<'a>]>) -> VariableHeader<'a>
+    ) -> Result<(Option<Self>, usize)> {
+        let (packet_id, read) = read_u16(source)?;
+        let (properties, properties_size) = VariableHeader::de_properties(source, version)?;
+        Ok((Some(variant(packet_id, properties)), read + properties_size))
     }
 }
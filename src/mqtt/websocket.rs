@@ -0,0 +1,597 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use bytes::{BufMut, BytesMut};
+use futures::{Async, Future, Poll};
+use sha1::{Digest, Sha1};
+use std::cmp;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::mem;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+// https://tools.ietf.org/html/rfc6455#section-1.3
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718127
+// The subprotocol MQTT is carried in [MQTT-6.0.0-3], and the one older clients ask for
+const SUBPROTOCOLS: [&str; 2] = ["mqtt", "mqttv3.1"];
+
+// Anything longer than this isn't a WebSocket handshake we want to read
+const MAX_REQUEST_LEN: usize = 8192;
+
+// https://tools.ietf.org/html/rfc6455#section-5.2
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+// https://tools.ietf.org/html/rfc6455#section-7.4.1
+const NORMAL_CLOSURE: u16 = 1000;
+
+// Answers the HTTP upgrade a WebSocket client starts with, then carries MQTT in binary frames
+// over `socket`. Resolves to a stream that `handle_connection` can serve like any socket.
+pub fn accept_websocket<S>(socket: S) -> WebSocketAccept<S>
+where S: AsyncRead + AsyncWrite
+{
+    WebSocketAccept{ state: Handshake::Reading{ socket, request: BytesMut::new() } }
+}
+
+pub struct WebSocketAccept<S> {
+    state: Handshake<S>
+}
+
+enum Handshake<S> {
+    Reading { socket: S, request: BytesMut },
+    // Whatever the client sent after its request is kept for the stream, unless it's being
+    // refused
+    Responding { socket: S, response: Vec<u8>, written: usize, outcome: Result<BytesMut> },
+    Done
+}
+
+impl<S> Future for WebSocketAccept<S>
+where S: AsyncRead + AsyncWrite
+{
+    type Item = WebSocketStream<S>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<WebSocketStream<S>, Error> {
+        loop {
+            match mem::replace(&mut self.state, Handshake::Done) {
+                Handshake::Reading{ mut socket, mut request } => {
+                    if let Some(end) = request.windows(4).position(|window| { window == b"\r\n\r\n" }) {
+                        let received = request.split_off(end + 4);
+                        let (response, outcome) = match upgrade(&request) {
+                            Ok(response) => (response, Ok(received)),
+                            Err((response, e)) => (response, Err(e))
+                        };
+                        self.state = Handshake::Responding{ socket, response, written: 0, outcome };
+                        continue
+                    }
+                    if request.len() >= MAX_REQUEST_LEN {
+                        let e = Error::new(ErrorKind::InvalidData, "WebSocket handshake request too long");
+                        let response = refusal("431 Request Header Fields Too Large", "");
+                        self.state = Handshake::Responding{ socket, response, written: 0, outcome: Err(e) };
+                        continue
+                    }
+                    let mut chunk = [0u8; 1024];
+                    match socket.poll_read(&mut chunk) {
+                        Ok(Async::Ready(0)) =>
+                            return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed during WebSocket handshake")),
+                        Ok(Async::Ready(n)) => request.extend_from_slice(&chunk[..n]),
+                        Ok(Async::NotReady) => {
+                            self.state = Handshake::Reading{ socket, request };
+                            return Ok(Async::NotReady)
+                        },
+                        Err(e) => return Err(e)
+                    }
+                    self.state = Handshake::Reading{ socket, request };
+                },
+                Handshake::Responding{ mut socket, response, mut written, outcome } => {
+                    while written < response.len() {
+                        match socket.poll_write(&response[written..]) {
+                            Ok(Async::Ready(0)) => return Err(Error::new(ErrorKind::WriteZero, "couldn't write WebSocket handshake")),
+                            Ok(Async::Ready(n)) => written += n,
+                            Ok(Async::NotReady) => {
+                                self.state = Handshake::Responding{ socket, response, written, outcome };
+                                return Ok(Async::NotReady)
+                            },
+                            Err(e) => return Err(e)
+                        }
+                    }
+                    if let Async::NotReady = socket.poll_flush()? {
+                        self.state = Handshake::Responding{ socket, response, written, outcome };
+                        return Ok(Async::NotReady)
+                    }
+                    return outcome.map(|received| { Async::Ready(WebSocketStream::new(socket, received)) })
+                },
+                Handshake::Done => panic!("WebSocketAccept polled after it resolved")
+            }
+        }
+    }
+}
+
+// https://tools.ietf.org/html/rfc6455#section-4.2.1
+// The 101 response accepting `request`, or the response refusing it and why
+fn upgrade(request: &[u8]) -> ::std::result::Result<Vec<u8>, (Vec<u8>, Error)> {
+    let bad_request = |problem: &str| {
+        (refusal("400 Bad Request", ""), Error::new(ErrorKind::InvalidData, problem.to_string()))
+    };
+    let request = ::std::str::from_utf8(request).map_err(|_| { bad_request("WebSocket handshake isn't text") })?;
+    let mut lines = request.split("\r\n");
+    let request_line = lines.next().unwrap_or("");
+    if !request_line.starts_with("GET ") || !request_line.ends_with(" HTTP/1.1") {
+        return Err(bad_request("WebSocket handshake must be an HTTP/1.1 GET"))
+    }
+    let headers: Vec<(String, &str)> = lines
+        .filter_map(|line| {
+            let colon = line.find(':')?;
+            Some((line[..colon].trim().to_ascii_lowercase(), line[colon + 1..].trim()))
+        })
+        .collect();
+    // Headers that can be given more than once, or as a comma separated list, are both
+    let values = |name: &str| -> Vec<&str> {
+        headers
+            .iter()
+            .filter(|&(header, _)| { header == name })
+            .flat_map(|&(_, value)| { value.split(',') })
+            .map(|value| { value.trim() })
+            .collect()
+    };
+    if !values("upgrade").iter().any(|value| { value.eq_ignore_ascii_case("websocket") }) {
+        return Err(bad_request("WebSocket handshake without Upgrade: websocket"))
+    }
+    if !values("connection").iter().any(|value| { value.eq_ignore_ascii_case("upgrade") }) {
+        return Err(bad_request("WebSocket handshake without Connection: Upgrade"))
+    }
+    if values("sec-websocket-version") != ["13"] {
+        return Err((
+            refusal("426 Upgrade Required", "Sec-WebSocket-Version: 13\r\n"),
+            Error::new(ErrorKind::InvalidData, "unsupported WebSocket version")
+        ))
+    }
+    let key = match values("sec-websocket-key").as_slice() {
+        [key] if !key.is_empty() => key.to_string(),
+        _ => return Err(bad_request("WebSocket handshake without a Sec-WebSocket-Key"))
+    };
+    let requested = values("sec-websocket-protocol");
+    let protocol = match SUBPROTOCOLS.iter().find(|protocol| { requested.contains(protocol) }) {
+        Some(protocol) => protocol,
+        None => return Err(bad_request("WebSocket handshake didn't ask for the mqtt subprotocol [MQTT-6.0.0-3]"))
+    };
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(ACCEPT_GUID.as_bytes());
+    Ok(format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\
+         Sec-WebSocket-Protocol: {}\r\n\r\n",
+        BASE64.encode(hasher.finalize()),
+        protocol
+    ).into_bytes())
+}
+
+fn refusal(status: &str, headers: &str) -> Vec<u8> {
+    format!("HTTP/1.1 {}\r\n{}Connection: close\r\nContent-Length: 0\r\n\r\n", status, headers).into_bytes()
+}
+
+// https://tools.ietf.org/html/rfc6455#section-5
+// The bytes of the binary frames a WebSocket client sends, one after another with no regard for
+// where the frames start and end, and whatever is written sent back as binary frames. Pings are
+// answered, and a close is answered and then read as the end of the stream.
+//
+// A frame can carry any number of packets, so binary frames are unmasked as they arrive rather
+// than once they're whole: however long a frame says it is, no more than a read's worth of it
+// is held here at once. How long a packet can be is left to the codec reading the stream.
+pub struct WebSocketStream<S> {
+    socket: S,
+    // Read from the socket but not yet unmasked
+    received: BytesMut,
+    // Payload of binary frames, waiting to be read
+    payload: BytesMut,
+    // Frames waiting to be written to the socket
+    sending: BytesMut,
+    // The binary or continuation frame whose payload is still arriving
+    incoming: Option<Incoming>,
+    // Whether the last binary frame left its message unfinished, so continuation frames follow
+    fragmented: bool,
+    close_sent: bool,
+    close_received: bool
+}
+
+struct Incoming {
+    fin: bool,
+    remaining: u64,
+    mask: [u8; 4],
+    // How far into the payload the frame is, which decides the byte of the mask to use next
+    offset: usize
+}
+
+impl<S> WebSocketStream<S>
+where S: AsyncRead + AsyncWrite
+{
+    fn new(socket: S, received: BytesMut) -> Self {
+        WebSocketStream {
+            socket,
+            received,
+            payload: BytesMut::new(),
+            sending: BytesMut::new(),
+            incoming: None,
+            fragmented: false,
+            close_sent: false,
+            close_received: false
+        }
+    }
+
+    // Unmasks whatever has been received of the binary frame in progress, if there is one
+    fn read_incoming(&mut self, mut incoming: Incoming) -> bool {
+        let n = cmp::min(incoming.remaining, self.received.len() as u64) as usize;
+        let mut data = self.received.split_to(n);
+        for byte in data.iter_mut() {
+            *byte ^= incoming.mask[incoming.offset % 4];
+            incoming.offset += 1;
+        }
+        self.payload.extend_from_slice(&data);
+        incoming.remaining -= n as u64;
+        if incoming.remaining == 0 {
+            self.fragmented = !incoming.fin;
+            true
+        } else {
+            self.incoming = Some(incoming);
+            n > 0
+        }
+    }
+
+    // https://tools.ietf.org/html/rfc6455#section-5.2
+    // Takes what it can out of what's been received: the payload of the binary frame in
+    // progress, or else the header of the next frame and, if it's a control frame, the rest
+    // of it. Says whether there was anything to take.
+    fn read_frame(&mut self) -> Result<bool> {
+        if let Some(incoming) = self.incoming.take() {
+            return Ok(self.read_incoming(incoming))
+        }
+        if self.received.len() < 2 {
+            return Ok(false)
+        }
+        let invalid = |problem: &str| { Error::new(ErrorKind::InvalidData, problem.to_string()) };
+        let fin = self.received[0] & 0x80 != 0;
+        let opcode = self.received[0] & 0x0F;
+        if self.received[0] & 0x70 != 0 {
+            return Err(invalid("WebSocket frame uses an extension that wasn't negotiated"))
+        }
+        // https://tools.ietf.org/html/rfc6455#section-5.1
+        if self.received[1] & 0x80 == 0 {
+            return Err(invalid("WebSocket frames from a client must be masked"))
+        }
+        let (len, len_size) = match self.received[1] & 0x7F {
+            126 if self.received.len() >= 4 =>
+                (u64::from(self.received[2]) << 8 | u64::from(self.received[3]), 2),
+            127 if self.received.len() >= 10 =>
+                (self.received[2..10].iter().fold(0u64, |len, &byte| { len << 8 | u64::from(byte) }), 8),
+            126 | 127 => return Ok(false),
+            len => (u64::from(len), 0)
+        };
+        if len >> 63 != 0 {
+            return Err(invalid("WebSocket frame length has its most significant bit set"))
+        }
+        let header_len = 2 + len_size + 4;
+        if self.received.len() < header_len {
+            return Ok(false)
+        }
+        let mut mask = [0u8; 4];
+        mask.copy_from_slice(&self.received[header_len - 4..header_len]);
+        match opcode {
+            BINARY if !self.fragmented => (),
+            CONTINUATION if self.fragmented => (),
+            // https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718127
+            TEXT => return Err(invalid("MQTT has to be sent in binary WebSocket frames [MQTT-6.0.0-1]")),
+            CLOSE | PING | PONG => {
+                return self.read_control_frame(opcode, fin, len, header_len, mask)
+            },
+            _ => return Err(invalid("unexpected WebSocket frame"))
+        }
+        self.received.split_to(header_len);
+        self.read_incoming(Incoming{ fin, remaining: len, mask, offset: 0 });
+        Ok(true)
+    }
+
+    // https://tools.ietf.org/html/rfc6455#section-5.5
+    // Control frames are short enough to wait for in full
+    fn read_control_frame(&mut self, opcode: u8, fin: bool, len: u64, header_len: usize, mask: [u8; 4]) -> Result<bool> {
+        if !fin || len > 125 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "WebSocket control frames can't be fragmented or longer than 125 bytes"
+            ))
+        }
+        let frame_len = header_len + len as usize;
+        if self.received.len() < frame_len {
+            return Ok(false)
+        }
+        let mut payload = self.received.split_to(frame_len).split_off(header_len);
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        match opcode {
+            // https://tools.ietf.org/html/rfc6455#section-5.5.1
+            CLOSE => {
+                self.close_received = true;
+                if !self.close_sent {
+                    let status = &payload[..cmp::min(payload.len(), 2)];
+                    self.queue(CLOSE, status);
+                    self.close_sent = true;
+                    self.try_send_queued()?;
+                }
+            },
+            // https://tools.ietf.org/html/rfc6455#section-5.5.2
+            PING => {
+                self.queue(PONG, &payload);
+                self.try_send_queued()?;
+            },
+            // A pong needs no answer
+            _ => ()
+        }
+        Ok(true)
+    }
+
+    // Frames `payload` as a single, unmasked, frame [RFC 6455 5.1]
+    fn queue(&mut self, opcode: u8, payload: &[u8]) {
+        self.sending.reserve(10 + payload.len());
+        self.sending.put_u8(0x80 | opcode);
+        match payload.len() {
+            len if len < 126 => self.sending.put_u8(len as u8),
+            len if len <= 0xFFFF => {
+                self.sending.put_u8(126);
+                self.sending.put_u16_be(len as u16);
+            },
+            len => {
+                self.sending.put_u8(127);
+                self.sending.put_u64_be(len as u64);
+            }
+        }
+        self.sending.put_slice(payload);
+    }
+
+    fn send_queued(&mut self) -> Result<()> {
+        while !self.sending.is_empty() {
+            match self.socket.write(&self.sending)? {
+                0 => return Err(Error::new(ErrorKind::WriteZero, "couldn't write WebSocket frame")),
+                n => { self.sending.split_to(n); }
+            }
+        }
+        Ok(())
+    }
+
+    // Sends what it can without waiting, leaving the rest for the next write or flush
+    fn try_send_queued(&mut self) -> Result<()> {
+        match self.send_queued() {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            result => result
+        }
+    }
+}
+
+impl<S> Read for WebSocketStream<S>
+where S: AsyncRead + AsyncWrite
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            if !self.payload.is_empty() {
+                let n = cmp::min(buf.len(), self.payload.len());
+                buf[..n].copy_from_slice(&self.payload.split_to(n));
+                return Ok(n)
+            }
+            if self.close_received {
+                return Ok(0)
+            }
+            if !self.read_frame()? {
+                let mut chunk = [0u8; 4096];
+                match self.socket.read(&mut chunk)? {
+                    0 if self.received.is_empty() && self.incoming.is_none() => return Ok(0),
+                    0 => return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed in the middle of a WebSocket frame")),
+                    n => self.received.extend_from_slice(&chunk[..n])
+                }
+            }
+        }
+    }
+}
+
+impl<S> Write for WebSocketStream<S>
+where S: AsyncRead + AsyncWrite
+{
+    // Each write goes out as one binary frame, once those before it have gone
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.send_queued()?;
+        if self.close_sent {
+            return Err(Error::new(ErrorKind::BrokenPipe, "WebSocket is closed"))
+        }
+        self.queue(BINARY, buf);
+        self.try_send_queued()?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.send_queued()?;
+        self.socket.flush()
+    }
+}
+
+impl<S> AsyncRead for WebSocketStream<S>
+where S: AsyncRead + AsyncWrite
+{
+}
+
+impl<S> AsyncWrite for WebSocketStream<S>
+where S: AsyncRead + AsyncWrite
+{
+    // https://tools.ietf.org/html/rfc6455#section-7.1.2
+    // Closes the WebSocket before the connection under it
+    fn shutdown(&mut self) -> Poll<(), Error> {
+        if !self.close_sent {
+            let status = [(NORMAL_CLOSURE >> 8) as u8, NORMAL_CLOSURE as u8];
+            self.queue(CLOSE, &status);
+            self.close_sent = true;
+        }
+        match self.send_queued() {
+            Ok(()) => self.socket.shutdown(),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(e) => Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Future;
+    use mqtt::*;
+    use std::io::Cursor;
+    use tokio::codec::Decoder;
+
+    // A socket that reads from a script and keeps whatever is written to it
+    struct Script {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>
+    }
+
+    impl Script {
+        fn new(input: Vec<u8>) -> Self {
+            Script{ input: Cursor::new(input), output: Vec::new() }
+        }
+    }
+
+    impl Read for Script {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Script {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for Script {}
+
+    impl AsyncWrite for Script {
+        fn shutdown(&mut self) -> Poll<(), Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    fn masked(opcode: u8, fin: bool, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xFA, 0x21, 0x3D];
+        let mut frame = vec![if fin { 0x80 | opcode } else { opcode }];
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len => frame.extend_from_slice(&[0x80 | 126, (len >> 8) as u8, len as u8])
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| { byte ^ mask[i % 4] }));
+        frame
+    }
+
+    fn request(protocol: &str) -> Vec<u8> {
+        format!(
+            "GET /mqtt HTTP/1.1\r\n\
+             Host: localhost\r\n\
+             Upgrade: websocket\r\n\
+             Connection: keep-alive, Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+             Sec-WebSocket-Version: 13\r\n\
+             Sec-WebSocket-Protocol: {}\r\n\r\n",
+            protocol
+        ).into_bytes()
+    }
+
+    fn read_all(stream: &mut WebSocketStream<Script>) -> Result<Vec<u8>> {
+        let mut read = Vec::new();
+        stream.read_to_end(&mut read)?;
+        Ok(read)
+    }
+
+    #[test]
+    fn accepts_the_mqtt_subprotocol() {
+        let stream = accept_websocket(Script::new(request("chat, mqtt"))).wait().unwrap();
+        let response = String::from_utf8(stream.socket.output).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 "));
+        // The example from RFC 6455 section 1.3
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(response.contains("Sec-WebSocket-Protocol: mqtt\r\n"));
+    }
+
+    #[test]
+    fn refuses_other_subprotocols() {
+        assert!(accept_websocket(Script::new(request("chat"))).wait().is_err());
+    }
+
+    #[test]
+    fn reads_packets_split_across_frames_and_several_in_one() {
+        let packets = [0xC0, 0x00, 0xC0, 0x00, 0xE0, 0x00];
+        let mut input = masked(BINARY, false, &packets[..1]);
+        input.extend(masked(PING, true, b"ping"));
+        input.extend(masked(CONTINUATION, true, &packets[1..3]));
+        input.extend(masked(BINARY, true, &packets[3..]));
+        let mut stream = WebSocketStream::new(Script::new(input), BytesMut::new());
+        assert_eq!(read_all(&mut stream).unwrap(), packets);
+        // The ping was answered
+        assert_eq!(stream.socket.output, [0x80 | PONG, 4, b'p', b'i', b'n', b'g']);
+    }
+
+    #[test]
+    fn reads_a_frame_longer_than_any_packet_in_it() {
+        // Three PUBLISHes of 100 bytes each, in one frame of 300 with a 16-bit length
+        let mut packets = Vec::new();
+        for _ in 0..3 {
+            packets.extend_from_slice(&[0x30, 98, 0x00, 0x01, b't']);
+            packets.extend_from_slice(&[0x55; 95]);
+        }
+        let mut stream = WebSocketStream::new(Script::new(masked(BINARY, true, &packets)), BytesMut::new());
+        let mut read = BytesMut::from(read_all(&mut stream).unwrap());
+        assert_eq!(&read[..], &packets[..]);
+        let mut codec = MqttCodec::with_max_packet_size(128);
+        for _ in 0..3 {
+            assert!(matches!(codec.decode(&mut read).unwrap(), Some(Message::Publish{ .. })));
+        }
+        assert!(read.is_empty());
+    }
+
+    #[test]
+    fn holds_no_more_of_a_long_frame_than_has_arrived() {
+        // A frame saying it's 256 MiB long, with only its first bytes sent
+        let mut input = vec![0x80 | BINARY, 0x80 | 127, 0, 0, 0, 0, 0x10, 0, 0, 0];
+        input.extend_from_slice(&[0, 0, 0, 0]);
+        input.extend_from_slice(&[0xC0, 0x00]);
+        let mut stream = WebSocketStream::new(Script::new(input), BytesMut::new());
+        let mut buf = [0u8; 16];
+        assert_eq!(stream.read(&mut buf).unwrap(), 2);
+        assert_eq!(buf[..2], [0xC0, 0x00]);
+        assert!(stream.received.capacity() < 8192 && stream.payload.capacity() < 8192);
+        // And then the connection ends in the middle of it
+        assert_eq!(stream.read(&mut buf).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn refuses_text_and_unmasked_frames() {
+        let mut stream = WebSocketStream::new(Script::new(masked(TEXT, true, b"{}")), BytesMut::new());
+        assert_eq!(read_all(&mut stream).unwrap_err().kind(), ErrorKind::InvalidData);
+        let mut stream = WebSocketStream::new(Script::new(vec![0x80 | BINARY, 2, 0xC0, 0x00]), BytesMut::new());
+        assert_eq!(read_all(&mut stream).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn answers_a_close_and_ends_the_stream() {
+        let mut input = masked(BINARY, true, &[0xC0, 0x00]);
+        input.extend(masked(CLOSE, true, &[0x03, 0xE8]));
+        let mut stream = WebSocketStream::new(Script::new(input), BytesMut::new());
+        assert_eq!(read_all(&mut stream).unwrap(), [0xC0, 0x00]);
+        assert_eq!(stream.socket.output, [0x80 | CLOSE, 2, 0x03, 0xE8]);
+    }
+}